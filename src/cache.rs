@@ -0,0 +1,183 @@
+// src/cache.rs
+//
+// The synced repository indexes live in `{root}/var/cache/koushou/repos`.
+// A sync never writes there: it builds the next generation of the whole
+// directory in `repos.staging` and swaps it in once every repository has
+// been fetched and validated, keeping the generation it replaces as
+// `repos.prev`. Only the holder of `lock` may stage, swap or recover, so two
+// syncs never touch each other's staging directory.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::lock::Lock;
+
+const CACHE_DIR: &str = "var/cache/koushou";
+
+/// The generations of the repository cache under one root.
+pub struct RepoCache {
+    base: PathBuf,
+}
+
+impl RepoCache {
+    pub fn new(root: &Path) -> Self {
+        Self { base: root.join(CACHE_DIR) }
+    }
+
+    /// The indexes depres reads.
+    pub fn current(&self) -> PathBuf {
+        self.base.join("repos")
+    }
+
+    /// The generation replaced by the last sync.
+    pub fn previous(&self) -> PathBuf {
+        self.base.join("repos.prev")
+    }
+
+    /// Where to read the indexes from: the current generation, or if a crash
+    /// interrupted a sync before `recover` ran, the one it left complete.
+    pub fn indexes(&self) -> PathBuf {
+        [self.current(), self.ready(), self.previous()]
+            .into_iter()
+            .find(|dir| dir.exists())
+            .unwrap_or_else(|| self.current())
+    }
+
+    fn staging(&self) -> PathBuf {
+        self.base.join("repos.staging")
+    }
+
+    /// A complete generation waiting to be swapped in.
+    fn ready(&self) -> PathBuf {
+        self.base.join("repos.new")
+    }
+
+    /// Take the lock needed to change the cache, waiting for a sync running
+    /// in another process.
+    pub fn lock(&self) -> io::Result<Lock> {
+        Lock::acquire(&self.base.join("lock"))
+    }
+
+    /// Finish a swap interrupted by a crash, so `current` is always a whole
+    /// generation. A staging directory left behind is incomplete and dropped.
+    pub fn recover(&self, _lock: &Lock) -> io::Result<()> {
+        if !self.current().exists() {
+            if self.ready().exists() {
+                fs::rename(self.ready(), self.current())?;
+            } else if self.previous().exists() {
+                fs::rename(self.previous(), self.current())?;
+            }
+        }
+        remove_dir(&self.ready())?;
+        remove_dir(&self.staging())
+    }
+
+    /// Start the next generation as a copy of the current one and return its
+    /// directory. Files are hard-linked where possible; sync only ever
+    /// replaces them by renaming, so the current generation is not touched.
+    pub fn stage(&self, lock: &Lock) -> io::Result<PathBuf> {
+        self.recover(lock)?;
+        let staging = self.staging();
+        fs::create_dir_all(&staging)?;
+        if self.current().exists() {
+            for entry in fs::read_dir(self.current())? {
+                let entry = entry?;
+                let name = entry.file_name();
+                let leftover = name.to_string_lossy().ends_with(".part")
+                    || name.to_string_lossy().ends_with(".tmp");
+                if !entry.file_type()?.is_file() || leftover {
+                    continue;
+                }
+                let dest = staging.join(&name);
+                if fs::hard_link(entry.path(), &dest).is_err() {
+                    fs::copy(entry.path(), &dest)?;
+                }
+            }
+        }
+        Ok(staging)
+    }
+
+    /// Make the staged generation current, keeping the one it replaces.
+    pub fn commit(&self, _lock: &Lock) -> io::Result<()> {
+        let staging = self.staging();
+        for entry in fs::read_dir(&staging)? {
+            fs::File::open(entry?.path())?.sync_all()?;
+        }
+        sync_dir(&staging)?;
+
+        // From here on the staged generation is complete; `recover` finishes
+        // the swap if it is interrupted.
+        fs::rename(&staging, self.ready())?;
+        sync_dir(&self.base)?;
+        remove_dir(&self.previous())?;
+        if self.current().exists() {
+            fs::rename(self.current(), self.previous())?;
+        }
+        fs::rename(self.ready(), self.current())?;
+        sync_dir(&self.base)
+    }
+
+    /// Throw the staged generation away.
+    pub fn discard(&self, _lock: &Lock) -> io::Result<()> {
+        remove_dir(&self.staging())
+    }
+}
+
+fn remove_dir(path: &Path) -> io::Result<()> {
+    match fs::remove_dir_all(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn sync_dir(path: &Path) -> io::Result<()> {
+    fs::File::open(path)?.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn swaps_generations() {
+        let root = tempfile::tempdir().unwrap();
+        let cache = RepoCache::new(root.path());
+        let lock = cache.lock().unwrap();
+
+        let staging = cache.stage(&lock).unwrap();
+        fs::write(staging.join("core.db"), "one").unwrap();
+        cache.commit(&lock).unwrap();
+
+        let staging = cache.stage(&lock).unwrap();
+        assert_eq!(fs::read_to_string(staging.join("core.db")).unwrap(), "one");
+        fs::write(staging.join("core.db.tmp"), "two").unwrap();
+        fs::rename(staging.join("core.db.tmp"), staging.join("core.db")).unwrap();
+        assert_eq!(fs::read_to_string(cache.current().join("core.db")).unwrap(), "one");
+        cache.commit(&lock).unwrap();
+        assert_eq!(fs::read_to_string(cache.current().join("core.db")).unwrap(), "two");
+        assert_eq!(fs::read_to_string(cache.previous().join("core.db")).unwrap(), "one");
+
+        // A failed sync leaves the current generation alone.
+        let staging = cache.stage(&lock).unwrap();
+        fs::write(staging.join("main.db"), "broken").unwrap();
+        cache.discard(&lock).unwrap();
+        assert!(!cache.current().join("main.db").exists());
+
+        // Interrupted between the two renames of a commit.
+        let staging = cache.stage(&lock).unwrap();
+        fs::write(staging.join("main.db"), "three").unwrap();
+        fs::rename(&staging, cache.ready()).unwrap();
+        fs::remove_dir_all(cache.previous()).unwrap();
+        fs::rename(cache.current(), cache.previous()).unwrap();
+        cache.recover(&lock).unwrap();
+        assert_eq!(fs::read_to_string(cache.current().join("main.db")).unwrap(), "three");
+
+        // Interrupted while staging.
+        let staging = cache.stage(&lock).unwrap();
+        fs::write(staging.join("extra.db"), "partial").unwrap();
+        cache.recover(&lock).unwrap();
+        assert!(!staging.exists());
+        assert!(!cache.current().join("extra.db").exists());
+    }
+}
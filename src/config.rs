@@ -0,0 +1,95 @@
+// src/config.rs
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use thiserror::Error;
+
+use crate::pkgdb::{PackageDatabase, PkgDbError};
+use crate::transaction::{self, NEW_SUFFIX};
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Package database error: {0}")]
+    PkgDb(#[from] PkgDbError),
+}
+
+/// Configuration files of installed packages that have a `.kpnew` version
+/// waiting to be merged, as (current, new) paths.
+pub fn pending_merges(root: &Path) -> Result<Vec<(PathBuf, PathBuf)>, ConfigError> {
+    let db = PackageDatabase::load_or_new(root.join("var/lib/koushou/db.json"))?;
+    let mut pending: Vec<(PathBuf, PathBuf)> = db
+        .list()
+        .flat_map(|pkg| pkg.config.keys())
+        .map(|file| {
+            let path = root.join(file);
+            let new = transaction::config_path(&path, NEW_SUFFIX);
+            (path, new)
+        })
+        .filter(|(_, new)| new.exists())
+        .collect();
+    pending.sort();
+    Ok(pending)
+}
+
+/// List the pending merges and show how each new version differs from the
+/// file in use.
+pub fn diff_pending(root: &Path) -> Result<(), ConfigError> {
+    let pending = pending_merges(root)?;
+    if pending.is_empty() {
+        println!("✓ No configuration files waiting to be merged.");
+        return Ok(());
+    }
+
+    println!("📋 {} configuration file(s) waiting to be merged:", pending.len());
+    for (_, new) in &pending {
+        println!("  {}", new.display());
+    }
+    for (path, new) in &pending {
+        println!();
+        Command::new("diff").arg("-u").arg(path).arg(new).status()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::fs;
+    use crate::pkgdb::InstalledPackage;
+    use crate::version::Version;
+
+    #[test]
+    fn finds_pending_merges() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        fs::create_dir_all(root.join("etc/ssh")).unwrap();
+        fs::write(root.join("etc/ssh/sshd_config"), "Port 2222\n").unwrap();
+        fs::write(root.join("etc/ssh/sshd_config.kpnew"), "Port 22\n").unwrap();
+        fs::write(root.join("etc/ssh/ssh_config"), "\n").unwrap();
+
+        let mut db = PackageDatabase::new();
+        db.add(InstalledPackage {
+            name: "openssh".to_string(),
+            version: Version::parse("9.9").unwrap(),
+            arch: "x86_64".to_string(),
+            flavour: "glibc-systemd".to_string(),
+            depends: Vec::new(),
+            files: vec!["etc/ssh/ssh_config".to_string(), "etc/ssh/sshd_config".to_string()],
+            file_info: BTreeMap::new(),
+            config: BTreeMap::from([
+                ("etc/ssh/ssh_config".to_string(), "a".to_string()),
+                ("etc/ssh/sshd_config".to_string(), "b".to_string()),
+            ]),
+        });
+        fs::create_dir_all(root.join("var/lib/koushou")).unwrap();
+        db.save(root.join("var/lib/koushou/db.json")).unwrap();
+
+        assert_eq!(
+            pending_merges(root).unwrap(),
+            vec![(root.join("etc/ssh/sshd_config"), root.join("etc/ssh/sshd_config.kpnew"))]
+        );
+    }
+}
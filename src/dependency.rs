@@ -0,0 +1,373 @@
+// src/dependency.rs
+
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+use crate::version::{Version, VersionError};
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum DependencyError {
+    #[error("Empty dependency expression")]
+    Empty,
+    #[error("Invalid package name in dependency '{0}'")]
+    InvalidName(String),
+    #[error("Missing comparison operator in dependency '{expr}' before '{found}'")]
+    MissingOperator { expr: String, found: String },
+    #[error("Unknown operator '{op}' in dependency '{expr}'")]
+    UnknownOperator { expr: String, op: String },
+    #[error("Missing version after '{op}' in dependency '{expr}'")]
+    MissingVersion { expr: String, op: String },
+    #[error("Invalid version in dependency '{expr}': {source}")]
+    InvalidVersion { expr: String, source: VersionError },
+    #[error("Invalid provides '{0}': expected 'name' or 'name=version'")]
+    InvalidProvide(String),
+}
+
+/// A constraint on the version of a dependency.
+///
+/// `All` is a compound range such as `>=3.0, <4`; every member must match.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VersionPredicate {
+    Any,
+    Exact(Version),
+    NotEqual(Version),
+    GreaterThan(Version),
+    GreaterOrEqual(Version),
+    LessThan(Version),
+    LessOrEqual(Version),
+    All(Vec<VersionPredicate>),
+}
+
+impl VersionPredicate {
+    pub fn matches(&self, candidate: &Version) -> bool {
+        match self {
+            VersionPredicate::Any => true,
+            VersionPredicate::Exact(v) => candidate == v,
+            VersionPredicate::NotEqual(v) => candidate != v,
+            VersionPredicate::GreaterThan(v) => candidate > v,
+            VersionPredicate::GreaterOrEqual(v) => candidate >= v,
+            VersionPredicate::LessThan(v) => candidate < v,
+            VersionPredicate::LessOrEqual(v) => candidate <= v,
+            VersionPredicate::All(preds) => preds.iter().all(|p| p.matches(candidate)),
+        }
+    }
+
+    /// Parse the constraint part of a dependency, e.g. `>=3.0, <4`.
+    /// An empty string means `Any`.
+    pub fn parse(s: &str) -> Result<Self, DependencyError> {
+        Self::parse_for(s, s)
+    }
+
+    fn parse_for(s: &str, expr: &str) -> Result<Self, DependencyError> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Ok(VersionPredicate::Any);
+        }
+
+        let mut preds = Vec::new();
+        for part in s.split(',') {
+            preds.push(Self::parse_single(part.trim(), expr)?);
+        }
+
+        if preds.len() == 1 {
+            Ok(preds.remove(0))
+        } else {
+            Ok(VersionPredicate::All(preds))
+        }
+    }
+
+    fn parse_single(s: &str, expr: &str) -> Result<Self, DependencyError> {
+        let op_len = s.find(|c: char| !matches!(c, '<' | '>' | '=' | '!')).unwrap_or(s.len());
+        let (op, version) = s.split_at(op_len);
+        let version = version.trim();
+
+        if op.is_empty() {
+            return Err(DependencyError::MissingOperator {
+                expr: expr.to_string(),
+                found: s.to_string(),
+            });
+        }
+        if version.is_empty() {
+            return Err(DependencyError::MissingVersion {
+                expr: expr.to_string(),
+                op: op.to_string(),
+            });
+        }
+
+        let version = Version::parse(version).map_err(|source| DependencyError::InvalidVersion {
+            expr: expr.to_string(),
+            source,
+        })?;
+
+        match op {
+            "=" | "==" => Ok(VersionPredicate::Exact(version)),
+            "!=" => Ok(VersionPredicate::NotEqual(version)),
+            ">" => Ok(VersionPredicate::GreaterThan(version)),
+            ">=" => Ok(VersionPredicate::GreaterOrEqual(version)),
+            "<" => Ok(VersionPredicate::LessThan(version)),
+            "<=" => Ok(VersionPredicate::LessOrEqual(version)),
+            _ => Err(DependencyError::UnknownOperator {
+                expr: expr.to_string(),
+                op: op.to_string(),
+            }),
+        }
+    }
+}
+
+impl fmt::Display for VersionPredicate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VersionPredicate::Any => Ok(()),
+            VersionPredicate::Exact(v) => write!(f, "={}", v),
+            VersionPredicate::NotEqual(v) => write!(f, "!={}", v),
+            VersionPredicate::GreaterThan(v) => write!(f, ">{}", v),
+            VersionPredicate::GreaterOrEqual(v) => write!(f, ">={}", v),
+            VersionPredicate::LessThan(v) => write!(f, "<{}", v),
+            VersionPredicate::LessOrEqual(v) => write!(f, "<={}", v),
+            VersionPredicate::All(preds) => {
+                for (i, p) in preds.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{}", p)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// How strongly a package wants one of its dependencies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum DependencyKind {
+    /// `depends`: always installed.
+    #[default]
+    Required,
+    /// `recommends`: installed unless the user opts out.
+    Recommended,
+    /// `optional`: only installed when the user opts in.
+    Optional,
+}
+
+impl DependencyKind {
+    /// The package.kdl node name, also used in the repository database.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DependencyKind::Required => "depends",
+            DependencyKind::Recommended => "recommends",
+            DependencyKind::Optional => "optional",
+        }
+    }
+
+    pub fn from_node(name: &str) -> Option<Self> {
+        match name {
+            "depends" => Some(DependencyKind::Required),
+            "recommends" => Some(DependencyKind::Recommended),
+            "optional" => Some(DependencyKind::Optional),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for DependencyKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
+/// A dependency expression such as `glibc`, `zlib>=1.3` or `openssl >=3.0, <4`.
+///
+/// Parsed expressions are required dependencies; recommended and optional
+/// ones also carry the packager's reason for wanting them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dependency {
+    pub name: String,
+    pub predicate: VersionPredicate,
+    pub kind: DependencyKind,
+    pub reason: Option<String>,
+}
+
+impl Dependency {
+    pub fn parse(s: &str) -> Result<Self, DependencyError> {
+        let expr = s.trim();
+        if expr.is_empty() {
+            return Err(DependencyError::Empty);
+        }
+
+        let name_len = expr
+            .find(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '+' | '-')))
+            .unwrap_or(expr.len());
+        let (name, rest) = expr.split_at(name_len);
+
+        if !name.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+            return Err(DependencyError::InvalidName(expr.to_string()));
+        }
+
+        Ok(Self {
+            name: name.to_string(),
+            predicate: VersionPredicate::parse_for(rest, expr)?,
+            kind: DependencyKind::Required,
+            reason: None,
+        })
+    }
+
+    pub fn is_required(&self) -> bool {
+        self.kind == DependencyKind::Required
+    }
+}
+
+impl fmt::Display for Dependency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.predicate {
+            VersionPredicate::Any => f.write_str(&self.name),
+            _ => write!(f, "{} {}", self.name, self.predicate),
+        }
+    }
+}
+
+impl FromStr for Dependency {
+    type Err = DependencyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+/// A virtual name offered by a package, from `provides "sh=5.2"`.
+///
+/// Without a version the entry only satisfies dependencies that carry no
+/// version constraint.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Provide {
+    pub name: String,
+    pub version: Option<Version>,
+}
+
+impl Provide {
+    pub fn parse(s: &str) -> Result<Self, DependencyError> {
+        let dep = Dependency::parse(s)?;
+        let version = match dep.predicate {
+            VersionPredicate::Any => None,
+            VersionPredicate::Exact(v) => Some(v),
+            _ => return Err(DependencyError::InvalidProvide(s.trim().to_string())),
+        };
+        Ok(Self { name: dep.name, version })
+    }
+}
+
+impl fmt::Display for Provide {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.version {
+            Some(v) => write!(f, "{}={}", self.name, v),
+            None => f.write_str(&self.name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v(s: &str) -> Version {
+        Version::parse(s).unwrap()
+    }
+
+    #[test]
+    fn parse_expressions() {
+        use VersionPredicate::*;
+        let cases: Vec<(&str, &str, VersionPredicate)> = vec![
+            ("glibc", "glibc", Any),
+            ("  glibc  ", "glibc", Any),
+            ("libstdc++", "libstdc++", Any),
+            ("zlib=1.3", "zlib", Exact(v("1.3"))),
+            ("zlib==1.3", "zlib", Exact(v("1.3"))),
+            ("zlib != 1.3", "zlib", NotEqual(v("1.3"))),
+            ("zlib>1.3", "zlib", GreaterThan(v("1.3"))),
+            ("zlib>=1.3", "zlib", GreaterOrEqual(v("1.3"))),
+            ("zlib <1.3", "zlib", LessThan(v("1.3"))),
+            ("zlib<= 1.3", "zlib", LessOrEqual(v("1.3"))),
+            ("foo-bar>=1:2.0-3", "foo-bar", GreaterOrEqual(v("1:2.0-3"))),
+            (
+                "openssl >=3.0, <4",
+                "openssl",
+                All(vec![GreaterOrEqual(v("3.0")), LessThan(v("4"))]),
+            ),
+            (
+                "openssl>3.0,<=3.5,!=3.2",
+                "openssl",
+                All(vec![GreaterThan(v("3.0")), LessOrEqual(v("3.5")), NotEqual(v("3.2"))]),
+            ),
+        ];
+        for (input, name, predicate) in cases {
+            let dep = Dependency::parse(input).unwrap_or_else(|e| panic!("{}: {}", input, e));
+            assert_eq!(dep.name, name, "name of '{}'", input);
+            assert_eq!(dep.predicate, predicate, "predicate of '{}'", input);
+            assert_eq!(Dependency::parse(&dep.to_string()).unwrap(), dep, "round trip of '{}'", input);
+        }
+    }
+
+    #[test]
+    fn parse_errors() {
+        let cases: &[(&str, fn(&DependencyError) -> bool)] = &[
+            ("", |e| matches!(e, DependencyError::Empty)),
+            ("   ", |e| matches!(e, DependencyError::Empty)),
+            (">=1.0", |e| matches!(e, DependencyError::InvalidName(_))),
+            ("-foo", |e| matches!(e, DependencyError::InvalidName(_))),
+            ("foo 1.0", |e| matches!(e, DependencyError::MissingOperator { .. })),
+            ("foo =>1.0", |e| matches!(e, DependencyError::UnknownOperator { .. })),
+            ("foo <>1.0", |e| matches!(e, DependencyError::UnknownOperator { .. })),
+            ("foo !1.0", |e| matches!(e, DependencyError::UnknownOperator { .. })),
+            ("foo >=", |e| matches!(e, DependencyError::MissingVersion { .. })),
+            ("foo >=1.0,", |e| matches!(e, DependencyError::MissingOperator { .. })),
+            ("foo >=1.0, <", |e| matches!(e, DependencyError::MissingVersion { .. })),
+            ("foo >=1.0-", |e| matches!(e, DependencyError::InvalidVersion { .. })),
+            ("foo >=1 .0", |e| matches!(e, DependencyError::InvalidVersion { .. })),
+        ];
+        for (input, check) in cases {
+            let err = Dependency::parse(input).unwrap_err();
+            assert!(check(&err), "unexpected error for '{}': {:?}", input, err);
+        }
+    }
+
+    #[test]
+    fn parse_provides() {
+        let cases: &[(&str, Result<Provide, DependencyError>)] = &[
+            ("sh", Ok(Provide { name: "sh".into(), version: None })),
+            ("sh=5.2", Ok(Provide { name: "sh".into(), version: Some(v("5.2")) })),
+            ("libc.so.6 == 2.39", Ok(Provide { name: "libc.so.6".into(), version: Some(v("2.39")) })),
+            ("sh>=5", Err(DependencyError::InvalidProvide("sh>=5".into()))),
+            ("sh=1, =2", Err(DependencyError::InvalidProvide("sh=1, =2".into()))),
+            ("", Err(DependencyError::Empty)),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(&Provide::parse(input), expected, "parsing '{}'", input);
+        }
+        assert_eq!(Provide::parse("sh = 5.2").unwrap().to_string(), "sh=5.2");
+    }
+
+    #[test]
+    fn predicate_matching() {
+        let cases: &[(&str, &str, bool)] = &[
+            ("", "1.0", true),
+            ("=1.0", "1.00", true),
+            ("=1.0", "1.0-1", false),
+            ("!=1.0", "1.0", false),
+            ("!=1.0", "1.1", true),
+            (">1.9", "1.10", true),
+            (">1.10", "1.10", false),
+            (">=1.10", "1.10", true),
+            ("<2.0", "2.0~rc1", true),
+            ("<=2.0", "2.0", true),
+            ("<=2.0", "2.0-1", false),
+            (">=3.0, <4", "3.5", true),
+            (">=3.0, <4", "4.0", false),
+            (">=3.0, <4", "2.9", false),
+            (">=3.0, <4, !=3.2", "3.2", false),
+        ];
+        for &(pred, candidate, expected) in cases {
+            let p = VersionPredicate::parse(pred).unwrap();
+            assert_eq!(p.matches(&v(candidate)), expected, "'{}' matches {}", pred, candidate);
+        }
+    }
+}
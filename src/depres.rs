@@ -1,12 +1,17 @@
 // src/depres.rs
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::path::Path;
 use thiserror::Error;
 use serde::{Deserialize, Serialize};
-use regex::Regex;
 use rusqlite::{Connection, params};
 
+use crate::cache;
+use crate::dependency::{Dependency, DependencyError, DependencyKind, Provide, VersionPredicate};
+use crate::index;
+use crate::repo;
+use crate::version::{Version, VersionError};
+
 #[derive(Error, Debug)]
 pub enum DepresError {
     #[error("Package '{0}' not found in any repository")]
@@ -15,10 +20,18 @@ pub enum DepresError {
     NoSolution(String),
     #[error("Flavour mismatch: package requires '{required}', system is '{system}'")]
     FlavourMismatch { required: String, system: String },
-    #[error("Circular dependency detected involving: {0}")]
-    CircularDependency(String),
     #[error("Version constraint not satisfied: {0}")]
     VersionConstraint(String),
+    #[error("Invalid version: {0}")]
+    InvalidVersion(#[from] VersionError),
+    #[error("Invalid dependency: {0}")]
+    InvalidDependency(#[from] DependencyError),
+    #[error("Unknown dependency kind '{0}'")]
+    InvalidDependencyKind(String),
+    #[error("Repository configuration error: {0}")]
+    Repo(#[from] repo::RepoError),
+    #[error("Repository index error: {0}")]
+    Index(#[from] index::IndexError),
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
     #[error("SQLite error: {0}")]
@@ -28,7 +41,7 @@ pub enum DepresError {
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct PackageId {
     pub name: String,
-    pub version: String,
+    pub version: Version,
     pub arch: String,
     pub flavour: String,
 }
@@ -39,50 +52,53 @@ impl PackageId {
     }
 }
 
-#[derive(Debug, Clone)]
-pub enum VersionPredicate {
-    Any,
-    Exact(String),
-    GreaterOrEqual(String),
-    LessThan(String),
-}
-
-impl VersionPredicate {
-    pub fn matches(&self, candidate: &str) -> bool {
-        match self {
-            VersionPredicate::Any => true,
-            VersionPredicate::Exact(v) => candidate == v,
-            VersionPredicate::GreaterOrEqual(v) => candidate >= v.as_str(),
-            VersionPredicate::LessThan(v) => candidate < v.as_str(),
-        }
-    }
-}
-
-#[derive(Debug, Clone)]
-pub struct Dependency {
-    pub name: String,
-    pub predicate: VersionPredicate,
-}
-
 #[derive(Debug, Clone)]
 pub struct PackageMetadata {
     pub id: PackageId,
-    pub url: String,
+    /// Name of the repository the package comes from.
+    pub repo: String,
+    /// Location of the package file relative to a mirror's root.
+    pub path: String,
     pub sha256: String,
     pub depends: Vec<Dependency>,
+    pub provides: Vec<Provide>,
+    pub conflicts: Vec<Dependency>,
+    pub replaces: Vec<Dependency>,
 }
 
 #[derive(Debug)]
 pub struct PackageUniverse {
     packages: HashMap<(String, String, String), Vec<PackageMetadata>>,
+    /// Virtual name -> names of the packages that provide it, keyed like `packages`.
+    virtuals: HashMap<(String, String, String), BTreeSet<String>>,
 }
 
 impl PackageUniverse {
+    /// Load every synced repository listed in the repository configuration.
+    /// Repositories that have not been synced yet are skipped.
     pub fn load_from_cache(root: &Path) -> Result<Self, DepresError> {
-        let db_path = root.join("var/cache/koushou/repos/core.db");
-        let conn = Connection::open(&db_path)?;
+        let repos = repo::load_repositories(root)?;
+        let cache_dir = cache::RepoCache::new(root).indexes();
 
-        let mut packages: HashMap<(String, String, String), Vec<PackageMetadata>> = HashMap::new();
+        let mut metas = Vec::new();
+        for repository in &repos {
+            let db_path = cache_dir.join(format!("{}.db", repository.name));
+            if !db_path.exists() {
+                continue;
+            }
+            let conn = Connection::open(&db_path)?;
+            index::check_format(&conn)?;
+            metas.extend(Self::load_repository(&conn, repository)?);
+        }
+
+        Ok(Self::from_packages(prefer_repositories(metas, &repos)))
+    }
+
+    fn load_repository(
+        conn: &Connection,
+        repository: &repo::Repository,
+    ) -> Result<Vec<PackageMetadata>, DepresError> {
+        let mut metas = Vec::new();
 
         let mut stmt = conn.prepare(
             "SELECT name, version, arch, flavour, filename, sha256 FROM packages"
@@ -102,159 +118,1746 @@ impl PackageUniverse {
             let (name, version, arch, flavour, filename, sha256) = pkg?;
             let id = PackageId {
                 name: name.clone(),
-                version: version.clone(),
+                version: Version::parse(&version)?,
                 arch: arch.clone(),
                 flavour: flavour.clone(),
             };
-            let url = format!(
-                "https://seiryolinux.github.io/repo/{}/{}/{}/{}",
-                flavour, "core", arch, filename
-            );
-            packages.entry((name, arch, flavour)).or_default().push(PackageMetadata {
+            let path = repository.file_path(&flavour, &arch, &filename);
+            metas.push(PackageMetadata {
                 id,
-                url,
+                repo: repository.name.clone(),
+                path,
                 sha256,
                 depends: Vec::new(),
+                provides: Vec::new(),
+                conflicts: Vec::new(),
+                replaces: Vec::new(),
             });
         }
 
-        let mut dep_stmt = conn.prepare(
-            "SELECT package_name, dep_name, dep_predicate FROM dependencies"
+        let mut depends =
+            load_relations(conn, "dependencies", "dep_name, dep_predicate, kind, reason")?;
+        let mut conflicts =
+            load_relations(conn, "conflicts", "conflict_name, conflict_predicate, NULL, NULL")?;
+        let mut replaces =
+            load_relations(conn, "replaces", "replaced_name, replaced_predicate, NULL, NULL")?;
+
+        let mut prov_stmt = conn.prepare(
+            "SELECT package_name, package_version, package_arch, package_flavour, provides_name, provides_version
+             FROM provides"
         )?;
-        let dep_iter = dep_stmt.query_map([], |row| {
+        let prov_iter = prov_stmt.query_map([], |row| {
             Ok((
-                row.get(0)?, // package_name
-                row.get(1)?, // dep_name
-                row.get(2)?, // dep_predicate (TEXT, may be NULL)
+                (
+                    row.get::<_, String>(0)?, // package_name
+                    row.get::<_, String>(1)?, // package_version
+                    row.get::<_, String>(2)?, // package_arch
+                    row.get::<_, String>(3)?, // package_flavour
+                ),
+                row.get::<_, String>(4)?,         // provides_name
+                row.get::<_, Option<String>>(5)?, // provides_version (TEXT, may be NULL)
             ))
         })?;
 
-        let mut dep_map: HashMap<String, Vec<(String, Option<String>)>> = HashMap::new();
-        for dep in dep_iter {
-            let (pkg_name, dep_name, predicate) = dep?;
-            dep_map.entry(pkg_name).or_default().push((dep_name, predicate));
-        }
-
-        for pkg_list in packages.values_mut() {
-            for pkg in pkg_list {
-                if let Some(deps) = dep_map.get(&pkg.id.name) {
-                    for (dep_name, predicate_str) in deps {
-                        let predicate = match predicate_str.as_deref() {
-                            Some(p) if p.starts_with(">=") => {
-                                VersionPredicate::GreaterOrEqual(p[2..].to_string())
-                            }
-                            Some(p) if p.starts_with("<") => {
-                                VersionPredicate::LessThan(p[1..].to_string())
-                            }
-                            Some(p) if p.starts_with("=") => {
-                                VersionPredicate::Exact(p[1..].to_string())
-                            }
-                            Some(p) => VersionPredicate::Exact(p.to_string()),
-                            None => VersionPredicate::Any,
-                            _ => VersionPredicate::Any,
-                        };
-                        pkg.depends.push(Dependency {
-                            name: dep_name.clone(),
-                            predicate,
-                        });
-                    }
-                }
+        let mut provides: HashMap<RowKey, Vec<Provide>> = HashMap::new();
+        for prov in prov_iter {
+            let (pkg_key, name, version) = prov?;
+            let version = version.as_deref().map(Version::parse).transpose()?;
+            provides.entry(pkg_key).or_default().push(Provide { name, version });
+        }
+
+        for pkg in &mut metas {
+            let key = (
+                pkg.id.name.clone(),
+                pkg.id.version.to_string(),
+                pkg.id.arch.clone(),
+                pkg.id.flavour.clone(),
+            );
+            pkg.depends = depends.remove(&key).unwrap_or_default();
+            pkg.provides = provides.remove(&key).unwrap_or_default();
+            pkg.conflicts = conflicts.remove(&key).unwrap_or_default();
+            pkg.replaces = replaces.remove(&key).unwrap_or_default();
+        }
+
+        Ok(metas)
+    }
+
+    pub fn from_packages(metas: impl IntoIterator<Item = PackageMetadata>) -> Self {
+        let mut packages: HashMap<(String, String, String), Vec<PackageMetadata>> = HashMap::new();
+        let mut virtuals: HashMap<(String, String, String), BTreeSet<String>> = HashMap::new();
+        for meta in metas {
+            for provide in &meta.provides {
+                let key = (provide.name.clone(), meta.id.arch.clone(), meta.id.flavour.clone());
+                virtuals.entry(key).or_default().insert(meta.id.name.clone());
             }
+            let key = (meta.id.name.clone(), meta.id.arch.clone(), meta.id.flavour.clone());
+            packages.entry(key).or_default().push(meta);
         }
+        Self { packages, virtuals }
+    }
+
+    /// The newest version of the package called `name`, if any repository
+    /// carries it.
+    pub fn newest(&self, name: &str, flavour: &str, arch: &str) -> Option<&PackageMetadata> {
+        self.candidates(name, flavour, arch)?
+            .iter()
+            .max_by(|a, b| a.id.version.cmp(&b.id.version))
+    }
 
-        Ok(Self { packages })
+    fn candidates(&self, name: &str, flavour: &str, arch: &str) -> Option<&[PackageMetadata]> {
+        let key = (name.to_string(), arch.to_string(), flavour.to_string());
+        self.packages.get(&key).map(|v| v.as_slice())
     }
 
+    /// A flavour other than `flavour` that carries or provides `name` for
+    /// `arch`, picked deterministically when there are several.
+    fn other_flavour(&self, name: &str, flavour: &str, arch: &str) -> Option<&str> {
+        self.packages
+            .keys()
+            .chain(self.virtuals.keys())
+            .filter(|(n, a, f)| n == name && a == arch && f != flavour)
+            .map(|(_, _, f)| f.as_str())
+            .min()
+    }
+
+    /// Choose one version of every package reachable from `root_packages` such
+    /// that every dependency predicate holds.
+    ///
+    /// Each root is a dependency expression (`htop`, `foo>=2`). The search is a
+    /// backtracking one: packages are decided most-constrained first, trying
+    /// the newest admissible version first. When a choice fails, the conflict
+    /// records which earlier decisions caused it, so the search can jump back
+    /// past decisions that had nothing to do with it.
+    ///
+    /// `installed` maps the names of installed packages to their versions.
+    /// A dependency that is already installed keeps its version whenever that
+    /// version is admissible; only the requested packages move to the newest.
+    /// This includes installed versions that no repository carries.
+    ///
+    /// Two packages that conflict with or replace one another are never
+    /// chosen together, and a chosen package may not conflict with an
+    /// installed one that stays. Installed packages replaced by a chosen
+    /// package are listed in `removals`.
+    ///
+    /// Recommended and optional dependencies are followed as `policy` says,
+    /// as long as the rest of the solution allows it: one that cannot be
+    /// satisfied is left out rather than failing the resolution. The ones
+    /// left out are listed in `suggestions`.
     pub fn resolve(
         &self,
         root_packages: &[String],
         system_flavour: &str,
         arch: &str,
+        installed: &HashMap<String, Version>,
+        policy: DependencyPolicy,
     ) -> Result<ResolutionSolution, DepresError> {
-        let mut selected: HashMap<String, PackageMetadata> = HashMap::new();
-        let mut visited: HashSet<String> = HashSet::new();
+        let mut state = SearchState::default();
+
+        for root in root_packages {
+            let dep = Dependency::parse(root)?;
+            if self.providers(&dep.name, system_flavour, arch).is_empty() {
+                if let Some(required) = self.other_flavour(&dep.name, system_flavour, arch) {
+                    return Err(DepresError::FlavourMismatch {
+                        required: required.to_string(),
+                        system: system_flavour.to_string(),
+                    });
+                }
+                return Err(DepresError::PackageNotFound(dep.name));
+            }
+            state.requirements.entry(dep.name.clone()).or_default().push(Requirement {
+                dep,
+                required_by: None,
+            });
+        }
+
+        // An installed version that no repository carries can still satisfy
+        // dependencies; it can only be kept as it is.
+        let mut installed_only: Vec<PackageMetadata> = installed
+            .iter()
+            .filter(|(name, version)| {
+                self.candidates(name, system_flavour, arch)
+                    .map_or(true, |metas| !metas.iter().any(|meta| meta.id.version == **version))
+            })
+            .map(|(name, version)| PackageMetadata {
+                id: PackageId {
+                    name: name.clone(),
+                    version: version.clone(),
+                    arch: arch.to_string(),
+                    flavour: system_flavour.to_string(),
+                },
+                repo: String::new(),
+                path: String::new(),
+                sha256: String::new(),
+                depends: Vec::new(),
+                provides: Vec::new(),
+                conflicts: Vec::new(),
+                replaces: Vec::new(),
+            })
+            .collect();
+        installed_only.sort_by(|a, b| a.id.name.cmp(&b.id.name));
 
-        for pkg_name in root_packages {
-            self.resolve_package(pkg_name, system_flavour, arch, &mut selected, &mut visited)?;
+        let mut solver = Solver {
+            universe: self,
+            installed_only: &installed_only,
+            flavour: system_flavour,
+            arch,
+            installed,
+            policy,
+            steps: 0,
+        };
+        if let Err(conflict) = solver.search(&mut state) {
+            let mut derivation = String::new();
+            conflict.render(0, &mut derivation);
+            return Err(DepresError::NoSolution(format!("\n{}", derivation.trim_end())));
         }
 
+        let chosen: BTreeMap<String, &PackageMetadata> = state
+            .selected
+            .iter()
+            .map(|(name, selection)| (name.clone(), selection.meta))
+            .collect();
+
         let mut packages = Vec::new();
-        let mut download_urls = HashMap::new();
+        let mut plan = Vec::new();
+        let mut actions = HashMap::new();
+        let mut download_paths = HashMap::new();
         let mut sha256_sums = HashMap::new();
+        let mut repositories = HashMap::new();
+
+        for group in install_order(&chosen) {
+            let mut step = InstallStep { packages: Vec::new() };
+            for meta in group {
+                let requested = state
+                    .requirements
+                    .get(&meta.id.name)
+                    .map_or(false, |reqs| reqs.iter().any(|r| r.required_by.is_none()));
+                let action = match installed.get(&meta.id.name) {
+                    None => Action::Install,
+                    Some(old) => match meta.id.version.cmp(old) {
+                        std::cmp::Ordering::Greater => Action::Upgrade { from: old.clone() },
+                        std::cmp::Ordering::Less => Action::Downgrade { from: old.clone() },
+                        std::cmp::Ordering::Equal if requested => Action::Reinstall,
+                        std::cmp::Ordering::Equal => Action::Keep,
+                    },
+                };
 
-        for meta in selected.values() {
-            packages.push(meta.id.clone());
-            download_urls.insert(meta.id.name.clone(), meta.url.clone());
-            sha256_sums.insert(meta.id.name.clone(), meta.sha256.clone());
+                packages.push(meta.id.clone());
+                step.packages.push(meta.id.clone());
+                actions.insert(meta.id.name.clone(), action);
+                download_paths.insert(meta.id.name.clone(), meta.path.clone());
+                sha256_sums.insert(meta.id.name.clone(), meta.sha256.clone());
+                repositories.insert(meta.id.name.clone(), meta.repo.clone());
+            }
+            plan.push(step);
+        }
+
+        let mut removals = Vec::new();
+        let mut kept: Vec<(&String, &Version)> = installed.iter().collect();
+        kept.sort();
+        for (name, version) in kept {
+            if chosen.contains_key(name) {
+                continue;
+            }
+            let meta = solver.installed_meta(name, version);
+            let replacer = chosen.values().find(|p| {
+                p.replaces.iter().any(|rule| match meta {
+                    Some(meta) => answers(meta, rule),
+                    None => rule.name == *name && rule.predicate.matches(version),
+                })
+            });
+            if let Some(replacer) = replacer {
+                removals.push(Removal {
+                    name: name.clone(),
+                    version: version.clone(),
+                    replaced_by: replacer.id.clone(),
+                });
+            }
+        }
+
+        let mut suggestions = Vec::new();
+        for meta in chosen.values() {
+            for dep in meta.depends.iter().filter(|d| !d.is_required()) {
+                let present = installed.contains_key(&dep.name)
+                    || chosen.values().any(|other| answers(other, dep));
+                if !present {
+                    suggestions.push(Suggestion {
+                        package: meta.id.clone(),
+                        dependency: dep.clone(),
+                    });
+                }
+            }
         }
 
         Ok(ResolutionSolution {
             packages,
-            download_urls,
+            plan,
+            removals,
+            suggestions,
+            actions,
+            download_paths,
             sha256_sums,
+            repositories,
         })
     }
+}
 
-    fn resolve_package(
-        &self,
-        name: &str,
-        flavour: &str,
-        arch: &str,
-        selected: &mut HashMap<String, PackageMetadata>,
-        visited: &mut HashSet<String>,
-    ) -> Result<(), DepresError> {
-        if visited.contains(name) {
-            return Err(DepresError::CircularDependency(name.to_string()));
+/// Drop every package that a higher-priority repository also carries under
+/// the same name, arch and flavour. Repositories missing from `repos` rank
+/// below all listed ones.
+fn prefer_repositories(
+    metas: Vec<PackageMetadata>,
+    repos: &[repo::Repository],
+) -> Vec<PackageMetadata> {
+    let priority = |meta: &PackageMetadata| {
+        repos.iter().find(|r| r.name == meta.repo).map_or(i64::MIN, |r| r.priority)
+    };
+
+    let mut best: HashMap<(&str, &str, &str), i64> = HashMap::new();
+    for meta in &metas {
+        let key = (meta.id.name.as_str(), meta.id.arch.as_str(), meta.id.flavour.as_str());
+        let entry = best.entry(key).or_insert(i64::MIN);
+        *entry = (*entry).max(priority(meta));
+    }
+    let keep: Vec<bool> = metas
+        .iter()
+        .map(|meta| {
+            let key = (meta.id.name.as_str(), meta.id.arch.as_str(), meta.id.flavour.as_str());
+            priority(meta) == best[&key]
+        })
+        .collect();
+
+    metas.into_iter().zip(keep).filter(|(_, keep)| *keep).map(|(meta, _)| meta).collect()
+}
+
+/// (name, version, arch, flavour) of the package a repository row belongs to.
+type RowKey = (String, String, String, String);
+
+/// Read a table of package relationships (`dependencies`, `conflicts`,
+/// `replaces`). `columns` selects the related package name, its version
+/// predicate, the dependency kind and the reason; tables without the last
+/// two select NULL for them.
+fn load_relations(
+    conn: &Connection,
+    table: &str,
+    columns: &str,
+) -> Result<HashMap<RowKey, Vec<Dependency>>, DepresError> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT package_name, package_version, package_arch, package_flavour, {} FROM {}",
+        columns, table
+    ))?;
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            (
+                row.get::<_, String>(0)?, // package_name
+                row.get::<_, String>(1)?, // package_version
+                row.get::<_, String>(2)?, // package_arch
+                row.get::<_, String>(3)?, // package_flavour
+            ),
+            row.get::<_, String>(4)?,         // related package name
+            row.get::<_, Option<String>>(5)?, // predicate (TEXT, may be NULL)
+            row.get::<_, Option<String>>(6)?, // kind
+            row.get::<_, Option<String>>(7)?, // reason
+        ))
+    })?;
+
+    let mut relations: HashMap<RowKey, Vec<Dependency>> = HashMap::new();
+    for row in rows {
+        let (pkg_key, name, predicate, kind, reason) = row?;
+        let predicate = match predicate.as_deref() {
+            Some(p) => VersionPredicate::parse(p)?,
+            None => VersionPredicate::Any,
+        };
+        let kind = match kind.as_deref() {
+            Some(k) => DependencyKind::from_node(k)
+                .ok_or_else(|| DepresError::InvalidDependencyKind(k.to_string()))?,
+            None => DependencyKind::Required,
+        };
+        relations.entry(pkg_key).or_default().push(Dependency {
+            name,
+            predicate,
+            kind,
+            reason,
+        });
+    }
+    Ok(relations)
+}
+
+/// Order the selected packages so that dependencies come before dependents.
+///
+/// Dependency cycles are legal (glibc and its locale data, for instance), so
+/// the graph is split into strongly connected components with Tarjan's
+/// algorithm. The components come out dependencies-first; each one is a
+/// group of mutually dependent packages, or a single package. Nodes and
+/// edges are visited in name order, so the same selection always gives the
+/// same order. A dependency on a virtual name points at every selected
+/// package that provides it.
+///
+/// Inside a cycle the order is broken by repeatedly taking the member with
+/// the fewest dependencies on not-yet-placed members of the same cycle,
+/// falling back to the package name on ties.
+fn install_order<'u>(
+    selected: &BTreeMap<String, &'u PackageMetadata>,
+) -> Vec<Vec<&'u PackageMetadata>> {
+    let mut provided_by: HashMap<&str, Vec<&str>> = HashMap::new();
+    for (name, meta) in selected {
+        provided_by.entry(name.as_str()).or_default().push(name.as_str());
+        for provide in &meta.provides {
+            provided_by.entry(provide.name.as_str()).or_default().push(name.as_str());
+        }
+    }
+
+    let graph: BTreeMap<&str, BTreeSet<&str>> = selected
+        .iter()
+        .map(|(name, meta)| {
+            let deps = meta
+                .depends
+                .iter()
+                .flat_map(|dep| provided_by.get(dep.name.as_str()).into_iter().flatten().copied())
+                .collect();
+            (name.as_str(), deps)
+        })
+        .collect();
+
+    let mut tarjan = Tarjan {
+        graph: &graph,
+        next_index: 0,
+        index: HashMap::new(),
+        lowlink: HashMap::new(),
+        stack: Vec::new(),
+        on_stack: HashSet::new(),
+        groups: Vec::new(),
+    };
+    for name in graph.keys() {
+        if !tarjan.index.contains_key(name) {
+            tarjan.visit(name);
+        }
+    }
+
+    tarjan
+        .groups
+        .into_iter()
+        .map(|group| order_cycle(selected, &graph, group))
+        .collect()
+}
+
+struct Tarjan<'a> {
+    graph: &'a BTreeMap<&'a str, BTreeSet<&'a str>>,
+    next_index: usize,
+    index: HashMap<&'a str, usize>,
+    lowlink: HashMap<&'a str, usize>,
+    stack: Vec<&'a str>,
+    on_stack: HashSet<&'a str>,
+    groups: Vec<Vec<&'a str>>,
+}
+
+impl<'a> Tarjan<'a> {
+    fn visit(&mut self, name: &'a str) {
+        self.index.insert(name, self.next_index);
+        self.lowlink.insert(name, self.next_index);
+        self.next_index += 1;
+        self.stack.push(name);
+        self.on_stack.insert(name);
+
+        let graph = self.graph;
+        for &dep in &graph[name] {
+            if !self.index.contains_key(dep) {
+                self.visit(dep);
+                let low = self.lowlink[name].min(self.lowlink[dep]);
+                self.lowlink.insert(name, low);
+            } else if self.on_stack.contains(dep) {
+                let low = self.lowlink[name].min(self.index[dep]);
+                self.lowlink.insert(name, low);
+            }
+        }
+
+        if self.lowlink[name] == self.index[name] {
+            let mut group = Vec::new();
+            while let Some(member) = self.stack.pop() {
+                self.on_stack.remove(member);
+                group.push(member);
+                if member == name {
+                    break;
+                }
+            }
+            self.groups.push(group);
         }
-        visited.insert(name.to_string());
+    }
+}
+
+fn order_cycle<'u>(
+    selected: &BTreeMap<String, &'u PackageMetadata>,
+    graph: &BTreeMap<&str, BTreeSet<&str>>,
+    mut members: Vec<&str>,
+) -> Vec<&'u PackageMetadata> {
+    members.sort_unstable();
+    let mut placed: Vec<&'u PackageMetadata> = Vec::with_capacity(members.len());
+
+    while !members.is_empty() {
+        let unmet = |name: &str| {
+            graph[name]
+                .iter()
+                .filter(|dep| **dep != name && members.contains(dep))
+                .count()
+        };
+        // `min_by_key` keeps the first minimum, and members are name-sorted.
+        let pos = (0..members.len()).min_by_key(|&i| unmet(members[i])).unwrap();
+        placed.push(selected[members.remove(pos)]);
+    }
+
+    placed
+}
+
+/// Upper bound on the number of versions tried before the search gives up.
+const MAX_SEARCH_STEPS: usize = 100_000;
+
+#[derive(Debug, Clone)]
+struct Requirement {
+    dep: Dependency,
+    /// `None` when the package was requested directly.
+    required_by: Option<PackageId>,
+}
+
+impl std::fmt::Display for Requirement {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.required_by {
+            Some(id) => write!(f, "{} (required by {} {})", self.dep, id.name, id.version),
+            None => write!(f, "{} (requested)", self.dep),
+        }
+    }
+}
+
+/// A package chosen by the search, and the name whose decision chose it.
+struct Selection<'u> {
+    meta: &'u PackageMetadata,
+    decision: String,
+}
+
+#[derive(Default)]
+struct SearchState<'u> {
+    /// Chosen packages, by package name.
+    selected: BTreeMap<String, Selection<'u>>,
+    /// Requirements by the name they ask for, real or virtual.
+    requirements: BTreeMap<String, Vec<Requirement>>,
+    /// Names only wanted by soft dependencies that the search left out.
+    dropped: BTreeSet<String>,
+}
+
+/// The requirements that decide `name`: the required ones if there are
+/// any, since a soft dependency never constrains what a required one picks.
+fn deciding(reqs: &[Requirement]) -> Vec<Requirement> {
+    let required: Vec<Requirement> = reqs.iter().filter(|r| r.dep.is_required()).cloned().collect();
+    if required.is_empty() {
+        reqs.to_vec()
+    } else {
+        required
+    }
+}
+
+/// A package version that answers to some name, either its own or one it
+/// provides, together with the version under which it does so. `provided`
+/// is `None` for an unversioned `provides`, which only satisfies
+/// unversioned dependencies.
+#[derive(Debug, Clone, Copy)]
+struct Candidate<'u> {
+    meta: &'u PackageMetadata,
+    provided: Option<&'u Version>,
+}
+
+impl Candidate<'_> {
+    fn accepts(&self, reqs: &[Requirement]) -> bool {
+        reqs.iter().all(|r| self.meets(&r.dep))
+    }
+
+    fn meets(&self, dep: &Dependency) -> bool {
+        match self.provided {
+            Some(v) => dep.predicate.matches(v),
+            None => dep.predicate == VersionPredicate::Any,
+        }
+    }
+
+    fn describe(&self, name: &str) -> String {
+        if self.meta.id.name == name {
+            return format!("{} {}", self.meta.id.name, self.meta.id.version);
+        }
+        match self.provided {
+            Some(v) => format!("{} {} (as {}={})", self.meta.id.name, self.meta.id.version, name, v),
+            None => format!("{} {} (as {})", self.meta.id.name, self.meta.id.version, name),
+        }
+    }
+}
+
+/// Whether `meta` answers to `rule`, under its own name or a provided one.
+fn answers(meta: &PackageMetadata, rule: &Dependency) -> bool {
+    (meta.id.name == rule.name && Candidate { meta, provided: Some(&meta.id.version) }.meets(rule))
+        || meta.provides.iter().any(|p| {
+            p.name == rule.name && Candidate { meta, provided: p.version.as_ref() }.meets(rule)
+        })
+}
+
+/// The first `conflicts` or `replaces` rule of `meta` that rules out `other`.
+/// A package never rules itself out, even when it conflicts with a virtual
+/// name it also provides.
+fn excludes<'m>(
+    meta: &'m PackageMetadata,
+    other: &PackageMetadata,
+) -> Option<(&'static str, &'m Dependency)> {
+    if meta.id.name == other.id.name {
+        return None;
+    }
+    meta.conflicts
+        .iter()
+        .map(|rule| ("conflicts", rule))
+        .chain(meta.replaces.iter().map(|rule| ("replaces", rule)))
+        .find(|(_, rule)| answers(other, rule))
+}
+
+/// "(rule: musl conflicts "glibc")", for conflict messages.
+fn describe_rule(owner: &PackageMetadata, kind: &str, rule: &Dependency) -> String {
+    format!("(rule: {} {} \"{}\")", owner.id.name, kind, rule)
+}
+
+impl PackageUniverse {
+    /// Every package version that answers to `name`: the packages called
+    /// `name`, then the ones that list it in `provides`.
+    fn providers(&self, name: &str, flavour: &str, arch: &str) -> Vec<Candidate<'_>> {
+        let mut out: Vec<Candidate<'_>> = self
+            .candidates(name, flavour, arch)
+            .unwrap_or_default()
+            .iter()
+            .map(|meta| Candidate { meta, provided: Some(&meta.id.version) })
+            .collect();
 
         let key = (name.to_string(), arch.to_string(), flavour.to_string());
-        let candidates = self.packages.get(&key)
-            .ok_or_else(|| DepresError::PackageNotFound(name.to_string()))?;
+        for provider in self.virtuals.get(&key).into_iter().flatten() {
+            let Some(metas) = self.candidates(provider, flavour, arch) else {
+                continue;
+            };
+            for meta in metas {
+                for provide in meta.provides.iter().filter(|p| p.name == name) {
+                    out.push(Candidate { meta, provided: provide.version.as_ref() });
+                }
+            }
+        }
+        out
+    }
+}
 
-        let best = candidates.iter()
-            .max_by_key(|m| &m.id.version)
-            .unwrap();
+/// Why part of the search failed.
+///
+/// `involved` holds the decisions whose outcome led to the failure. If a
+/// decision is not among them, trying its other candidates cannot help.
+#[derive(Debug)]
+struct Conflict {
+    message: String,
+    causes: Vec<Conflict>,
+    involved: BTreeSet<String>,
+}
 
-        if best.id.flavour != flavour {
-            return Err(DepresError::FlavourMismatch {
-                required: best.id.flavour.clone(),
-                system: flavour.to_string(),
-            });
+impl Conflict {
+    fn render(&self, depth: usize, out: &mut String) {
+        out.push_str(&"  ".repeat(depth));
+        out.push_str(&self.message);
+        out.push('\n');
+        for cause in &self.causes {
+            cause.render(depth + 1, out);
         }
+    }
+}
 
-        selected.insert(name.to_string(), best.clone());
+struct Solver<'u> {
+    universe: &'u PackageUniverse,
+    /// Installed package versions that no repository carries.
+    installed_only: &'u [PackageMetadata],
+    flavour: &'u str,
+    arch: &'u str,
+    installed: &'u HashMap<String, Version>,
+    policy: DependencyPolicy,
+    steps: usize,
+}
+
+impl<'u> Solver<'u> {
+    fn search(&mut self, state: &mut SearchState<'u>) -> Result<(), Conflict> {
+        // Decide the open name with the fewest admissible candidates next,
+        // names only wanted by soft dependencies after all others; ties are
+        // broken by name so the result is deterministic.
+        let mut next: Option<(&String, bool, Vec<Candidate<'u>>)> = None;
+        for (name, reqs) in &state.requirements {
+            let reqs = deciding(reqs);
+            let soft = !reqs.iter().any(|r| r.dep.is_required());
+            if (soft && state.dropped.contains(name)) || self.satisfied(state, name, &reqs) {
+                continue;
+            }
+            let (admissible, _) = self.admissible(state, name, &reqs);
+            if next.as_ref().map_or(true, |(_, best_soft, best)| {
+                (soft, admissible.len()) < (*best_soft, best.len())
+            }) {
+                next = Some((name, soft, admissible));
+            }
+        }
+        let Some((name, soft, candidates)) = next else {
+            return self.check_installed(state);
+        };
+        let name = name.clone();
+        let reqs = deciding(&state.requirements[&name]);
+        let mut involved: BTreeSet<String> = reqs
+            .iter()
+            .filter_map(|r| r.required_by.as_ref())
+            .map(|id| state.selected[&id.name].decision.clone())
+            .collect();
+
+        if candidates.is_empty() && !soft {
+            return Err(self.no_candidates(state, &name, &reqs, involved));
+        }
+
+        let mut causes = Vec::new();
+
+        for cand in candidates {
+            self.steps += 1;
+            if self.steps > MAX_SEARCH_STEPS {
+                return Err(Conflict {
+                    message: format!("gave up after trying {} package versions", MAX_SEARCH_STEPS),
+                    causes: Vec::new(),
+                    involved: BTreeSet::new(),
+                });
+            }
+
+            if let Some((chosen, message)) = self.clash(state, cand.meta) {
+                involved.insert(chosen);
+                causes.push(Conflict {
+                    message: format!("{} {}", cand.describe(&name), message),
+                    causes: Vec::new(),
+                    involved: BTreeSet::new(),
+                });
+                continue;
+            }
+
+            let pkg_name = cand.meta.id.name.clone();
+            state.selected.insert(pkg_name.clone(), Selection {
+                meta: cand.meta,
+                decision: name.clone(),
+            });
+            let wanted = self.wanted(cand.meta);
+            for dep in &wanted {
+                state.requirements.entry(dep.name.clone()).or_default().push(Requirement {
+                    dep: (*dep).clone(),
+                    required_by: Some(cand.meta.id.clone()),
+                });
+            }
+
+            let conflict = match self.search(state) {
+                Ok(()) => return Ok(()),
+                Err(conflict) => conflict,
+            };
+
+            state.selected.remove(&pkg_name);
+            for dep in wanted.iter().rev() {
+                let reqs = state.requirements.get_mut(&dep.name).unwrap();
+                reqs.pop();
+                if reqs.is_empty() {
+                    state.requirements.remove(&dep.name);
+                }
+            }
 
-        for dep in &best.depends {
-            if !selected.contains_key(&dep.name) {
-                self.resolve_package(&dep.name, flavour, arch, selected, visited)?;
+            if !conflict.involved.contains(&name) {
+                return Err(conflict);
             }
+            involved.extend(conflict.involved.iter().filter(|n| **n != name).cloned());
+            causes.push(Conflict {
+                message: format!("{} cannot be used:", cand.describe(&name)),
+                causes: vec![conflict],
+                involved: BTreeSet::new(),
+            });
+        }
+
+        // A soft dependency can also be left out; it becomes a suggestion.
+        if soft {
+            state.dropped.insert(name.clone());
+            let conflict = match self.search(state) {
+                Ok(()) => return Ok(()),
+                Err(conflict) => conflict,
+            };
+            state.dropped.remove(&name);
+            involved.extend(conflict.involved.iter().cloned());
+            causes.push(Conflict {
+                message: format!("leaving out {} does not help either:", name),
+                causes: vec![conflict],
+                involved: BTreeSet::new(),
+            });
         }
 
-        visited.remove(name);
+        let wanted: Vec<String> = reqs.iter().map(|r| r.to_string()).collect();
+        Err(Conflict {
+            message: format!("no version of {} works for {}:", name, wanted.join(", ")),
+            causes,
+            involved,
+        })
+    }
+
+    /// The dependencies of `meta` the search tries to satisfy: all required
+    /// ones, plus the soft ones the policy follows.
+    fn wanted(&self, meta: &'u PackageMetadata) -> Vec<&'u Dependency> {
+        meta.depends.iter().filter(|dep| self.policy.follows(dep.kind)).collect()
+    }
+
+    /// A chosen package that cannot be installed alongside `meta`, because
+    /// either of them conflicts with or replaces the other. Returns the
+    /// decision that chose it and a description of the clash.
+    fn clash(&self, state: &SearchState<'u>, meta: &PackageMetadata) -> Option<(String, String)> {
+        state.selected.values().find_map(|selection| {
+            let chosen = selection.meta;
+            let (owner, kind, rule) = match excludes(meta, chosen) {
+                Some((kind, rule)) => (meta, kind, rule),
+                None => {
+                    let (kind, rule) = excludes(chosen, meta)?;
+                    (chosen, kind, rule)
+                }
+            };
+            let message = format!(
+                "conflicts with the selected {} {} {}",
+                chosen.id.name,
+                chosen.id.version,
+                describe_rule(owner, kind, rule)
+            );
+            Some((selection.decision.clone(), message))
+        })
+    }
+
+    /// Check a complete selection against the installed packages it leaves
+    /// in place. Installed packages that a chosen package replaces are not
+    /// checked, since they are removed by the same transaction.
+    fn check_installed(&self, state: &SearchState<'u>) -> Result<(), Conflict> {
+        let installed: BTreeMap<&String, &Version> = self.installed.iter().collect();
+        for (name, version) in installed {
+            if state.selected.contains_key(name) {
+                continue;
+            }
+            let meta = self.installed_meta(name, version);
+            let hits = |rule: &Dependency| match &meta {
+                Some(meta) => answers(meta, rule),
+                None => rule.name == *name && rule.predicate.matches(version),
+            };
+            if state.selected.values().any(|s| s.meta.replaces.iter().any(|r| hits(r))) {
+                continue;
+            }
+
+            for selection in state.selected.values() {
+                let chosen = selection.meta;
+                let rule = match chosen.conflicts.iter().find(|r| hits(r)) {
+                    Some(rule) => Some((chosen, "conflicts", rule)),
+                    None => meta.and_then(|meta| {
+                        excludes(meta, chosen).map(|(kind, rule)| (meta, kind, rule))
+                    }),
+                };
+                if let Some((owner, kind, rule)) = rule {
+                    return Err(Conflict {
+                        message: format!(
+                            "{} {} conflicts with the installed {} {} {}",
+                            chosen.id.name,
+                            chosen.id.version,
+                            name,
+                            version,
+                            describe_rule(owner, kind, rule)
+                        ),
+                        causes: Vec::new(),
+                        involved: BTreeSet::from([selection.decision.clone()]),
+                    });
+                }
+            }
+        }
         Ok(())
     }
+
+    /// Every package version that answers to `name`, including installed
+    /// ones that no repository carries.
+    fn providers(&self, name: &str) -> Vec<Candidate<'u>> {
+        let mut out = self.universe.providers(name, self.flavour, self.arch);
+        out.extend(
+            self.installed_only
+                .iter()
+                .filter(|meta| meta.id.name == name)
+                .map(|meta| Candidate { meta, provided: Some(&meta.id.version) }),
+        );
+        out
+    }
+
+    /// Repository metadata for an installed package, when the repository
+    /// still carries that version.
+    fn installed_meta(&self, name: &str, version: &Version) -> Option<&'u PackageMetadata> {
+        self.universe
+            .candidates(name, self.flavour, self.arch)?
+            .iter()
+            .find(|meta| meta.id.version == *version)
+    }
+
+    /// Whether a package chosen so far already answers to `name` in a way
+    /// that meets all of `reqs`.
+    fn satisfied(&self, state: &SearchState<'u>, name: &str, reqs: &[Requirement]) -> bool {
+        state.selected.values().any(|selection| {
+            let meta = selection.meta;
+            let own = Candidate { meta, provided: Some(&meta.id.version) };
+            (meta.id.name == name && own.accepts(reqs))
+                || meta.provides.iter().any(|p| {
+                    p.name == name && Candidate { meta, provided: p.version.as_ref() }.accepts(reqs)
+                })
+        })
+    }
+
+    /// Candidates for `name` that meet `reqs`, best first, plus the ones that
+    /// would have met them but clash with a different version of the same
+    /// package that is already chosen.
+    ///
+    /// The preference is: a version that is already installed (unless the
+    /// user asked for `name` itself and this is the package of that name),
+    /// then the package actually called `name`, then other providers by
+    /// package name; newer versions first within one package.
+    fn admissible(
+        &self,
+        state: &SearchState<'u>,
+        name: &str,
+        reqs: &[Requirement],
+    ) -> (Vec<Candidate<'u>>, Vec<Candidate<'u>>) {
+        let (mut admissible, blocked): (Vec<Candidate<'u>>, Vec<Candidate<'u>>) = self
+            .providers(name)
+            .into_iter()
+            .filter(|c| c.accepts(reqs))
+            .partition(|c| !state.selected.contains_key(&c.meta.id.name));
+
+        let requested = reqs.iter().any(|r| r.required_by.is_none());
+        let kept = |c: &Candidate<'u>| {
+            !(requested && c.meta.id.name == name)
+                && self.installed.get(&c.meta.id.name) == Some(&c.meta.id.version)
+        };
+        admissible.sort_by(|a, b| {
+            kept(b)
+                .cmp(&kept(a))
+                .then_with(|| (b.meta.id.name == name).cmp(&(a.meta.id.name == name)))
+                .then_with(|| a.meta.id.name.cmp(&b.meta.id.name))
+                .then_with(|| b.meta.id.version.cmp(&a.meta.id.version))
+        });
+        (admissible, blocked)
+    }
+
+    fn no_candidates(
+        &self,
+        state: &SearchState<'u>,
+        name: &str,
+        reqs: &[Requirement],
+        mut involved: BTreeSet<String>,
+    ) -> Conflict {
+        let wanted: Vec<String> = reqs.iter().map(|r| r.to_string()).collect();
+        let mut all = self.providers(name);
+        if all.is_empty() {
+            return Conflict {
+                message: format!(
+                    "{} is not available for {}/{}, but is needed by {}",
+                    name, self.flavour, self.arch, wanted.join(", ")
+                ),
+                causes: Vec::new(),
+                involved,
+            };
+        }
+
+        let mut causes = Vec::new();
+        let (_, blocked) = self.admissible(state, name, reqs);
+        for cand in blocked {
+            let chosen = &state.selected[&cand.meta.id.name];
+            involved.insert(chosen.decision.clone());
+            causes.push(Conflict {
+                message: format!(
+                    "{} clashes with the selected {} {}",
+                    cand.describe(name),
+                    chosen.meta.id.name,
+                    chosen.meta.id.version
+                ),
+                causes: Vec::new(),
+                involved: BTreeSet::new(),
+            });
+        }
+
+        all.sort_by(|a, b| {
+            (b.meta.id.name == name)
+                .cmp(&(a.meta.id.name == name))
+                .then_with(|| a.meta.id.name.cmp(&b.meta.id.name))
+                .then_with(|| a.meta.id.version.cmp(&b.meta.id.version))
+        });
+        let available: Vec<String> = all
+            .iter()
+            .map(|c| {
+                if c.meta.id.name == name {
+                    c.meta.id.version.to_string()
+                } else {
+                    c.describe(name)
+                }
+            })
+            .collect();
+
+        Conflict {
+            message: format!(
+                "no version of {} satisfies {} (available: {})",
+                name,
+                wanted.join(", "),
+                available.join(", ")
+            ),
+            causes,
+            involved,
+        }
+    }
+}
+
+/// Which soft dependencies a resolution follows. Required dependencies are
+/// always followed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DependencyPolicy {
+    pub recommends: bool,
+    pub optional: bool,
 }
 
-fn parse_dependency(s: &str) -> Option<(String, Option<String>)> {
-    let re = Regex::new(r"^([a-zA-Z0-9._-]+)([<>=!]+)?(.*)$").ok()?;
-    if let Some(caps) = re.captures(s) {
-        let name = caps.get(1)?.as_str().to_string();
-        let op = caps.get(2)?.as_str();
-        let version = caps.get(3)?.as_str();
-        if version.is_empty() {
-            Some((name, None))
-        } else {
-            Some((name, Some(format!("{}{}", op, version))))
+impl Default for DependencyPolicy {
+    fn default() -> Self {
+        Self {
+            recommends: true,
+            optional: false,
         }
-    } else {
-        Some((s.to_string(), None))
     }
 }
 
+impl DependencyPolicy {
+    pub fn follows(&self, kind: DependencyKind) -> bool {
+        match kind {
+            DependencyKind::Required => true,
+            DependencyKind::Recommended => self.recommends,
+            DependencyKind::Optional => self.optional,
+        }
+    }
+}
+
+/// What a transaction does to one package of the solution, compared with
+/// what is installed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Install,
+    Upgrade { from: Version },
+    Downgrade { from: Version },
+    Reinstall,
+    Keep,
+}
+
+impl std::fmt::Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let label = match self {
+            Action::Install => "install",
+            Action::Upgrade { .. } => "upgrade",
+            Action::Downgrade { .. } => "downgrade",
+            Action::Reinstall => "reinstall",
+            Action::Keep => "keep",
+        };
+        f.pad(label)
+    }
+}
+
+/// One step of an install plan: a single package, or a dependency cycle
+/// whose members have to be installed together.
+#[derive(Debug, Clone)]
+pub struct InstallStep {
+    pub packages: Vec<PackageId>,
+}
+
+/// An installed package that a selected package replaces. It is removed in
+/// the same transaction that installs its replacement.
+#[derive(Debug, Clone)]
+pub struct Removal {
+    pub name: String,
+    pub version: Version,
+    pub replaced_by: PackageId,
+}
+
+/// A recommended or optional dependency that the solution leaves out.
+#[derive(Debug, Clone)]
+pub struct Suggestion {
+    pub package: PackageId,
+    pub dependency: Dependency,
+}
+
 #[derive(Debug)]
 pub struct ResolutionSolution {
+    /// Every selected package, in install order (see `install_order`).
     pub packages: Vec<PackageId>,
-    pub download_urls: HashMap<String, String>,
+    /// The same packages grouped into steps; every step only depends on
+    /// itself and on earlier steps.
+    pub plan: Vec<InstallStep>,
+    pub removals: Vec<Removal>,
+    pub suggestions: Vec<Suggestion>,
+    pub actions: HashMap<String, Action>,
+    pub download_paths: HashMap<String, String>,
     pub sha256_sums: HashMap<String, String>,
+    /// Name of the repository each package comes from.
+    pub repositories: HashMap<String, String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pkg(name: &str, version: &str, depends: &[&str]) -> PackageMetadata {
+        PackageMetadata {
+            id: PackageId {
+                name: name.to_string(),
+                version: Version::parse(version).unwrap(),
+                arch: "x86_64".to_string(),
+                flavour: "glibc-systemd".to_string(),
+            },
+            repo: "core".to_string(),
+            path: format!("glibc-systemd/core/x86_64/{}-{}-x86_64.kpkg", name, version),
+            sha256: String::new(),
+            depends: depends.iter().map(|d| Dependency::parse(d).unwrap()).collect(),
+            provides: Vec::new(),
+            conflicts: Vec::new(),
+            replaces: Vec::new(),
+        }
+    }
+
+    fn provider(name: &str, version: &str, provides: &[&str], depends: &[&str]) -> PackageMetadata {
+        let mut meta = pkg(name, version, depends);
+        meta.provides = provides.iter().map(|p| Provide::parse(p).unwrap()).collect();
+        meta
+    }
+
+    fn with_rules(mut meta: PackageMetadata, conflicts: &[&str], replaces: &[&str]) -> PackageMetadata {
+        meta.conflicts = conflicts.iter().map(|c| Dependency::parse(c).unwrap()).collect();
+        meta.replaces = replaces.iter().map(|r| Dependency::parse(r).unwrap()).collect();
+        meta
+    }
+
+    fn installed(packages: &[(&str, &str)]) -> HashMap<String, Version> {
+        packages
+            .iter()
+            .map(|(n, v)| (n.to_string(), Version::parse(v).unwrap()))
+            .collect()
+    }
+
+    fn resolve(universe: &PackageUniverse, roots: &[&str]) -> Result<Vec<String>, DepresError> {
+        let roots: Vec<String> = roots.iter().map(|s| s.to_string()).collect();
+        let solution = universe.resolve(
+            &roots,
+            "glibc-systemd",
+            "x86_64",
+            &HashMap::new(),
+            DependencyPolicy::default(),
+        )?;
+        let mut picked: Vec<String> = solution
+            .packages
+            .iter()
+            .map(|id| format!("{}-{}", id.name, id.version))
+            .collect();
+        picked.sort();
+        Ok(picked)
+    }
+
+    #[test]
+    fn picks_newest_by_version_order() {
+        let universe = PackageUniverse::from_packages([
+            pkg("foo", "1.9", &[]),
+            pkg("foo", "1.10", &[]),
+            pkg("foo", "1.10~rc1", &[]),
+        ]);
+        assert_eq!(resolve(&universe, &["foo"]).unwrap(), ["foo-1.10"]);
+    }
+
+    #[test]
+    fn honours_predicates() {
+        let universe = PackageUniverse::from_packages([
+            pkg("app", "1.0", &["lib >=2, <3"]),
+            pkg("lib", "1.0", &[]),
+            pkg("lib", "2.5", &[]),
+            pkg("lib", "3.0", &[]),
+        ]);
+        assert_eq!(resolve(&universe, &["app"]).unwrap(), ["app-1.0", "lib-2.5"]);
+        assert_eq!(resolve(&universe, &["lib<2"]).unwrap(), ["lib-1.0"]);
+    }
+
+    #[test]
+    fn backtracks_to_older_version() {
+        // The newest app needs a lib that conflicts with what tool needs.
+        let universe = PackageUniverse::from_packages([
+            pkg("app", "2.0", &["lib>=2"]),
+            pkg("app", "1.0", &["lib>=1"]),
+            pkg("tool", "1.0", &["lib<2"]),
+            pkg("lib", "1.5", &[]),
+            pkg("lib", "2.0", &[]),
+        ]);
+        assert_eq!(
+            resolve(&universe, &["app", "tool"]).unwrap(),
+            ["app-1.0", "lib-1.5", "tool-1.0"]
+        );
+    }
+
+    #[test]
+    fn backtracks_through_transitive_dependencies() {
+        let universe = PackageUniverse::from_packages([
+            pkg("a", "2.0", &["b=2.0"]),
+            pkg("a", "1.0", &["b=1.0"]),
+            pkg("b", "2.0", &["c>=2"]),
+            pkg("b", "1.0", &["c"]),
+            pkg("c", "1.0", &[]),
+        ]);
+        assert_eq!(resolve(&universe, &["a"]).unwrap(), ["a-1.0", "b-1.0", "c-1.0"]);
+    }
+
+    fn install_names(solution: &ResolutionSolution) -> Vec<&str> {
+        solution.packages.iter().map(|id| id.name.as_str()).collect()
+    }
+
+    fn install_order_of(universe: &PackageUniverse, roots: &[&str]) -> Vec<String> {
+        let roots: Vec<String> = roots.iter().map(|s| s.to_string()).collect();
+        let solution = universe.resolve(
+            &roots,
+            "glibc-systemd",
+            "x86_64",
+            &HashMap::new(),
+            DependencyPolicy::default(),
+        ).unwrap();
+        solution.packages.iter().map(|id| id.name.clone()).collect()
+    }
+
+    #[test]
+    fn diamond_is_not_a_cycle() {
+        let universe = PackageUniverse::from_packages([
+            pkg("app", "1.0", &["left", "right"]),
+            pkg("left", "1.0", &["base"]),
+            pkg("right", "1.0", &["base"]),
+            pkg("base", "1.0", &[]),
+        ]);
+        assert_eq!(install_order_of(&universe, &["app"]), ["base", "left", "right", "app"]);
+    }
+
+    #[test]
+    fn accepts_cycles_with_stable_order() {
+        let universe = PackageUniverse::from_packages([
+            pkg("htop", "1.0", &["glibc", "ncurses"]),
+            pkg("ncurses", "1.0", &["glibc"]),
+            pkg("glibc", "1.0", &["glibc-locales", "linux-api"]),
+            pkg("glibc-locales", "1.0", &["glibc"]),
+            pkg("linux-api", "1.0", &[]),
+        ]);
+        let expected = ["linux-api", "glibc", "glibc-locales", "ncurses", "htop"];
+        assert_eq!(install_order_of(&universe, &["htop"]), expected);
+        assert_eq!(install_order_of(&universe, &["ncurses", "htop"]), expected);
+
+        let solution = universe
+            .resolve(
+                &["htop".to_string()],
+                "glibc-systemd",
+                "x86_64",
+                &HashMap::new(),
+                DependencyPolicy::default(),
+            )
+            .unwrap();
+        let steps: Vec<Vec<&str>> = solution
+            .plan
+            .iter()
+            .map(|step| step.packages.iter().map(|id| id.name.as_str()).collect())
+            .collect();
+        assert_eq!(
+            steps,
+            [vec!["linux-api"], vec!["glibc", "glibc-locales"], vec!["ncurses"], vec!["htop"]]
+        );
+    }
+
+    #[test]
+    fn plan_installs_dependencies_first() {
+        let universe = PackageUniverse::from_packages([
+            pkg("editor", "1.0", &["gui", "libc"]),
+            pkg("gui", "1.0", &["toolkit"]),
+            pkg("toolkit", "1.0", &["gui", "libc"]),
+            pkg("libc", "1.0", &[]),
+        ]);
+        let solution = universe
+            .resolve(
+                &["editor".to_string()],
+                "glibc-systemd",
+                "x86_64",
+                &HashMap::new(),
+                DependencyPolicy::default(),
+            )
+            .unwrap();
+        let steps: Vec<Vec<&str>> = solution
+            .plan
+            .iter()
+            .map(|step| step.packages.iter().map(|id| id.name.as_str()).collect())
+            .collect();
+        assert_eq!(steps, [vec!["libc"], vec!["gui", "toolkit"], vec!["editor"]]);
+
+        // Every dependency is installed in an earlier step or the same one.
+        for (i, step) in steps.iter().enumerate() {
+            let available: Vec<&str> = steps[..=i].iter().flatten().copied().collect();
+            for name in step {
+                let meta = universe.newest(name, "glibc-systemd", "x86_64").unwrap();
+                for dep in &meta.depends {
+                    assert!(available.contains(&dep.name.as_str()), "{} before {}", dep.name, name);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn breaks_cycles_by_fewest_unmet_dependencies() {
+        // b depends on both other members, so it goes last; a and c tie on
+        // one unmet dependency each and are taken by name.
+        let universe = PackageUniverse::from_packages([
+            pkg("a", "1.0", &["b"]),
+            pkg("b", "1.0", &["a", "c"]),
+            pkg("c", "1.0", &["a"]),
+        ]);
+        assert_eq!(install_order_of(&universe, &["b"]), ["a", "c", "b"]);
+    }
+
+    #[test]
+    fn classifies_against_installed_packages() {
+        let universe = PackageUniverse::from_packages([
+            pkg("htop", "3.3", &["ncurses", "glibc"]),
+            pkg("htop", "3.2", &["ncurses", "glibc"]),
+            pkg("ncurses", "6.5", &["glibc"]),
+            pkg("glibc", "2.40", &[]),
+            pkg("glibc", "2.39", &[]),
+            pkg("zlib", "1.3", &[]),
+            pkg("zlib", "1.2", &[]),
+        ]);
+        let installed: HashMap<String, Version> = [
+            ("htop", "3.2"),
+            ("glibc", "2.39"),
+            ("zlib", "1.3"),
+        ]
+        .iter()
+        .map(|(n, v)| (n.to_string(), Version::parse(v).unwrap()))
+        .collect();
+
+        let roots: Vec<String> = ["htop", "zlib<1.3"].iter().map(|s| s.to_string()).collect();
+        let solution = universe.resolve(
+            &roots,
+            "glibc-systemd",
+            "x86_64",
+            &installed,
+            DependencyPolicy::default(),
+        ).unwrap();
+        let action = |name: &str| solution.actions[name].clone();
+
+        assert_eq!(action("htop"), Action::Upgrade { from: Version::parse("3.2").unwrap() });
+        assert_eq!(action("ncurses"), Action::Install);
+        assert_eq!(action("glibc"), Action::Keep);
+        assert_eq!(action("zlib"), Action::Downgrade { from: Version::parse("1.3").unwrap() });
+
+        let roots = vec!["glibc".to_string()];
+        let installed: HashMap<String, Version> =
+            [("glibc".to_string(), Version::parse("2.40").unwrap())].into_iter().collect();
+        let solution = universe.resolve(
+            &roots,
+            "glibc-systemd",
+            "x86_64",
+            &installed,
+            DependencyPolicy::default(),
+        ).unwrap();
+        assert_eq!(solution.actions["glibc"], Action::Reinstall);
+    }
+
+    #[test]
+    fn keeps_installed_packages_no_repository_carries() {
+        let universe = PackageUniverse::from_packages([
+            pkg("app", "1.0", &["oldlib>=1"]),
+            pkg("tool", "1.0", &["oldlib>=2"]),
+            pkg("oldlib", "0.9", &[]),
+        ]);
+        let solve = |root: &str, installed: &HashMap<String, Version>| {
+            universe.resolve(
+                &[root.to_string()],
+                "glibc-systemd",
+                "x86_64",
+                installed,
+                DependencyPolicy::default(),
+            )
+        };
+
+        let solution = solve("app", &installed(&[("oldlib", "1.2")])).unwrap();
+        assert_eq!(install_names(&solution), ["oldlib", "app"]);
+        assert_eq!(solution.packages[0].version.to_string(), "1.2");
+        assert_eq!(solution.actions["oldlib"], Action::Keep);
+        assert_eq!(solution.actions["app"], Action::Install);
+
+        // Only the installed version is available, and only where it matches.
+        let tool = solve("tool", &installed(&[("oldlib", "1.2")]));
+        assert!(matches!(tool, Err(DepresError::NoSolution(_))));
+        assert!(matches!(solve("app", &HashMap::new()), Err(DepresError::NoSolution(_))));
+    }
+
+    #[test]
+    fn reports_conflicting_constraints() {
+        let universe = PackageUniverse::from_packages([
+            pkg("app", "1.0", &["lib>=2"]),
+            pkg("tool", "1.0", &["lib<2"]),
+            pkg("lib", "1.0", &[]),
+            pkg("lib", "2.0", &[]),
+        ]);
+        let err = resolve(&universe, &["app", "tool"]).unwrap_err();
+        let DepresError::NoSolution(derivation) = err else {
+            panic!("expected NoSolution, got {:?}", err);
+        };
+        assert!(
+            derivation.contains(
+                "no version of lib satisfies lib >=2 (required by app 1.0), \
+                 lib <2 (required by tool 1.0) (available: 1.0, 2.0)"
+            ),
+            "{}",
+            derivation
+        );
+    }
+
+    #[test]
+    fn reports_clash_with_selected_version() {
+        let universe = PackageUniverse::from_packages([
+            provider("lib", "1.0", &["libc"], &[]),
+            pkg("lib", "2.0", &[]),
+            pkg("tool", "1.0", &["lib>=2"]),
+        ]);
+        let err = resolve(&universe, &["libc", "tool"]).unwrap_err();
+        let DepresError::NoSolution(derivation) = err else {
+            panic!("expected NoSolution, got {:?}", err);
+        };
+        assert!(
+            derivation.contains("lib 2.0 clashes with the selected lib 1.0"),
+            "{}",
+            derivation
+        );
+    }
+
+    #[test]
+    fn upgrades_kept_dependency_when_required() {
+        let universe = PackageUniverse::from_packages([
+            pkg("app", "1.0", &["lib", "tool"]),
+            pkg("tool", "1.0", &["lib>=2"]),
+            pkg("lib", "1.0", &[]),
+            pkg("lib", "2.0", &[]),
+        ]);
+        let installed: HashMap<String, Version> =
+            [("lib".to_string(), Version::parse("1.0").unwrap())].into_iter().collect();
+        // lib 1.0 is kept until tool asks for more, then the solver moves on.
+        let roots = vec!["app".to_string()];
+        let solution = universe.resolve(
+            &roots,
+            "glibc-systemd",
+            "x86_64",
+            &installed,
+            DependencyPolicy::default(),
+        ).unwrap();
+        assert_eq!(
+            solution.actions["lib"],
+            Action::Upgrade { from: Version::parse("1.0").unwrap() }
+        );
+    }
+
+    #[test]
+    fn satisfies_virtual_dependencies() {
+        let universe = PackageUniverse::from_packages([
+            pkg("script", "1.0", &["sh"]),
+            provider("dash", "0.5", &["sh"], &[]),
+            provider("bash", "5.2", &["sh=5.2"], &[]),
+        ]);
+        // With no real sh and nothing installed, providers go by name.
+        assert_eq!(resolve(&universe, &["script"]).unwrap(), ["bash-5.2", "script-1.0"]);
+
+        // An installed provider is kept.
+        let installed: HashMap<String, Version> =
+            [("dash".to_string(), Version::parse("0.5").unwrap())].into_iter().collect();
+        let roots = vec!["script".to_string()];
+        let solution = universe.resolve(
+            &roots,
+            "glibc-systemd",
+            "x86_64",
+            &installed,
+            DependencyPolicy::default(),
+        ).unwrap();
+        assert_eq!(install_names(&solution), ["dash", "script"]);
+        assert_eq!(solution.actions["dash"], Action::Keep);
+    }
+
+    #[test]
+    fn versioned_virtual_dependencies() {
+        let universe = PackageUniverse::from_packages([
+            pkg("script", "1.0", &["sh>=5"]),
+            provider("dash", "0.5", &["sh"], &[]),
+            provider("bash", "5.2", &["sh=5.2"], &[]),
+            provider("yash", "2.5", &["sh=4.0"], &[]),
+        ]);
+        // dash only provides an unversioned sh, yash a too old one.
+        assert_eq!(resolve(&universe, &["script"]).unwrap(), ["bash-5.2", "script-1.0"]);
+        // The virtual name can be requested directly as well.
+        assert_eq!(resolve(&universe, &["sh<5"]).unwrap(), ["yash-2.5"]);
+    }
+
+    #[test]
+    fn real_package_preferred_over_providers() {
+        let universe = PackageUniverse::from_packages([
+            pkg("app", "1.0", &["libc", "sh"]),
+            pkg("libc", "1.0", &[]),
+            provider("musl", "1.2", &["libc=1.0"], &[]),
+            provider("busybox", "1.36", &["sh"], &["libc"]),
+        ]);
+        let roots = vec!["app".to_string()];
+        let solution = universe.resolve(
+            &roots,
+            "glibc-systemd",
+            "x86_64",
+            &HashMap::new(),
+            DependencyPolicy::default(),
+        ).unwrap();
+        assert_eq!(install_names(&solution), ["libc", "busybox", "app"]);
+    }
+
+    #[test]
+    fn virtual_dependencies_order_installation() {
+        let universe = PackageUniverse::from_packages([
+            pkg("aaa", "1.0", &["zzz-virtual"]),
+            provider("zzz", "1.0", &["zzz-virtual"], &[]),
+        ]);
+        let roots = vec!["aaa".to_string()];
+        let solution = universe.resolve(
+            &roots,
+            "glibc-systemd",
+            "x86_64",
+            &HashMap::new(),
+            DependencyPolicy::default(),
+        ).unwrap();
+        assert_eq!(install_names(&solution), ["zzz", "aaa"]);
+    }
+
+    #[test]
+    fn reports_unsatisfiable_predicate() {
+        let universe = PackageUniverse::from_packages([
+            pkg("app", "1.0", &["lib>=5"]),
+            pkg("lib", "1.0", &[]),
+            pkg("lib", "2.0", &[]),
+        ]);
+        let err = resolve(&universe, &["app"]).unwrap_err();
+        let DepresError::NoSolution(derivation) = err else {
+            panic!("expected NoSolution, got {:?}", err);
+        };
+        assert!(
+            derivation.contains(
+                "no version of lib satisfies lib >=5 (required by app 1.0) (available: 1.0, 2.0)"
+            ),
+            "{}",
+            derivation
+        );
+    }
+
+    #[test]
+    fn reports_missing_dependency() {
+        let universe = PackageUniverse::from_packages([pkg("app", "1.0", &["ghost"])]);
+        let err = resolve(&universe, &["app"]).unwrap_err();
+        let DepresError::NoSolution(derivation) = err else {
+            panic!("expected NoSolution, got {:?}", err);
+        };
+        assert!(derivation.contains("ghost is not available"), "{}", derivation);
+        assert!(matches!(
+            resolve(&universe, &["nope"]),
+            Err(DepresError::PackageNotFound(name)) if name == "nope"
+        ));
+    }
+
+    #[test]
+    fn reports_root_of_another_flavour() {
+        let mut busybox = pkg("busybox", "1.36", &[]);
+        busybox.id.flavour = "musl-openrc".to_string();
+        let universe = PackageUniverse::from_packages([busybox]);
+        assert!(matches!(
+            resolve(&universe, &["busybox"]),
+            Err(DepresError::FlavourMismatch { required, system })
+                if required == "musl-openrc" && system == "glibc-systemd"
+        ));
+    }
+
+    #[test]
+    fn avoids_conflicting_packages() {
+        let universe = PackageUniverse::from_packages([
+            with_rules(provider("musl", "1.2", &["libc"], &[]), &["glibc"], &[]),
+            provider("glibc", "2.39", &["libc"], &[]),
+            pkg("app", "1.0", &["libc", "glibc"]),
+        ]);
+        assert_eq!(resolve(&universe, &["app"]).unwrap(), vec!["app-1.0", "glibc-2.39"]);
+
+        let err = resolve(&universe, &["glibc", "musl"]).unwrap_err();
+        let DepresError::NoSolution(derivation) = err else {
+            panic!("expected NoSolution, got {:?}", err);
+        };
+        assert!(
+            derivation.contains(
+                "musl 1.2 conflicts with the selected glibc 2.39 (rule: musl conflicts \"glibc\")"
+            ),
+            "{}",
+            derivation
+        );
+    }
+
+    #[test]
+    fn conflicts_with_installed_packages() {
+        let universe = PackageUniverse::from_packages([
+            with_rules(pkg("app", "2.0", &[]), &["legacy<2"], &[]),
+            pkg("app", "1.0", &[]),
+            pkg("legacy", "1.0", &[]),
+        ]);
+        let roots = vec!["app".to_string()];
+        let solution = universe
+            .resolve(
+                &roots,
+                "glibc-systemd",
+                "x86_64",
+                &installed(&[("legacy", "1.0")]),
+                DependencyPolicy::default(),
+            )
+            .unwrap();
+        assert_eq!(solution.packages[0].version.to_string(), "1.0");
+
+        let roots = vec!["app>=2".to_string()];
+        let err = universe
+            .resolve(
+                &roots,
+                "glibc-systemd",
+                "x86_64",
+                &installed(&[("legacy", "1.0")]),
+                DependencyPolicy::default(),
+            )
+            .unwrap_err();
+        let DepresError::NoSolution(derivation) = err else {
+            panic!("expected NoSolution, got {:?}", err);
+        };
+        assert!(
+            derivation.contains(
+                "app 2.0 conflicts with the installed legacy 1.0 (rule: app conflicts \"legacy <2\")"
+            ),
+            "{}",
+            derivation
+        );
+    }
+
+    #[test]
+    fn replaced_packages_are_removed() {
+        let universe = PackageUniverse::from_packages([
+            with_rules(pkg("procps-ng", "4.0", &[]), &[], &["procps"]),
+            pkg("procps", "3.3", &[]),
+            pkg("top", "1.0", &["procps"]),
+        ]);
+        let roots = vec!["procps-ng".to_string()];
+        let solution = universe
+            .resolve(
+                &roots,
+                "glibc-systemd",
+                "x86_64",
+                &installed(&[("procps", "3.3")]),
+                DependencyPolicy::default(),
+            )
+            .unwrap();
+        assert_eq!(solution.removals.len(), 1);
+        assert_eq!(solution.removals[0].name, "procps");
+        assert_eq!(solution.removals[0].replaced_by.name, "procps-ng");
+
+        // A replaced package cannot be installed next to its replacement.
+        assert!(resolve(&universe, &["procps-ng", "top"]).is_err());
+    }
+
+    #[test]
+    fn follows_soft_dependencies_by_policy() {
+        let soft = |mut dep: Dependency, kind: DependencyKind, reason: &str| {
+            dep.kind = kind;
+            dep.reason = Some(reason.to_string());
+            dep
+        };
+        let mut htop = pkg("htop", "3.3", &["ncurses"]);
+        htop.depends.extend([
+            soft(Dependency::parse("lsof").unwrap(), DependencyKind::Recommended, "open files"),
+            soft(Dependency::parse("strace").unwrap(), DependencyKind::Optional, "tracing"),
+            soft(Dependency::parse("missing").unwrap(), DependencyKind::Recommended, "unpackaged"),
+        ]);
+        let universe = PackageUniverse::from_packages([
+            htop,
+            pkg("ncurses", "6.5", &[]),
+            pkg("lsof", "4.99", &[]),
+            pkg("strace", "6.10", &[]),
+        ]);
+        let roots = vec!["htop".to_string()];
+        let names = |policy: DependencyPolicy| {
+            let solution = universe
+                .resolve(&roots, "glibc-systemd", "x86_64", &HashMap::new(), policy)
+                .unwrap();
+            let mut names: Vec<String> =
+                solution.packages.iter().map(|id| id.name.clone()).collect();
+            names.sort();
+            let suggested: Vec<String> =
+                solution.suggestions.iter().map(|s| s.dependency.name.clone()).collect();
+            (names, suggested)
+        };
+
+        let (picked, suggested) = names(DependencyPolicy::default());
+        assert_eq!(picked, vec!["htop", "lsof", "ncurses"]);
+        assert_eq!(suggested, vec!["strace", "missing"]);
+
+        let (picked, _) = names(DependencyPolicy { recommends: true, optional: true });
+        assert_eq!(picked, vec!["htop", "lsof", "ncurses", "strace"]);
+
+        let (picked, suggested) = names(DependencyPolicy { recommends: false, optional: false });
+        assert_eq!(picked, vec!["htop", "ncurses"]);
+        assert_eq!(suggested, vec!["lsof", "strace", "missing"]);
+    }
+
+    #[test]
+    fn leaves_out_unsatisfiable_soft_dependencies() {
+        let recommends = |rule: &str| {
+            let mut dep = Dependency::parse(rule).unwrap();
+            dep.kind = DependencyKind::Recommended;
+            dep
+        };
+        let mut htop = pkg("htop", "3.3", &[]);
+        htop.depends.extend([recommends("lsof>=5"), recommends("strace"), recommends("perf")]);
+        let universe = PackageUniverse::from_packages([
+            htop,
+            pkg("lsof", "4.99", &[]),
+            pkg("strace", "6.10", &["libunwind"]),
+            with_rules(pkg("perf", "6.11", &[]), &["htop"], &[]),
+        ]);
+        let roots = vec!["htop".to_string()];
+        let solution = universe
+            .resolve(&roots, "glibc-systemd", "x86_64", &HashMap::new(), DependencyPolicy::default())
+            .unwrap();
+        assert_eq!(install_names(&solution), vec!["htop"]);
+        let suggested: Vec<String> =
+            solution.suggestions.iter().map(|s| s.dependency.name.clone()).collect();
+        assert_eq!(suggested, vec!["lsof", "strace", "perf"]);
+
+        // A required dependency is not held back by a recommends on the
+        // same name.
+        let mut top = pkg("top", "1.0", &["lsof"]);
+        top.depends.push(recommends("lsof>=5"));
+        let universe = PackageUniverse::from_packages([top, pkg("lsof", "4.99", &[])]);
+        assert_eq!(resolve(&universe, &["top"]).unwrap(), vec!["lsof-4.99", "top-1.0"]);
+    }
+
+    #[test]
+    fn higher_priority_repository_wins() {
+        let in_repo = |mut meta: PackageMetadata, repo: &str| {
+            meta.repo = repo.to_string();
+            meta
+        };
+        let repos = vec![
+            repo::Repository::new("work", 200),
+            repo::Repository::new("core", 100),
+        ];
+        let metas = prefer_repositories(
+            vec![
+                in_repo(pkg("zlib", "1.3", &[]), "core"),
+                in_repo(pkg("zlib", "1.2", &[]), "work"),
+                in_repo(pkg("htop", "3.3", &["zlib"]), "core"),
+                in_repo(pkg("htop", "3.4", &["zlib"]), "extra"),
+            ],
+            &repos,
+        );
+        let universe = PackageUniverse::from_packages(metas);
+        assert_eq!(resolve(&universe, &["htop"]).unwrap(), vec!["htop-3.3", "zlib-1.2"]);
+    }
 }
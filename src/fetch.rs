@@ -0,0 +1,642 @@
+// src/fetch.rs
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use std::future::Future;
+use futures_util::{stream, StreamExt, TryStreamExt};
+use indicatif::{MultiProgress, ProgressBar, ProgressFinish, ProgressStyle};
+use reqwest::header;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+
+use crate::mirror::{Location, Mirror, MirrorError};
+use crate::repo::Repository;
+
+#[derive(Error, Debug)]
+pub enum FetchError {
+    #[error("Mirror configuration error: {0}")]
+    Mirror(#[from] MirrorError),
+    #[error("No active mirrors configured")]
+    NoMirrors,
+    #[error("HTTP error: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("{url} returned {status}")]
+    Status { url: String, status: reqwest::StatusCode },
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("{path}: {source}")]
+    Local {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    /// `offline` is set when no mirror could be reached at all, as opposed
+    /// to one answering with an error or a file that did not verify.
+    #[error("Could not fetch {path} from any mirror: {reasons}")]
+    AllMirrorsFailed { path: String, reasons: String, offline: bool },
+}
+
+/// What a server said about a file, so that a later request for it can be
+/// made conditional.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Validators {
+    /// Where the file came from; validators only apply to the same URL.
+    pub url: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+/// Outcome of `Fetcher::fetch_if_changed`.
+pub enum Fetched<'m> {
+    Updated { mirror: &'m Mirror, validators: Validators },
+    NotModified { mirror: &'m Mirror },
+}
+
+/// Timings of one `Fetcher::probe`.
+#[derive(Debug, Clone, Copy)]
+pub struct Probe {
+    pub latency: Duration,
+    pub elapsed: Duration,
+    pub bytes: u64,
+}
+
+impl Probe {
+    pub fn bytes_per_sec(&self) -> u64 {
+        let transfer = self.elapsed.saturating_sub(self.latency).as_secs_f64();
+        if transfer > 0.0 {
+            (self.bytes as f64 / transfer) as u64
+        } else {
+            self.bytes
+        }
+    }
+}
+
+/// Give up on a mirror that does not answer or stops sending data.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many files are downloaded at once unless `--jobs` says otherwise.
+pub const DEFAULT_JOBS: usize = 4;
+
+/// Downloads repository files from the configured mirrors, falling back to
+/// the next mirror when one fails.
+pub struct Fetcher {
+    mirrors: Vec<Mirror>,
+    client: reqwest::Client,
+    progress: MultiProgress,
+}
+
+impl Fetcher {
+    /// A fetcher for the mirrors `repository` is configured to use.
+    pub fn for_repository(root: &Path, repository: &Repository) -> Result<Self, FetchError> {
+        Self::new(Mirror::for_repository(root, repository)?)
+    }
+
+    pub fn new(mirrors: Vec<Mirror>) -> Result<Self, FetchError> {
+        if mirrors.is_empty() {
+            return Err(FetchError::NoMirrors);
+        }
+        let client = reqwest::Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .read_timeout(READ_TIMEOUT)
+            .build()?;
+        Ok(Self { mirrors, client, progress: MultiProgress::new() })
+    }
+
+    /// Show the bars of this fetcher's downloads in `progress`, together
+    /// with those of other fetchers.
+    pub fn with_progress(mut self, progress: MultiProgress) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Where the bars are drawn; print through its `suspend` while
+    /// downloads run.
+    pub fn progress(&self) -> &MultiProgress {
+        &self.progress
+    }
+
+    /// Fetch `path` (relative to the mirror root) into `dest`, trying the
+    /// mirrors in priority order; local mirrors are copied from disk. A mirror
+    /// fails on an HTTP error, a timeout, a missing file, or when `verify`
+    /// rejects what it served; the next one is tried then. `verify` runs on
+    /// the blocking thread pool, since it usually hashes or unpacks the file.
+    ///
+    /// Whatever is at `dest` is replaced, and nothing is left there unless
+    /// the fetch succeeded. Returns the mirror that served the file.
+    pub async fn fetch<F>(&self, path: &str, dest: &Path, verify: F) -> Result<&Mirror, FetchError>
+    where
+        F: Fn(&Path) -> Result<(), String> + Clone + Send + 'static,
+    {
+        match self.fetch_from_mirrors(path, dest, None, false, verify).await? {
+            Fetched::Updated { mirror, .. } | Fetched::NotModified { mirror } => Ok(mirror),
+        }
+    }
+
+    /// Like `fetch`, but an existing `dest` is taken to be the start of the
+    /// file and resumed with a Range request. It is deleted when it does not
+    /// verify, but kept when a download breaks off, so that it can be
+    /// continued later.
+    pub async fn fetch_resumable<F>(
+        &self,
+        path: &str,
+        dest: &Path,
+        verify: F,
+    ) -> Result<&Mirror, FetchError>
+    where
+        F: Fn(&Path) -> Result<(), String> + Clone + Send + 'static,
+    {
+        match self.fetch_from_mirrors(path, dest, None, true, verify).await? {
+            Fetched::Updated { mirror, .. } | Fetched::NotModified { mirror } => Ok(mirror),
+        }
+    }
+
+    /// Like `fetch`, but with the validators of an earlier fetch the request
+    /// is conditional: a mirror at the same URL may answer that nothing
+    /// changed, and `dest` is then left alone.
+    pub async fn fetch_if_changed<F>(
+        &self,
+        path: &str,
+        dest: &Path,
+        previous: Option<&Validators>,
+        verify: F,
+    ) -> Result<Fetched<'_>, FetchError>
+    where
+        F: Fn(&Path) -> Result<(), String> + Clone + Send + 'static,
+    {
+        self.fetch_from_mirrors(path, dest, previous, false, verify).await
+    }
+
+    async fn fetch_from_mirrors<F>(
+        &self,
+        path: &str,
+        dest: &Path,
+        previous: Option<&Validators>,
+        resume: bool,
+        verify: F,
+    ) -> Result<Fetched<'_>, FetchError>
+    where
+        F: Fn(&Path) -> Result<(), String> + Clone + Send + 'static,
+    {
+        let mut reasons = Vec::new();
+        let mut offline = true;
+        for mirror in &self.mirrors {
+            let location = mirror.locate(path);
+            // A partial file left by an earlier attempt is continued; if the
+            // result does not verify, the mirror gets a second try from scratch.
+            let mut attempts = if resume && dest.exists() { 2 } else { 1 };
+            let reason = loop {
+                attempts -= 1;
+                let fetched = match &location {
+                    Location::Remote(url) => {
+                        let previous = previous.filter(|v| &v.url == url);
+                        self.download(url, dest, previous, resume).await
+                    }
+                    Location::Local(source) => copy_local(source, dest).await.map(|()| {
+                        Some(Validators { url: location.to_string(), ..Validators::default() })
+                    }),
+                };
+                match fetched {
+                    Ok(None) => return Ok(Fetched::NotModified { mirror }),
+                    Ok(Some(validators)) => match run_verify(&verify, dest).await {
+                        Ok(()) => return Ok(Fetched::Updated { mirror, validators }),
+                        Err(reason) => {
+                            let _ = fs::remove_file(dest);
+                            offline = false;
+                            if attempts == 0 {
+                                break reason;
+                            }
+                        }
+                    },
+                    // What was downloaded so far is kept for the next mirror
+                    // (or the next run) to continue, if it can.
+                    Err(e) => {
+                        if !resume {
+                            let _ = fs::remove_file(dest);
+                        }
+                        offline &= match &e {
+                            FetchError::Http(_) => true,
+                            FetchError::Local { source, .. } => {
+                                source.kind() == std::io::ErrorKind::NotFound && !mounted(mirror)
+                            }
+                            _ => false,
+                        };
+                        break e.to_string();
+                    }
+                }
+            };
+            self.progress.suspend(|| eprintln!("    ⚠️ Mirror {} failed: {}", mirror.name, reason));
+            reasons.push(format!("{}: {}", mirror.name, reason));
+        }
+        Err(FetchError::AllMirrorsFailed {
+            path: path.to_string(),
+            reasons: reasons.join("; "),
+            offline,
+        })
+    }
+
+    /// Time fetching `path` from one mirror: how long until it answers, and
+    /// how long the whole file takes.
+    pub async fn probe(&self, mirror: &Mirror, path: &str) -> Result<Probe, FetchError> {
+        let start = Instant::now();
+        match mirror.locate(path) {
+            Location::Remote(url) => {
+                let response = self.client.get(&url).send().await?;
+                if !response.status().is_success() {
+                    return Err(FetchError::Status { url, status: response.status() });
+                }
+                let latency = start.elapsed();
+
+                let mut bytes: u64 = 0;
+                let mut stream = response.bytes_stream();
+                while let Some(item) = stream.next().await {
+                    bytes += item?.len() as u64;
+                }
+                Ok(Probe { latency, elapsed: start.elapsed(), bytes })
+            }
+            Location::Local(source) => {
+                let data = fs::read(&source).map_err(|e| FetchError::Local {
+                    path: source.clone(),
+                    source: e,
+                })?;
+                let elapsed = start.elapsed();
+                Ok(Probe { latency: elapsed, elapsed, bytes: data.len() as u64 })
+            }
+        }
+    }
+
+    /// Download `url` into `output_path`, continuing what is there when
+    /// `resume` is set. Returns `None` when `previous` made the request
+    /// conditional and the server says nothing changed.
+    async fn download(
+        &self,
+        url: &str,
+        output_path: &Path,
+        previous: Option<&Validators>,
+        resume: bool,
+    ) -> Result<Option<Validators>, FetchError> {
+        // Continue a partial download. A server that cannot serve the rest
+        // of it sends the whole file, refuses the range or answers with
+        // another one; start over then.
+        let mut resume_from = if resume {
+            tokio::fs::metadata(output_path).await.map(|m| m.len()).unwrap_or(0)
+        } else {
+            0
+        };
+        let response = loop {
+            let mut request = self.client.get(url);
+            if let Some(previous) = previous {
+                if let Some(etag) = &previous.etag {
+                    request = request.header(header::IF_NONE_MATCH, etag);
+                }
+                if let Some(last_modified) = &previous.last_modified {
+                    request = request.header(header::IF_MODIFIED_SINCE, last_modified);
+                }
+            }
+            if resume_from > 0 {
+                request = request.header(header::RANGE, format!("bytes={}-", resume_from));
+            }
+
+            let response = request.send().await?;
+            let restart = match response.status() {
+                reqwest::StatusCode::RANGE_NOT_SATISFIABLE => true,
+                reqwest::StatusCode::PARTIAL_CONTENT => range_start(&response) != Some(resume_from),
+                _ => false,
+            };
+            if restart && resume_from > 0 {
+                tokio::fs::remove_file(output_path).await?;
+                resume_from = 0;
+                continue;
+            }
+            break response;
+        };
+        if response.status() == reqwest::StatusCode::NOT_MODIFIED && previous.is_some() {
+            return Ok(None);
+        }
+        if !response.status().is_success() {
+            return Err(FetchError::Status {
+                url: url.to_string(),
+                status: response.status(),
+            });
+        }
+        let resumed = resume_from > 0 && response.status() == reqwest::StatusCode::PARTIAL_CONTENT;
+        let offset = if resumed { resume_from } else { 0 };
+        let header_value = |name: header::HeaderName| {
+            response.headers().get(name).and_then(|v| v.to_str().ok()).map(|v| v.to_string())
+        };
+        let validators = Validators {
+            url: url.to_string(),
+            etag: header_value(header::ETAG),
+            last_modified: header_value(header::LAST_MODIFIED),
+        };
+        let total_size = offset + response.content_length().unwrap_or(0);
+
+        // Cleared when done, and also when the download is cancelled.
+        let pb = ProgressBar::new(total_size).with_finish(ProgressFinish::AndClear);
+        let pb = self.progress.add(pb);
+        pb.set_style(
+            ProgressStyle::default_bar()
+                .template("{spinner:.green} {msg:24!} [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+                .unwrap()
+                .progress_chars("=>-"),
+        );
+        pb.set_message(url.rsplit('/').next().unwrap_or(url).to_string());
+
+        let mut file = if resumed {
+            tokio::fs::OpenOptions::new().append(true).open(output_path).await?
+        } else {
+            tokio::fs::File::create(output_path).await?
+        };
+        let mut stream = response.bytes_stream();
+        let mut downloaded: u64 = offset;
+        pb.set_position(downloaded);
+
+        while let Some(item) = stream.next().await {
+            let chunk = item?;
+            file.write_all(&chunk).await?;
+            downloaded += chunk.len() as u64;
+            pb.set_position(downloaded);
+        }
+        file.flush().await?;
+
+        pb.finish_and_clear();
+        Ok(Some(validators))
+    }
+}
+
+/// A bar in `progress` counting finished files out of `len`.
+pub fn total_bar(progress: &MultiProgress, len: usize, what: &str) -> ProgressBar {
+    let pb = ProgressBar::new(len as u64).with_finish(ProgressFinish::AndClear);
+    let pb = progress.add(pb);
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("{spinner:.green} {msg:24!} [{bar:40.green/white}] {pos}/{len}")
+            .unwrap()
+            .progress_chars("=>-"),
+    );
+    pb.set_message(what.to_string());
+    pb
+}
+
+/// Run `tasks` with at most `jobs` of them at a time, counting each one
+/// that finishes on `total`. The first error is returned right away; the
+/// tasks still running are dropped, which cancels their downloads.
+pub async fn run_all<T, E, F>(tasks: Vec<F>, jobs: usize, total: &ProgressBar) -> Result<Vec<T>, E>
+where
+    F: Future<Output = Result<T, E>>,
+{
+    stream::iter(tasks)
+        .buffer_unordered(jobs.max(1))
+        .inspect(|_| total.inc(1))
+        .try_collect()
+        .await
+}
+
+/// Where the body of a 206 response starts, from `Content-Range: bytes
+/// <start>-<end>/<size>`.
+fn range_start(response: &reqwest::Response) -> Option<u64> {
+    let range = response.headers().get(header::CONTENT_RANGE)?.to_str().ok()?;
+    range.strip_prefix("bytes ")?.split('-').next()?.trim().parse().ok()
+}
+
+/// Run `verify` on `dest` without holding up the other downloads.
+async fn run_verify<F>(verify: &F, dest: &Path) -> Result<(), String>
+where
+    F: Fn(&Path) -> Result<(), String> + Clone + Send + 'static,
+{
+    let (verify, dest) = (verify.clone(), dest.to_path_buf());
+    tokio::task::spawn_blocking(move || verify(&dest)).await.map_err(|e| e.to_string())?
+}
+
+/// Whether a local mirror's directory is there: not missing, and not an
+/// empty mount point with nothing mounted on it. Remote mirrors always are.
+fn mounted(mirror: &Mirror) -> bool {
+    match mirror.locate("") {
+        Location::Remote(_) => true,
+        Location::Local(root) => {
+            fs::read_dir(root).map_or(false, |mut entries| entries.next().is_some())
+        }
+    }
+}
+
+async fn copy_local(source: &Path, dest: &Path) -> Result<(), FetchError> {
+    tokio::fs::copy(source, dest).await.map_err(|e| FetchError::Local {
+        path: source.to_path_buf(),
+        source: e,
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    fn local_mirror(name: &str, dir: &Path) -> Mirror {
+        Mirror {
+            name: name.to_string(),
+            url: format!("file://{}", dir.display()),
+            ..Mirror::official()
+        }
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_next_mirror() {
+        let broken = tempfile::tempdir().unwrap();
+        let good = tempfile::tempdir().unwrap();
+        let out = tempfile::tempdir().unwrap();
+        fs::create_dir_all(broken.path().join("core")).unwrap();
+        fs::create_dir_all(good.path().join("core")).unwrap();
+        fs::write(broken.path().join("core/pkg.kpkg"), b"corrupt").unwrap();
+        fs::write(good.path().join("core/pkg.kpkg"), b"package").unwrap();
+
+        let fetcher = Fetcher::new(vec![
+            local_mirror("missing", &out.path().join("nowhere")),
+            local_mirror("broken", broken.path()),
+            local_mirror("good", good.path()),
+        ])
+        .unwrap();
+
+        let dest = out.path().join("pkg.kpkg");
+        let verify = |path: &Path| match fs::read(path).unwrap().as_slice() {
+            b"package" => Ok(()),
+            _ => Err("checksum mismatch".to_string()),
+        };
+        let mirror = fetcher.fetch("core/pkg.kpkg", &dest, verify).await.unwrap();
+        assert_eq!(mirror.name, "good");
+        assert_eq!(fs::read(&dest).unwrap(), b"package");
+
+        let other = out.path().join("other.kpkg");
+        let err = fetcher.fetch("core/other.kpkg", &other, verify).await.unwrap_err();
+        assert!(matches!(err, FetchError::AllMirrorsFailed { offline: false, .. }));
+        assert!(!other.exists());
+    }
+
+    #[tokio::test]
+    async fn missing_local_mirrors_are_offline() {
+        let out = tempfile::tempdir().unwrap();
+        let unmounted = out.path().join("mnt");
+        fs::create_dir(&unmounted).unwrap();
+        let fetcher = Fetcher::new(vec![
+            local_mirror("usb", &out.path().join("nowhere")),
+            local_mirror("nfs", &unmounted),
+        ])
+        .unwrap();
+        let dest = out.path().join("pkg.kpkg");
+        let err = fetcher.fetch("core/pkg.kpkg", &dest, |_| Ok(())).await.unwrap_err();
+        assert!(matches!(err, FetchError::AllMirrorsFailed { offline: true, .. }), "{:?}", err);
+    }
+
+    #[tokio::test]
+    async fn first_error_cancels_remaining_tasks() {
+        let started = std::sync::atomic::AtomicUsize::new(0);
+        let task = |n: usize| {
+            let started = &started;
+            async move {
+                started.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                match n {
+                    1 => Err(format!("task {} failed", n)),
+                    0 => Ok(n),
+                    // Would never finish unless cancelled.
+                    _ => std::future::pending().await,
+                }
+            }
+        };
+        let total = ProgressBar::hidden();
+        let tasks = (0..6).map(task).collect();
+        let result = tokio::time::timeout(Duration::from_secs(5), run_all(tasks, 3, &total))
+            .await
+            .expect("remaining tasks were not cancelled");
+        assert_eq!(result.unwrap_err(), "task 1 failed");
+        // Never more than `jobs` running: the last tasks were not started.
+        assert!(started.load(std::sync::atomic::Ordering::SeqCst) < 6);
+    }
+
+    /// Serve `body` at every path over plain HTTP, honouring `Range: bytes=N-`
+    /// unless `ranges_from_zero` is set: then every range is answered with
+    /// the whole body. Records the start of the range each request asked for.
+    async fn serve(
+        body: &'static [u8],
+        ranges_from_zero: bool,
+    ) -> (String, Arc<Mutex<Vec<Option<usize>>>>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let ranges = Arc::new(Mutex::new(Vec::new()));
+        let seen = ranges.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    let n = socket.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let request = String::from_utf8_lossy(&request).to_lowercase();
+                let start = request
+                    .lines()
+                    .find_map(|l| l.strip_prefix("range: bytes="))
+                    .and_then(|r| r.trim_end_matches('-').parse::<usize>().ok());
+                seen.lock().unwrap().push(start);
+                let served = start.map(|start| if ranges_from_zero { 0 } else { start });
+
+                let head = match served {
+                    Some(start) => format!(
+                        "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\n\
+                         Content-Range: bytes {}-{}/{}\r\nConnection: close\r\n\r\n",
+                        body.len() - start,
+                        start,
+                        body.len() - 1,
+                        body.len()
+                    ),
+                    None => format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        body.len()
+                    ),
+                };
+                socket.write_all(head.as_bytes()).await.unwrap();
+                socket.write_all(&body[served.unwrap_or(0)..]).await.unwrap();
+                let _ = socket.shutdown().await;
+            }
+        });
+        (url, ranges)
+    }
+
+    #[tokio::test]
+    async fn resumes_partial_downloads() {
+        let (url, ranges) = serve(b"0123456789", false).await;
+        let out = tempfile::tempdir().unwrap();
+        let fetcher = Fetcher::new(vec![Mirror { url, ..Mirror::official() }]).unwrap();
+        let dest = out.path().join("pkg.kpkg.part");
+        let verify = |path: &Path| match fs::read(path).unwrap().as_slice() {
+            b"0123456789" => Ok(()),
+            _ => Err("checksum mismatch".to_string()),
+        };
+
+        fs::write(&dest, b"01234").unwrap();
+        fetcher.fetch_resumable("core/pkg.kpkg", &dest, verify).await.unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), b"0123456789");
+        assert_eq!(*ranges.lock().unwrap(), vec![Some(5)]);
+
+        // Not the start of this file: resuming fails the check, so the
+        // download starts over.
+        fs::write(&dest, b"abc").unwrap();
+        fetcher.fetch_resumable("core/pkg.kpkg", &dest, verify).await.unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), b"0123456789");
+        assert_eq!(*ranges.lock().unwrap(), vec![Some(5), Some(3), None]);
+
+        // A plain fetch never resumes.
+        fs::write(&dest, b"01234").unwrap();
+        fetcher.fetch("core/pkg.kpkg", &dest, verify).await.unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), b"0123456789");
+        assert_eq!(ranges.lock().unwrap().last(), Some(&None));
+    }
+
+    #[tokio::test]
+    async fn fails_over_between_http_mirrors() {
+        let (broken, _) = serve(b"corrupt", false).await;
+        let (good, requests) = serve(b"package", false).await;
+        let out = tempfile::tempdir().unwrap();
+        let mirror = |name: &str, url: String| Mirror {
+            name: name.to_string(),
+            url,
+            ..Mirror::official()
+        };
+        let fetcher = Fetcher::new(vec![
+            mirror("down", "http://127.0.0.1:1".to_string()),
+            mirror("broken", broken),
+            mirror("good", good),
+        ])
+        .unwrap();
+
+        let dest = out.path().join("pkg.kpkg");
+        let verify = |path: &Path| match fs::read(path).unwrap().as_slice() {
+            b"package" => Ok(()),
+            _ => Err("checksum mismatch".to_string()),
+        };
+        let served_by = fetcher.fetch("core/pkg.kpkg", &dest, verify).await.unwrap();
+        assert_eq!(served_by.name, "good");
+        assert_eq!(fs::read(&dest).unwrap(), b"package");
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn restarts_when_the_range_does_not_match() {
+        let (url, ranges) = serve(b"0123456789", true).await;
+        let out = tempfile::tempdir().unwrap();
+        let fetcher = Fetcher::new(vec![Mirror { url, ..Mirror::official() }]).unwrap();
+        let dest = out.path().join("pkg.kpkg.part");
+
+        fs::write(&dest, b"01234").unwrap();
+        fetcher.fetch_resumable("core/pkg.kpkg", &dest, |_| Ok(())).await.unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), b"0123456789");
+        assert_eq!(*ranges.lock().unwrap(), vec![Some(5), None]);
+    }
+}
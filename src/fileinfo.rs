@@ -0,0 +1,513 @@
+// src/fileinfo.rs
+//
+// Everything a package puts at a path besides the contents: the type of the
+// entry, its mode including setuid/setgid, its owner and its extended
+// attributes, which is where file capabilities (`security.capability`) live.
+// `pkgutil::build` writes all of it into files.tar.zst, transactions restore
+// it when unpacking and record it in the package database, and `verify`
+// checks the root against the record.
+//
+// Extended attributes travel as PAX records the way libarchive writes them,
+// `LIBARCHIVE.xattr.<name>=<base64 value>`, since values such as
+// capabilities are binary.
+
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::CString;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::{Component, Path};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde::{Deserialize, Serialize};
+use tar::{Builder, EntryType, Header};
+use walkdir::WalkDir;
+
+use crate::resolve;
+
+const XATTR_PAX_PREFIX: &str = "LIBARCHIVE.xattr.";
+
+/// What a package shipped at one path.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileInfo {
+    #[serde(flatten)]
+    pub kind: FileKind,
+    #[serde(flatten)]
+    pub attributes: Attributes,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum FileKind {
+    File { sha256: String },
+    /// Another name for the file at `target`, relative to the root.
+    Hardlink { target: String },
+    Symlink { target: String },
+    Fifo,
+    CharDevice { major: u32, minor: u32 },
+    BlockDevice { major: u32, minor: u32 },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Attributes {
+    /// Permission bits, including setuid, setgid and sticky.
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// Values are base64-encoded.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub xattrs: BTreeMap<String, String>,
+}
+
+impl Attributes {
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let meta = fs::symlink_metadata(path)?;
+        let mut xattrs = BTreeMap::new();
+        match xattr::list(path) {
+            Ok(names) => {
+                for name in names {
+                    let name = name.to_string_lossy().to_string();
+                    // Labels come from the policy of the system installed to.
+                    if name == "security.selinux" {
+                        continue;
+                    }
+                    if let Some(value) = xattr::get(path, &name)? {
+                        xattrs.insert(name, BASE64.encode(value));
+                    }
+                }
+            }
+            Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => {}
+            Err(e) => return Err(e),
+        }
+        Ok(Attributes { mode: meta.mode() & 0o7777, uid: meta.uid(), gid: meta.gid(), xattrs })
+    }
+
+    /// Give `path` these attributes. The owner is only changed when running
+    /// as root; it is set first, as changing it clears setuid and
+    /// capabilities.
+    pub fn apply(&self, path: &Path) -> io::Result<()> {
+        if unsafe { libc::geteuid() } == 0 {
+            std::os::unix::fs::lchown(path, Some(self.uid), Some(self.gid))?;
+        }
+        if fs::symlink_metadata(path)?.file_type().is_symlink() {
+            return Ok(());
+        }
+        fs::set_permissions(path, fs::Permissions::from_mode(self.mode))?;
+        for (name, value) in &self.xattrs {
+            let value = BASE64.decode(value).map_err(io::Error::other)?;
+            xattr::set(path, name, &value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Describe the entry at `path`, or record it as another name of
+/// `hardlink_of`.
+pub fn describe(path: &Path, hardlink_of: Option<String>) -> io::Result<FileInfo> {
+    let meta = fs::symlink_metadata(path)?;
+    let file_type = meta.file_type();
+    let device = || (libc::major(meta.rdev()), libc::minor(meta.rdev()));
+    let kind = if let Some(target) = hardlink_of {
+        FileKind::Hardlink { target }
+    } else if file_type.is_symlink() {
+        FileKind::Symlink { target: fs::read_link(path)?.to_string_lossy().to_string() }
+    } else if file_type.is_fifo() {
+        FileKind::Fifo
+    } else if file_type.is_char_device() {
+        let (major, minor) = device();
+        FileKind::CharDevice { major, minor }
+    } else if file_type.is_block_device() {
+        let (major, minor) = device();
+        FileKind::BlockDevice { major, minor }
+    } else if file_type.is_file() {
+        FileKind::File { sha256: resolve::compute_sha256(path)? }
+    } else {
+        return Err(io::Error::other(format!("Unsupported file type: {}", path.display())));
+    };
+    Ok(FileInfo { kind, attributes: Attributes::read(path)? })
+}
+
+/// Describe every entry below `tree` except directories, by path relative to
+/// it. Later names of a hard-linked file are recorded as links to the first.
+pub fn describe_tree(tree: &Path) -> io::Result<BTreeMap<String, FileInfo>> {
+    let mut infos = BTreeMap::new();
+    let mut inodes = HashMap::new();
+    for entry in WalkDir::new(tree).min_depth(1).sort_by_file_name() {
+        let entry = entry.map_err(io::Error::from)?;
+        if entry.file_type().is_dir() {
+            continue;
+        }
+        let rel_path = entry.path().strip_prefix(tree).unwrap().to_string_lossy().to_string();
+        let meta = entry.path().symlink_metadata()?;
+        let mut hardlink_of = None;
+        if meta.is_file() && meta.nlink() > 1 {
+            let first = inodes.entry((meta.dev(), meta.ino())).or_insert_with(|| rel_path.clone());
+            if *first != rel_path {
+                hardlink_of = Some(first.clone());
+            }
+        }
+        infos.insert(rel_path, describe(entry.path(), hardlink_of)?);
+    }
+    Ok(infos)
+}
+
+/// Create a FIFO or device node; `mode` includes the file type.
+pub fn make_node(path: &Path, mode: u32, rdev: u64) -> io::Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    if unsafe { libc::mknod(c_path.as_ptr(), mode as libc::mode_t, rdev as libc::dev_t) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Who owns the files of a package being built.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ownership {
+    /// The owners in the tree when building as root (or under fakeroot),
+    /// root otherwise: a packager's own uid must never end up owning
+    /// installed files.
+    Default,
+    /// Everything is owned by this uid and gid.
+    Fixed(u32, u32),
+}
+
+impl Ownership {
+    fn of(self, attributes: &Attributes) -> (u32, u32) {
+        match self {
+            Ownership::Fixed(uid, gid) => (uid, gid),
+            Ownership::Default if unsafe { libc::geteuid() } == 0 => (attributes.uid, attributes.gid),
+            Ownership::Default => (0, 0),
+        }
+    }
+}
+
+/// Parse `--owner UID:GID`.
+pub fn parse_owner(value: &str) -> Result<Ownership, String> {
+    let (uid, gid) = value.split_once(':').ok_or("expected UID:GID")?;
+    let id = |s: &str| s.parse::<u32>().map_err(|_| format!("invalid id '{}'", s));
+    Ok(Ownership::Fixed(id(uid)?, id(gid)?))
+}
+
+/// Append everything below `tree` to `builder`, with paths relative to it.
+pub fn append_tree<W: Write>(
+    builder: &mut Builder<W>,
+    tree: &Path,
+    ownership: Ownership,
+) -> io::Result<()> {
+    let mut inodes = HashMap::new();
+    for entry in WalkDir::new(tree).min_depth(1).sort_by_file_name() {
+        let entry = entry.map_err(io::Error::from)?;
+        let rel_path = entry.path().strip_prefix(tree).unwrap();
+        let meta = entry.path().symlink_metadata()?;
+        let file_type = meta.file_type();
+        let attributes = Attributes::read(entry.path())?;
+        let (uid, gid) = ownership.of(&attributes);
+
+        let mut header = Header::new_gnu();
+        header.set_path(rel_path)?;
+        header.set_mode(attributes.mode);
+        header.set_uid(uid as u64);
+        header.set_gid(gid as u64);
+        header.set_size(0);
+
+        if meta.is_file() && meta.nlink() > 1 {
+            let first = inodes.entry((meta.dev(), meta.ino())).or_insert_with(|| rel_path.to_path_buf());
+            if first.as_path() != rel_path {
+                header.set_entry_type(EntryType::Link);
+                header.set_link_name(first.as_path())?;
+                header.set_cksum();
+                builder.append(&header, io::empty())?;
+                continue;
+            }
+        }
+
+        if !attributes.xattrs.is_empty() {
+            append_xattrs(builder, rel_path, &attributes.xattrs)?;
+        }
+        if file_type.is_file() {
+            header.set_size(meta.len());
+            header.set_cksum();
+            builder.append(&header, fs::File::open(entry.path())?)?;
+            continue;
+        }
+        if file_type.is_dir() {
+            header.set_entry_type(EntryType::Directory);
+        } else if file_type.is_symlink() {
+            header.set_entry_type(EntryType::Symlink);
+            header.set_link_name(fs::read_link(entry.path())?)?;
+        } else if file_type.is_fifo() {
+            header.set_entry_type(EntryType::Fifo);
+        } else if file_type.is_char_device() || file_type.is_block_device() {
+            header.set_entry_type(if file_type.is_char_device() {
+                EntryType::Char
+            } else {
+                EntryType::Block
+            });
+            header.set_device_major(libc::major(meta.rdev()))?;
+            header.set_device_minor(libc::minor(meta.rdev()))?;
+        } else {
+            return Err(io::Error::other(format!("Unsupported file type: {}", entry.path().display())));
+        }
+        header.set_cksum();
+        builder.append(&header, io::empty())?;
+    }
+    Ok(())
+}
+
+/// A PAX header carrying the extended attributes of the entry that follows.
+fn append_xattrs<W: Write>(
+    builder: &mut Builder<W>,
+    path: &Path,
+    xattrs: &BTreeMap<String, String>,
+) -> io::Result<()> {
+    let mut data = Vec::new();
+    for (name, value) in xattrs {
+        // Each record starts with its own length, digits included.
+        let record = format!(" {}{}={}\n", XATTR_PAX_PREFIX, encode_name(name), value);
+        let mut len = record.len() + 1;
+        while len.to_string().len() + record.len() != len {
+            len += 1;
+        }
+        data.extend_from_slice(format!("{}{}", len, record).as_bytes());
+    }
+    let mut header = Header::new_ustar();
+    let name = format!("PaxHeaders/{}", path.file_name().unwrap_or_default().to_string_lossy());
+    header.set_path(name.chars().take(100).collect::<String>())?;
+    header.set_entry_type(EntryType::XHeader);
+    header.set_mode(0o644);
+    header.set_size(data.len() as u64);
+    header.set_cksum();
+    builder.append(&header, data.as_slice())
+}
+
+/// Unpack every entry of `archive` below `tree` with the type, mode, owner
+/// and extended attributes it was built with. tar itself would unpack FIFOs
+/// and device nodes as regular files.
+pub fn unpack_tree<R: Read>(archive: &mut tar::Archive<R>, tree: &Path) -> io::Result<()> {
+    let tree_real = tree.canonicalize()?;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let mut xattrs = BTreeMap::new();
+        if let Some(extensions) = entry.pax_extensions()? {
+            for extension in extensions {
+                let extension = extension?;
+                if let Some(name) = extension.key().ok().and_then(|k| k.strip_prefix(XATTR_PAX_PREFIX)) {
+                    let value = extension.value().map_err(io::Error::other)?;
+                    xattrs.insert(decode_name(name), value.to_string());
+                }
+            }
+        }
+
+        let header = entry.header();
+        let entry_type = header.entry_type();
+        let attributes = Attributes {
+            mode: header.mode()? & 0o7777,
+            uid: header.uid()? as u32,
+            gid: header.gid()? as u32,
+            xattrs,
+        };
+        let path = entry.path()?.into_owned();
+        if !path.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir)) {
+            return Err(io::Error::other(format!("Invalid path in package: {}", path.display())));
+        }
+        let dest = tree.join(&path);
+        // An earlier entry may have made a directory on the way a symlink
+        // out of the tree; nothing is created or changed through it.
+        if let Some(parent) = dest.parent() {
+            if !leads_into(parent, &tree_real) {
+                return Err(io::Error::other(format!(
+                    "Path in package leads outside the tree: {}",
+                    path.display()
+                )));
+            }
+            fs::create_dir_all(parent)?;
+        }
+
+        let node_type = match entry_type {
+            EntryType::Fifo => Some(libc::S_IFIFO),
+            EntryType::Char => Some(libc::S_IFCHR),
+            EntryType::Block => Some(libc::S_IFBLK),
+            _ => None,
+        };
+        if let Some(node_type) = node_type {
+            let rdev = if entry_type == EntryType::Fifo {
+                0
+            } else {
+                libc::makedev(
+                    header.device_major()?.unwrap_or(0),
+                    header.device_minor()?.unwrap_or(0),
+                )
+            };
+            make_node(&dest, node_type | attributes.mode, rdev)?;
+        } else if !entry.unpack_in(tree)? {
+            return Err(io::Error::other(format!("Invalid path in package: {}", path.display())));
+        }
+        // A hard link shares the attributes of the file it names.
+        if entry_type != EntryType::Link {
+            attributes.apply(&dest)?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether `dir` lies below `tree_real` once the part of it that already
+/// exists is resolved. The rest does not exist yet, so creating it cannot
+/// follow a symlink; a dangling symlink on the way counts as outside.
+fn leads_into(dir: &Path, tree_real: &Path) -> bool {
+    let mut existing = dir;
+    while existing.symlink_metadata().is_err() {
+        match existing.parent() {
+            Some(parent) => existing = parent,
+            None => return false,
+        }
+    }
+    existing.canonicalize().map_or(false, |real| real.starts_with(tree_real))
+}
+
+fn encode_name(name: &str) -> String {
+    let mut encoded = String::new();
+    for byte in name.bytes() {
+        if byte.is_ascii_graphic() && byte != b'%' && byte != b'=' {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+fn decode_name(name: &str) -> String {
+    let bytes = name.as_bytes();
+    let mut decoded = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok());
+        match (bytes[i], hex.and_then(|h| u8::from_str_radix(h, 16).ok())) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_through_tar() {
+        let src = tempfile::tempdir().unwrap();
+        let src = src.path();
+        fs::create_dir_all(src.join("usr/bin")).unwrap();
+        fs::write(src.join("usr/bin/ping"), "ping").unwrap();
+        fs::set_permissions(src.join("usr/bin/ping"), fs::Permissions::from_mode(0o4755)).unwrap();
+        fs::hard_link(src.join("usr/bin/ping"), src.join("usr/bin/ping6")).unwrap();
+        std::os::unix::fs::symlink("ping", src.join("usr/bin/ping4")).unwrap();
+        fs::create_dir_all(src.join("run")).unwrap();
+        make_node(&src.join("run/initctl"), libc::S_IFIFO | 0o600, 0).unwrap();
+        let _ = xattr::set(src.join("usr/bin/ping"), "user.kspkg=test", b"a\nb");
+        if unsafe { libc::geteuid() } == 0 {
+            std::os::unix::fs::lchown(src.join("usr/bin/ping4"), Some(12), Some(34)).unwrap();
+            make_node(&src.join("run/null"), libc::S_IFCHR | 0o666, libc::makedev(1, 3)).unwrap();
+            // cap_net_raw=ep, as setcap writes it.
+            let capability = [0, 0, 0, 2, 0, 32, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+            xattr::set(src.join("usr/bin/ping"), "security.capability", &capability).unwrap();
+        }
+
+        let mut builder = Builder::new(Vec::new());
+        append_tree(&mut builder, src, Ownership::Default).unwrap();
+        let data = builder.into_inner().unwrap();
+        let dest = tempfile::tempdir().unwrap();
+        unpack_tree(&mut tar::Archive::new(data.as_slice()), dest.path()).unwrap();
+
+        let shipped = describe_tree(src).unwrap();
+        assert_eq!(describe_tree(dest.path()).unwrap(), shipped);
+        assert_eq!(
+            shipped["usr/bin/ping6"].kind,
+            FileKind::Hardlink { target: "usr/bin/ping".to_string() }
+        );
+        assert_eq!(shipped["usr/bin/ping"].attributes.mode, 0o4755);
+        assert_eq!(shipped["run/initctl"].kind, FileKind::Fifo);
+        let ping = dest.path().join("usr/bin/ping");
+        assert_eq!(fs::metadata(&ping).unwrap().ino(), fs::metadata(dest.path().join("usr/bin/ping6")).unwrap().ino());
+    }
+
+    #[test]
+    fn refuses_entries_through_symlinks() {
+        let outside = tempfile::tempdir().unwrap();
+        let mut builder = Builder::new(Vec::new());
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::Symlink);
+        header.set_size(0);
+        header.set_mode(0o777);
+        header.set_uid(0);
+        header.set_gid(0);
+        builder.append_link(&mut header, "x", outside.path()).unwrap();
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::Fifo);
+        header.set_size(0);
+        header.set_mode(0o600);
+        header.set_uid(0);
+        header.set_gid(0);
+        builder.append_data(&mut header, "x/foo", io::empty()).unwrap();
+        let data = builder.into_inner().unwrap();
+
+        let dest = tempfile::tempdir().unwrap();
+        let err = unpack_tree(&mut tar::Archive::new(data.as_slice()), dest.path()).unwrap_err();
+        assert!(err.to_string().contains("outside the tree"), "{}", err);
+        assert!(!outside.path().join("foo").exists());
+    }
+
+    #[test]
+    fn creates_nothing_through_symlinks() {
+        let outside = tempfile::tempdir().unwrap();
+        let mut builder = Builder::new(Vec::new());
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::Symlink);
+        header.set_size(0);
+        header.set_mode(0o777);
+        header.set_uid(0);
+        header.set_gid(0);
+        builder.append_link(&mut header, "x", outside.path()).unwrap();
+        let mut header = Header::new_gnu();
+        header.set_size(5);
+        header.set_mode(0o644);
+        header.set_uid(0);
+        header.set_gid(0);
+        builder.append_data(&mut header, "x/newdir/deeper/foo", &b"hello"[..]).unwrap();
+        let data = builder.into_inner().unwrap();
+
+        let dest = tempfile::tempdir().unwrap();
+        let err = unpack_tree(&mut tar::Archive::new(data.as_slice()), dest.path()).unwrap_err();
+        assert!(err.to_string().contains("outside the tree"), "{}", err);
+        assert_eq!(fs::read_dir(outside.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn sets_the_owner_of_built_files() {
+        let src = tempfile::tempdir().unwrap();
+        fs::write(src.path().join("motd"), "hello").unwrap();
+        let owners = |ownership| {
+            let mut builder = Builder::new(Vec::new());
+            append_tree(&mut builder, src.path(), ownership).unwrap();
+            let data = builder.into_inner().unwrap();
+            let mut archive = tar::Archive::new(data.as_slice());
+            let entry = archive.entries().unwrap().next().unwrap().unwrap();
+            (entry.header().uid().unwrap(), entry.header().gid().unwrap())
+        };
+
+        let euid = unsafe { libc::geteuid() };
+        let expected = if euid == 0 { (euid as u64, unsafe { libc::getegid() } as u64) } else { (0, 0) };
+        assert_eq!(owners(Ownership::Default), expected);
+        assert_eq!(owners(parse_owner("12:34").unwrap()), (12, 34));
+        assert!(parse_owner("12").is_err());
+        assert!(parse_owner("root:root").is_err());
+    }
+}
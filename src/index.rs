@@ -0,0 +1,347 @@
+// src/index.rs
+//
+// The repository index is an SQLite database written by ksmkdb, published
+// zstd-compressed as `<repo>.db.zst`, cached by `kspkg sync` as `<repo>.db`
+// and read by depres.
+
+use std::path::Path;
+use rusqlite::{Connection, OptionalExtension};
+use thiserror::Error;
+
+/// Bumped whenever the schema changes in a way older clients cannot read.
+pub const FORMAT_VERSION: u32 = 1;
+
+#[derive(Error, Debug)]
+pub enum IndexError {
+    #[error("Not a koushou repository index (no format version)")]
+    MissingFormatVersion,
+    #[error(
+        "Repository index format {found} is not supported (this kspkg reads format {supported}); \
+         update kspkg or regenerate the index"
+    )]
+    UnsupportedFormat { found: String, supported: u32 },
+    #[error("Invalid '{key}' in index metadata: {value}")]
+    InvalidMeta { key: &'static str, value: String },
+    #[error("Not an index delta")]
+    NotADelta,
+    #[error("Delta starts from generation {found}, but the index is at generation {expected}")]
+    DeltaMismatch { expected: u64, found: String },
+    #[error("SQLite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+}
+
+/// Create the index tables in an empty database and stamp its format version.
+pub fn create_schema(conn: &Connection) -> Result<(), IndexError> {
+    conn.execute_batch(
+        "CREATE TABLE meta (
+            key TEXT PRIMARY KEY,
+            value TEXT NOT NULL
+        );
+        CREATE TABLE packages (
+            name TEXT NOT NULL,
+            version TEXT NOT NULL,
+            arch TEXT NOT NULL,
+            flavour TEXT NOT NULL,
+            filename TEXT NOT NULL,
+            sha256 TEXT NOT NULL,
+            PRIMARY KEY (name, version, arch, flavour)
+        );
+        CREATE TABLE dependencies (
+            package_name TEXT NOT NULL,
+            package_version TEXT NOT NULL,
+            package_arch TEXT NOT NULL,
+            package_flavour TEXT NOT NULL,
+            dep_name TEXT NOT NULL,
+            dep_predicate TEXT,
+            kind TEXT NOT NULL,
+            reason TEXT
+        );
+        CREATE TABLE conflicts (
+            package_name TEXT NOT NULL,
+            package_version TEXT NOT NULL,
+            package_arch TEXT NOT NULL,
+            package_flavour TEXT NOT NULL,
+            conflict_name TEXT NOT NULL,
+            conflict_predicate TEXT
+        );
+        CREATE TABLE replaces (
+            package_name TEXT NOT NULL,
+            package_version TEXT NOT NULL,
+            package_arch TEXT NOT NULL,
+            package_flavour TEXT NOT NULL,
+            replaced_name TEXT NOT NULL,
+            replaced_predicate TEXT
+        );
+        CREATE TABLE provides (
+            package_name TEXT NOT NULL,
+            package_version TEXT NOT NULL,
+            package_arch TEXT NOT NULL,
+            package_flavour TEXT NOT NULL,
+            provides_name TEXT NOT NULL,
+            provides_version TEXT
+        );
+        CREATE INDEX idx_packages_name ON packages(name);
+        CREATE INDEX idx_provides_name ON provides(provides_name);",
+    )?;
+    conn.execute(
+        "INSERT INTO meta (key, value) VALUES ('format_version', ?)",
+        [FORMAT_VERSION.to_string()],
+    )?;
+    Ok(())
+}
+
+/// Tables whose rows belong to one package, by the columns naming it.
+const PACKAGE_TABLES: [(&str, &str); 5] = [
+    ("packages", "name, version, arch, flavour"),
+    ("dependencies", "package_name, package_version, package_arch, package_flavour"),
+    ("conflicts", "package_name, package_version, package_arch, package_flavour"),
+    ("replaces", "package_name, package_version, package_arch, package_flavour"),
+    ("provides", "package_name, package_version, package_arch, package_flavour"),
+];
+
+/// The generation of an index or the one a delta leads to. Every index
+/// published with `ksmkdb --previous` is one generation after the previous
+/// one; 0 means the index carries no generation.
+pub fn generation(conn: &Connection) -> Result<u64, IndexError> {
+    generation_of(conn, "main")
+}
+
+pub fn set_generation(conn: &Connection, generation: u64) -> Result<(), IndexError> {
+    conn.execute(
+        "INSERT OR REPLACE INTO meta (key, value) VALUES ('generation', ?)",
+        [generation.to_string()],
+    )?;
+    Ok(())
+}
+
+/// The chain an index belongs to. An index published with `ksmkdb
+/// --previous` continues the chain of the previous one; any other starts a
+/// new chain, so generations are only comparable within one chain.
+pub fn chain(conn: &Connection) -> Result<Option<String>, IndexError> {
+    Ok(conn
+        .query_row("SELECT value FROM meta WHERE key = 'chain'", [], |row| row.get(0))
+        .optional()?)
+}
+
+pub fn set_chain(conn: &Connection, chain: &str) -> Result<(), IndexError> {
+    conn.execute("INSERT OR REPLACE INTO meta (key, value) VALUES ('chain', ?)", [chain])?;
+    Ok(())
+}
+
+/// Write the delta that turns the index at `old` into the one at `new` into
+/// an empty database at `delta`. A delta is an index holding only the rows
+/// of added and changed packages, plus a `removed` table of the packages
+/// whose old rows go away. Returns the generation the delta starts from.
+pub fn write_delta(old: &Path, new: &Path, delta: &Path) -> Result<u64, IndexError> {
+    let conn = Connection::open(delta)?;
+    create_schema(&conn)?;
+    conn.execute_batch(
+        "CREATE TABLE removed (
+            name TEXT NOT NULL,
+            version TEXT NOT NULL,
+            arch TEXT NOT NULL,
+            flavour TEXT NOT NULL
+        );",
+    )?;
+    conn.execute("ATTACH DATABASE ? AS old", [old.to_string_lossy()])?;
+    conn.execute("ATTACH DATABASE ? AS new", [new.to_string_lossy()])?;
+
+    let from: u64 = generation_of(&conn, "old")?;
+    let to: u64 = generation_of(&conn, "new")?;
+
+    // A package changed when its file did; its rows are replaced wholesale.
+    let same = "a.name = b.name AND a.version = b.version AND a.arch = b.arch \
+                AND a.flavour = b.flavour AND a.sha256 = b.sha256";
+    conn.execute_batch(&format!(
+        "BEGIN;
+         INSERT INTO removed SELECT name, version, arch, flavour FROM old.packages a
+             WHERE NOT EXISTS (SELECT 1 FROM new.packages b WHERE {same});
+         INSERT INTO packages SELECT * FROM new.packages a
+             WHERE NOT EXISTS (SELECT 1 FROM old.packages b WHERE {same});",
+        same = same
+    ))?;
+    for (table, key) in &PACKAGE_TABLES[1..] {
+        conn.execute(
+            &format!(
+                "INSERT INTO {table} SELECT * FROM new.{table}
+                 WHERE ({key}) IN (SELECT name, version, arch, flavour FROM packages)",
+                table = table,
+                key = key
+            ),
+            [],
+        )?;
+    }
+    conn.execute_batch(&format!(
+        "INSERT INTO meta (key, value) VALUES ('kind', 'delta'), ('from_generation', '{}');
+         COMMIT;",
+        from
+    ))?;
+    set_generation(&conn, to)?;
+    conn.execute_batch("DETACH DATABASE old; DETACH DATABASE new;")?;
+    Ok(from)
+}
+
+/// Apply the delta at `delta` to the index open in `conn`, which must be at
+/// the generation the delta starts from.
+pub fn apply_delta(conn: &Connection, delta: &Path) -> Result<(), IndexError> {
+    conn.execute("ATTACH DATABASE ? AS delta", [delta.to_string_lossy()])?;
+    let result = apply_attached_delta(conn);
+    conn.execute_batch("DETACH DATABASE delta;")?;
+    result
+}
+
+fn apply_attached_delta(conn: &Connection) -> Result<(), IndexError> {
+    let kind: Option<String> = conn
+        .query_row("SELECT value FROM delta.meta WHERE key = 'kind'", [], |row| row.get(0))
+        .optional()?;
+    if kind.as_deref() != Some("delta") {
+        return Err(IndexError::NotADelta);
+    }
+    let from: Option<String> = conn
+        .query_row("SELECT value FROM delta.meta WHERE key = 'from_generation'", [], |row| {
+            row.get(0)
+        })
+        .optional()?;
+    let current = generation(conn)?;
+    if from != Some(current.to_string()) {
+        return Err(IndexError::DeltaMismatch {
+            expected: current,
+            found: from.unwrap_or_default(),
+        });
+    }
+    let to = generation_of(conn, "delta")?;
+
+    conn.execute_batch("BEGIN;")?;
+    let result = (|| {
+        for (table, key) in &PACKAGE_TABLES {
+            conn.execute(
+                &format!(
+                    "DELETE FROM main.{table} WHERE ({key}) IN
+                     (SELECT name, version, arch, flavour FROM delta.removed
+                      UNION SELECT name, version, arch, flavour FROM delta.packages)",
+                    table = table,
+                    key = key
+                ),
+                [],
+            )?;
+            conn.execute(
+                &format!("INSERT INTO main.{table} SELECT * FROM delta.{table}", table = table),
+                [],
+            )?;
+        }
+        set_generation(conn, to)
+    })();
+    match result {
+        Ok(()) => conn.execute_batch("COMMIT;")?,
+        Err(_) => conn.execute_batch("ROLLBACK;")?,
+    }
+    result
+}
+
+fn generation_of(conn: &Connection, schema: &str) -> Result<u64, IndexError> {
+    let value: Option<String> = conn
+        .query_row(
+            &format!("SELECT value FROM {}.meta WHERE key = 'generation'", schema),
+            [],
+            |row| row.get(0),
+        )
+        .optional()?;
+    match value {
+        None => Ok(0),
+        Some(v) => v.parse().map_err(|_| IndexError::InvalidMeta { key: "generation", value: v }),
+    }
+}
+
+/// Make sure `conn` holds an index in a format this build understands.
+pub fn check_format(conn: &Connection) -> Result<(), IndexError> {
+    let has_meta: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'meta')",
+        [],
+        |row| row.get(0),
+    )?;
+    if !has_meta {
+        return Err(IndexError::MissingFormatVersion);
+    }
+
+    let found: Option<String> = conn
+        .query_row("SELECT value FROM meta WHERE key = 'format_version'", [], |row| row.get(0))
+        .optional()?;
+    match found {
+        None => Err(IndexError::MissingFormatVersion),
+        Some(v) if v == FORMAT_VERSION.to_string() => Ok(()),
+        Some(v) => Err(IndexError::UnsupportedFormat {
+            found: v,
+            supported: FORMAT_VERSION,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_format_version() {
+        let conn = Connection::open_in_memory().unwrap();
+        assert!(matches!(check_format(&conn), Err(IndexError::MissingFormatVersion)));
+
+        create_schema(&conn).unwrap();
+        check_format(&conn).unwrap();
+
+        conn.execute("UPDATE meta SET value = '99' WHERE key = 'format_version'", []).unwrap();
+        let err = check_format(&conn).unwrap_err();
+        assert!(matches!(err, IndexError::UnsupportedFormat { ref found, .. } if found == "99"));
+    }
+
+    fn index_with(path: &Path, generation: u64, packages: &[(&str, &str, &str)]) {
+        let conn = Connection::open(path).unwrap();
+        create_schema(&conn).unwrap();
+        set_generation(&conn, generation).unwrap();
+        for (name, version, sha256) in packages {
+            conn.execute(
+                "INSERT INTO packages VALUES (?, ?, 'x86_64', 'glibc-systemd', 'f.kpkg', ?)",
+                [name, version, sha256],
+            )
+            .unwrap();
+            conn.execute(
+                "INSERT INTO dependencies
+                 VALUES (?, ?, 'x86_64', 'glibc-systemd', 'glibc', NULL, 'depends', ?)",
+                [name, version, sha256],
+            )
+            .unwrap();
+        }
+    }
+
+    fn rows(conn: &Connection, sql: &str) -> Vec<String> {
+        let mut stmt = conn.prepare(sql).unwrap();
+        let rows = stmt.query_map([], |row| row.get::<_, String>(0)).unwrap();
+        rows.map(|r| r.unwrap()).collect()
+    }
+
+    #[test]
+    fn deltas_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let old = dir.path().join("old.db");
+        let new = dir.path().join("new.db");
+        let delta = dir.path().join("delta.db");
+        index_with(&old, 4, &[("htop", "3.3", "a"), ("zlib", "1.3", "b"), ("vim", "9.1", "c")]);
+        index_with(&new, 5, &[("htop", "3.3", "a"), ("zlib", "1.3", "B"), ("nano", "8.0", "d")]);
+
+        assert_eq!(write_delta(&old, &new, &delta).unwrap(), 4);
+
+        let conn = Connection::open(&old).unwrap();
+        apply_delta(&conn, &delta).unwrap();
+        assert_eq!(generation(&conn).unwrap(), 5);
+        assert_eq!(
+            rows(&conn, "SELECT name || ':' || sha256 FROM packages ORDER BY name"),
+            vec!["htop:a", "nano:d", "zlib:B"]
+        );
+        assert_eq!(
+            rows(&conn, "SELECT package_name || ':' || reason FROM dependencies ORDER BY 1"),
+            vec!["htop:a", "nano:d", "zlib:B"]
+        );
+
+        // The delta now starts from the wrong generation.
+        assert!(matches!(apply_delta(&conn, &delta), Err(IndexError::DeltaMismatch { .. })));
+    }
+}
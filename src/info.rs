@@ -0,0 +1,61 @@
+// src/info.rs
+
+use std::path::Path;
+use thiserror::Error;
+
+use crate::dependency::DependencyKind;
+use crate::depres;
+use crate::pkgdb;
+use crate::resolve;
+
+#[derive(Error, Debug)]
+pub enum InfoError {
+    #[error("Package '{0}' not found in any repository")]
+    NotFound(String),
+    #[error("Dependency resolution error: {0}")]
+    Depres(#[from] depres::DepresError),
+    #[error("Package database error: {0}")]
+    PkgDb(#[from] pkgdb::PkgDbError),
+    #[error("Resolve error: {0}")]
+    Resolve(#[from] resolve::ResolveError),
+}
+
+/// Show what the repositories know about `name`: its newest version, whether
+/// it is installed, and its relationships to other packages, including the
+/// reasons given for recommended and optional dependencies.
+pub fn show_package(root: &Path, name: &str) -> Result<(), InfoError> {
+    let (flavour, arch) = resolve::system_target(root)?;
+    let universe = depres::PackageUniverse::load_from_cache(root)?;
+    let meta = universe
+        .newest(name, &flavour, arch)
+        .ok_or_else(|| InfoError::NotFound(name.to_string()))?;
+
+    let db = pkgdb::PackageDatabase::load_or_new(root.join("var/lib/koushou/db.json"))?;
+
+    println!("📦 {} {} ({}, {})", meta.id.name, meta.id.version, meta.id.arch, meta.id.flavour);
+    match db.get(name) {
+        Ok(installed) => println!("  {:<11} {}", "installed:", installed.version),
+        Err(_) => println!("  {:<11} no", "installed:"),
+    }
+
+    for kind in [DependencyKind::Required, DependencyKind::Recommended, DependencyKind::Optional] {
+        let label = format!("{}:", kind);
+        for dep in meta.depends.iter().filter(|d| d.kind == kind) {
+            match &dep.reason {
+                Some(reason) => println!("  {:<11} {} — {}", label, dep, reason),
+                None => println!("  {:<11} {}", label, dep),
+            }
+        }
+    }
+    for provide in &meta.provides {
+        println!("  {:<11} {}", "provides:", provide);
+    }
+    for rule in &meta.conflicts {
+        println!("  {:<11} {}", "conflicts:", rule);
+    }
+    for rule in &meta.replaces {
+        println!("  {:<11} {}", "replaces:", rule);
+    }
+
+    Ok(())
+}
@@ -1,177 +1,213 @@
 // src/install.rs
 
-use std::fs::File;
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
-use tar::Archive;
-use zstd::stream::read::Decoder as ZstdDecoder;
-use flate2::read::GzDecoder;
-use tempfile::TempDir;
-use walkdir::WalkDir;
+use indicatif::MultiProgress;
 use thiserror::Error;
 
+use crate::depres;
+use crate::fetch;
 use crate::package;
-use crate::pkgdb;
+use crate::repo;
 use crate::resolve;
+use crate::transaction::{OverwritePolicy, Transaction, TransactionError};
 
 #[derive(Error, Debug)]
 pub enum InstallError {
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
-    #[error("Package parse error: {0}")]
-    PackageParse(#[from] package::PackageParseError),
-    #[error("Invalid package: missing 'files.tar.zst'")]
-    MissingFilesTar,
-    #[error("Failed to create temporary directory")]
-    TempDir,
     #[error("Target root is not a directory: {0}")]
     InvalidRoot(PathBuf),
-    #[error("Package database error: {0}")]
-    PkgDb(#[from] pkgdb::PkgDbError),
     #[error("Resolve error: {0}")]
     Resolve(#[from] resolve::ResolveError),
+    #[error("Transaction failed: {0}")]
+    Transaction(#[from] TransactionError),
+    #[error("Fetch error: {0}")]
+    Fetch(#[from] fetch::FetchError),
+    #[error("Repository configuration error: {0}")]
+    Repo(#[from] repo::RepoError),
+    #[error("Repository '{0}' is not configured")]
+    UnknownRepository(String),
 }
 
-/// Install a package by name (e.g. "htop") — resolves dependencies and downloads
-pub async fn install_package_by_name(name: &str, root: &Path) -> Result<(), InstallError> {
+/// Install a package by name (e.g. "htop") — resolves dependencies and
+/// downloads the packages, at most `jobs` at a time. Files the packages
+/// conflict on are only replaced as `overwrite` allows.
+pub async fn install_package_by_name(
+    name: &str,
+    root: &Path,
+    policy: depres::DependencyPolicy,
+    jobs: usize,
+    overwrite: OverwritePolicy,
+) -> Result<(), InstallError> {
     if !root.is_dir() {
         return Err(InstallError::InvalidRoot(root.to_path_buf()));
     }
 
-    let flavour_path = root.join("etc/koushou/flavour");
-    if !flavour_path.exists() {
-        return Err(InstallError::Resolve(
-            resolve::ResolveError::Other(
-                format!("Flavour file not found: {}", flavour_path.display())
-            )
-        ));
-    }
-
-    let flavour = std::fs::read_to_string(&flavour_path)?
-        .trim()
-        .to_string();
-
-    let arch = match std::env::consts::ARCH {
-        "x86_64" | "aarch64" => std::env::consts::ARCH,
-        _ => "x86_64",
-    };
+    let (flavour, arch) = resolve::system_target(root)?;
 
     let cache_dir = root.join("var/cache/koushou/pkgs");
     std::fs::create_dir_all(&cache_dir)?;
 
-    let resolved_pkgs = resolve::resolve_transaction(
+    let transaction = resolve::resolve_transaction(
         vec![name],
         &flavour,
         arch,
         root,
+        policy,
     ).await?;
 
-    for pkg in resolved_pkgs {
-        let kpkg_path = cache_dir.join(&pkg.filename);
+    print_plan(&transaction);
+
+    let wanted: Vec<&resolve::ResolvedPackage> = transaction
+        .steps
+        .iter()
+        .flat_map(|s| &s.packages)
+        .filter(|p| p.action != depres::Action::Keep)
+        .collect();
+
+    // Every repository has its own mirrors.
+    let progress = MultiProgress::new();
+    let mut fetchers = HashMap::new();
+    for repository in repo::load_repositories(root)? {
+        if wanted.iter().any(|p| p.repo == repository.name) {
+            let fetcher = fetch::Fetcher::for_repository(root, &repository)?;
+            fetchers.insert(repository.name.clone(), fetcher.with_progress(progress.clone()));
+        }
+    }
 
-        if !kpkg_path.exists() {
-            resolve::download_package(&pkg.url, &kpkg_path).await?;
-            let actual_sha = resolve::compute_sha256(&kpkg_path)?;
-            if actual_sha != pkg.sha256 {
-                return Err(InstallError::Resolve(resolve::ResolveError::Sha256Mismatch {
-                    filename: pkg.filename,
-                    expected: pkg.sha256,
-                    actual: actual_sha,
-                }));
+    // Download everything the plan needs before anything is installed. A
+    // cached package is only used if it still matches the index; partial
+    // downloads in `<file>.part` are continued.
+    let mut missing: Vec<&resolve::ResolvedPackage> = Vec::new();
+    for pkg in wanted {
+        let kpkg_path = cache_dir.join(&pkg.filename);
+        if kpkg_path.exists() {
+            match resolve::verify_sha256(&kpkg_path, &pkg.sha256) {
+                Ok(()) => continue,
+                Err(e) => {
+                    eprintln!("⚠️ Cached {} is damaged ({}), downloading it again.", pkg.filename, e);
+                    std::fs::remove_file(&kpkg_path)?;
+                }
             }
         }
+        missing.push(pkg);
+    }
+    let total = fetch::total_bar(&progress, missing.len(), "packages");
+    let tasks = missing
+        .iter()
+        .map(|pkg| download_package(&fetchers, pkg, &cache_dir, &progress))
+        .collect();
+    let downloaded = fetch::run_all(tasks, jobs, &total).await;
+    total.finish_and_clear();
+    downloaded?;
+
+    // Everything goes in as one transaction, in plan order; replaced
+    // packages are removed in the same transaction.
+    let mut txn = Transaction::begin(root)?;
+    txn.overwrite(overwrite);
+    for removal in &transaction.removals {
+        txn.remove(&removal.name);
+    }
+    let mut staged = Vec::new();
+    for pkg in transaction.steps.iter().flat_map(|s| &s.packages) {
+        if pkg.action != depres::Action::Keep {
+            let package = txn.stage(&cache_dir.join(&pkg.filename))?;
+            staged.push(package.clone());
+        }
+    }
+    txn.commit()?;
 
-        install_local_package(&kpkg_path, root)?;
+    for removal in &transaction.removals {
+        println!("✓ Removed {} (replaced by {})", removal.name, removal.replaced_by.name);
+    }
+    for package in &staged {
+        print_installed(package, root);
     }
 
     Ok(())
 }
 
-pub fn install_local_package(kpkg_path: &Path, root: &Path) -> Result<(), InstallError> {
-    if !root.is_dir() {
-        return Err(InstallError::InvalidRoot(root.to_path_buf()));
-    }
-
-    let file = File::open(kpkg_path)?;
-    let gz = GzDecoder::new(file);
-    let mut archive = Archive::new(gz);
-
-    let temp_dir = TempDir::new().map_err(|_| InstallError::TempDir)?;
-    let temp_path = temp_dir.path();
-
-    archive.unpack(temp_path)?;
-
-    let kdl_path = temp_path.join("package.kdl");
-    let kdl_content = std::fs::read_to_string(&kdl_path)
-        .map_err(|_| InstallError::PackageParse(package::PackageParseError::MissingPackageNode))?;
-    let pkg = package::Package::from_kdl(&kdl_content)?;
+/// Fetch one package into the cache. It is downloaded to `<file>.part` and
+/// only renamed once its checksum matched.
+async fn download_package(
+    fetchers: &HashMap<String, fetch::Fetcher>,
+    pkg: &resolve::ResolvedPackage,
+    cache_dir: &Path,
+    progress: &MultiProgress,
+) -> Result<(), InstallError> {
+    let fetcher = fetchers
+        .get(&pkg.repo)
+        .ok_or_else(|| InstallError::UnknownRepository(pkg.repo.clone()))?;
+    let part_path = cache_dir.join(format!("{}.part", pkg.filename));
+    let sha256 = pkg.sha256.clone();
+    let mirror = fetcher
+        .fetch_resumable(&pkg.path, &part_path, move |path| {
+            resolve::verify_sha256(path, &sha256).map_err(|e| e.to_string())
+        })
+        .await?;
+    std::fs::rename(&part_path, cache_dir.join(&pkg.filename))?;
+    progress.suspend(|| println!("  ✓ {} from {}", pkg.filename, mirror.name));
+    Ok(())
+}
 
-    let files_tar_path = temp_path.join("files.tar.zst");
-    if !files_tar_path.exists() {
-        return Err(InstallError::MissingFilesTar);
+fn print_plan(transaction: &resolve::ResolvedTransaction) {
+    let pkgs: Vec<&resolve::ResolvedPackage> =
+        transaction.steps.iter().flat_map(|s| &s.packages).collect();
+    let changes = pkgs.iter().filter(|p| p.action != depres::Action::Keep).count()
+        + transaction.removals.len();
+
+    println!("📋 Transaction plan:");
+    for pkg in &pkgs {
+        match &pkg.action {
+            depres::Action::Upgrade { from } | depres::Action::Downgrade { from } => {
+                println!("  {:<10} {} {} -> {}", pkg.action, pkg.name, from, pkg.version);
+            }
+            action => println!("  {:<10} {} {}", action, pkg.name, pkg.version),
+        }
+    }
+    for removal in &transaction.removals {
+        println!(
+            "  {:<10} {} {} (replaced by {})",
+            "remove", removal.name, removal.version, removal.replaced_by.name
+        );
     }
 
-    let staging_dir = temp_path.join("staging");
-    std::fs::create_dir_all(&staging_dir)?;
-
-    let files_file = File::open(&files_tar_path)?;
-    let zstd_decoder = ZstdDecoder::new(files_file)?;
-    let mut files_archive = Archive::new(zstd_decoder);
-    files_archive.unpack(&staging_dir)?;
-
-    let mut files = Vec::new();
-    for entry in WalkDir::new(&staging_dir).into_iter().filter_map(|e| e.ok()) {
-        if entry.file_type().is_file() {
-            let rel_path = entry.path().strip_prefix(&staging_dir).unwrap();
-            files.push(rel_path.to_string_lossy().to_string());
+    if !transaction.suggestions.is_empty() {
+        println!("💡 Recommended and optional dependencies not selected:");
+        for suggestion in &transaction.suggestions {
+            let reason = suggestion.dependency.reason.as_deref().unwrap_or("no reason given");
+            println!(
+                "  {} {} {}: {}",
+                suggestion.package.name, suggestion.dependency.kind, suggestion.dependency, reason
+            );
         }
     }
 
-    for entry in std::fs::read_dir(&staging_dir)? {
-        let entry = entry?;
-        let src_path = entry.path();
-        let file_name = entry.file_name();
-        let dest_path = root.join(file_name);
+    if changes == 0 {
+        println!("✓ Nothing to do, everything is up to date.");
+    }
+}
 
-        if src_path.is_dir() {
-            if !dest_path.exists() {
-                std::fs::create_dir_all(&dest_path)?;
-            }
-            for sub_entry in std::fs::read_dir(&src_path)? {
-                let sub_entry = sub_entry?;
-                let sub_src = sub_entry.path();
-                let sub_dest = dest_path.join(sub_entry.file_name());
-                if sub_dest.exists() {
-                    if sub_dest.is_dir() {
-                        std::fs::remove_dir_all(&sub_dest)?;
-                    } else {
-                        std::fs::remove_file(&sub_dest)?;
-                    }
-                }
-                std::fs::rename(&sub_src, &sub_dest)?;
-            }
-        } else {
-            if dest_path.exists() {
-                std::fs::remove_file(&dest_path)?;
-            }
-            std::fs::rename(&src_path, &dest_path)?;
-        }
+pub fn install_local_package(
+    kpkg_path: &Path,
+    root: &Path,
+    overwrite: OverwritePolicy,
+) -> Result<(), InstallError> {
+    if !root.is_dir() {
+        return Err(InstallError::InvalidRoot(root.to_path_buf()));
     }
 
-    let installed_pkg = pkgdb::InstalledPackage {
-        name: pkg.name.clone(),
-        version: pkg.version.clone(),
-        arch: pkg.arch.clone(),
-        flavour: pkg.flavour.clone(),
-        depends: pkg.depends.clone(),
-        files,
-    };
-
-    let db_path = root.join("var/lib/koushou/db.json");
-    std::fs::create_dir_all(db_path.parent().unwrap())?;
-    let mut db = pkgdb::PackageDatabase::load_or_new(&db_path)?;
-    db.add(installed_pkg);
-    db.save(&db_path)?;
+    let mut txn = Transaction::begin(root)?;
+    txn.overwrite(overwrite);
+    let package = txn.stage(kpkg_path)?.clone();
+    txn.commit()?;
 
+    print_installed(&package, root);
+    Ok(())
+}
+
+fn print_installed(pkg: &package::Package, root: &Path) {
     println!(
         "✓ Installed {}-{} ({}) into {}",
         pkg.name,
@@ -179,6 +215,4 @@ pub fn install_local_package(kpkg_path: &Path, root: &Path) -> Result<(), Instal
         pkg.arch,
         root.display()
     );
-
-    Ok(())
 }
@@ -0,0 +1,10 @@
+// src/lib.rs
+
+//! Package metadata, versions, dependency expressions, the repository index
+//! format and its signatures, shared by `kspkg` and `ksmkdb`.
+
+pub mod dependency;
+pub mod index;
+pub mod package;
+pub mod signature;
+pub mod version;
@@ -0,0 +1,51 @@
+// src/lock.rs
+//
+// Transactions and repository syncs must not run concurrently on one root:
+// each recovers whatever it finds half-done in its work directory, which
+// would be the work of the other. Both hold an exclusive `flock` for as long
+// as they run.
+
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io;
+use std::path::Path;
+
+/// An exclusive lock on a file, released when dropped.
+pub struct Lock {
+    _file: File,
+}
+
+impl Lock {
+    /// Take the lock on `path`, creating the file if needed. Waits for any
+    /// other process holding it.
+    pub fn acquire(path: &Path) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).truncate(false).write(true).open(path)?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                println!("⏳ Waiting for another kspkg process ({} is locked)...", path.display());
+                file.lock()?;
+            }
+            Err(TryLockError::Error(e)) => return Err(e),
+        }
+        Ok(Lock { _file: file })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn excludes_other_holders() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("var/lib/koushou/lock");
+        let lock = Lock::acquire(&path).unwrap();
+        let other = File::open(&path).unwrap();
+        assert!(matches!(other.try_lock(), Err(TryLockError::WouldBlock)));
+        drop(lock);
+        other.try_lock().unwrap();
+    }
+}
@@ -4,15 +4,29 @@ use std::path::PathBuf;
 use clap::Parser;
 use thiserror::Error;
 
-mod package;
 mod pkgdb;
 mod install;
+mod info;
 mod removal;
 mod pkgutil;
 mod list;
 mod sync;
 mod resolve;
-mod depres; 
+mod repo;
+mod cache;
+mod lock;
+mod transaction;
+mod fileinfo;
+mod verify;
+mod config;
+mod mirror;
+mod fetch;
+mod rank;
+mod depres;
+
+use kspkg::{dependency, index, package, signature, version};
+
+use fileinfo::Ownership;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about = "koushou — Seiryo Linux package manager", long_about = None)]
@@ -25,8 +39,12 @@ struct Args {
 enum Command {
     Install(InstallArgs),
     Remove(RemoveArgs),
+    Info(InfoArgs),
     List(ListArgs),
+    Verify(VerifyArgs),
     Sync(SyncArgs),
+    Mirrors(MirrorsArgs),
+    Config(ConfigArgs),
     Genpkg(GenpkgArgs),
     Buildpkg(BuildpkgArgs),
 }
@@ -37,6 +55,16 @@ struct InstallArgs {
     target: String,
     #[arg(long, short = 'r', default_value = "/", help = "Target root directory")]
     root: PathBuf,
+    #[arg(long, help = "Also install optional dependencies")]
+    with_optional: bool,
+    #[arg(long, help = "Do not install recommended dependencies")]
+    no_recommends: bool,
+    #[arg(long, short = 'j', default_value_t = fetch::DEFAULT_JOBS, help = "Number of packages to download at once")]
+    jobs: usize,
+    #[arg(long, value_name = "GLOB", help = "Replace conflicting files matching this pattern (repeatable)")]
+    overwrite: Vec<String>,
+    #[arg(long, help = "Replace all conflicting files")]
+    force: bool,
 }
 
 #[derive(clap::Args, Debug)]
@@ -47,6 +75,22 @@ struct RemoveArgs {
     root: PathBuf,
 }
 
+#[derive(clap::Args, Debug)]
+struct InfoArgs {
+    #[arg(help = "Name of package to show")]
+    package_name: String,
+    #[arg(long, short = 'r', default_value = "/", help = "Target root directory")]
+    root: PathBuf,
+}
+
+#[derive(clap::Args, Debug)]
+struct VerifyArgs {
+    #[arg(help = "Name of installed package to check")]
+    package_name: String,
+    #[arg(long, short = 'r', default_value = "/", help = "Target root directory")]
+    root: PathBuf,
+}
+
 #[derive(clap::Args, Debug)]
 struct ListArgs {
     #[arg(long, short = 'r', default_value = "/", help = "Target root directory")]
@@ -57,6 +101,46 @@ struct ListArgs {
 struct SyncArgs {
     #[arg(long, short = 'r', default_value = "/", help = "Target root directory")]
     root: PathBuf,
+    #[arg(long, short = 'j', default_value_t = fetch::DEFAULT_JOBS, help = "Number of repositories to sync at once")]
+    jobs: usize,
+}
+
+#[derive(clap::Args, Debug)]
+struct MirrorsArgs {
+    #[command(subcommand)]
+    command: MirrorsCommand,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum MirrorsCommand {
+    /// Measure the mirrors and save the fastest-first order used by sync
+    Rank(RankArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct RankArgs {
+    #[arg(long, help = "Only rank mirrors in this region (default: preferred-region from mirrorlist.kdl)")]
+    region: Option<String>,
+    #[arg(long, short = 'r', default_value = "/", help = "Target root directory")]
+    root: PathBuf,
+}
+
+#[derive(clap::Args, Debug)]
+struct ConfigArgs {
+    #[command(subcommand)]
+    command: ConfigCommand,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum ConfigCommand {
+    /// List configuration files with a pending .kpnew version and show the differences
+    Diff(ConfigDiffArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct ConfigDiffArgs {
+    #[arg(long, short = 'r', default_value = "/", help = "Target root directory")]
+    root: PathBuf,
 }
 
 #[derive(clap::Args, Debug)]
@@ -69,6 +153,14 @@ struct GenpkgArgs {
 struct BuildpkgArgs {
     #[arg(help = "Path to package directory")]
     dir: PathBuf,
+
+    #[arg(
+        long,
+        value_name = "UID:GID",
+        value_parser = fileinfo::parse_owner,
+        help = "Owner of every packaged file (default: as in the tree when run as root, root otherwise)"
+    )]
+    owner: Option<Ownership>,
 }
 
 #[derive(Error, Debug)]
@@ -77,10 +169,18 @@ pub enum KspkgError {
     Install(#[from] install::InstallError),
     #[error("Removal error: {0}")]
     Removal(#[from] removal::RemovalError),
+    #[error("Info error: {0}")]
+    Info(#[from] info::InfoError),
     #[error("List error: {0}")]
     List(#[from] list::ListError),
+    #[error("Verify error: {0}")]
+    Verify(#[from] verify::VerifyError),
     #[error("Sync error: {0}")]
     Sync(#[from] sync::SyncError),
+    #[error("Mirror ranking error: {0}")]
+    Rank(#[from] rank::RankError),
+    #[error("Configuration error: {0}")]
+    Config(#[from] config::ConfigError),
     #[error("Resolve error: {0}")]
     Resolve(#[from] resolve::ResolveError),
     #[error("Package utility error: {0}")]
@@ -93,27 +193,57 @@ async fn main() -> Result<(), KspkgError> {
 
     match args.command {
         Command::Install(install_args) => {
+            let overwrite =
+                transaction::OverwritePolicy::new(&install_args.overwrite, install_args.force)
+                    .map_err(install::InstallError::from)?;
             let path = std::path::Path::new(&install_args.target);
             if path.exists() && path.extension().map_or(false, |ext| ext == "kpkg") {
-                install::install_local_package(path, &install_args.root)?;
+                install::install_local_package(path, &install_args.root, overwrite)?;
             } else {
-                install::install_package_by_name(&install_args.target, &install_args.root).await?;
+                let policy = depres::DependencyPolicy {
+                    recommends: !install_args.no_recommends,
+                    optional: install_args.with_optional,
+                };
+                install::install_package_by_name(
+                    &install_args.target,
+                    &install_args.root,
+                    policy,
+                    install_args.jobs,
+                    overwrite,
+                )
+                .await?;
             }
         }
         Command::Remove(remove_args) => {
             removal::remove_package(&remove_args.root, &remove_args.package_name)?;
         }
+        Command::Info(info_args) => {
+            info::show_package(&info_args.root, &info_args.package_name)?;
+        }
         Command::List(list_args) => {
             list::list_packages(&list_args.root)?;
         }
+        Command::Verify(verify_args) => {
+            verify::verify_package(&verify_args.root, &verify_args.package_name)?;
+        }
         Command::Sync(sync_args) => {
-            sync::sync_repos(&sync_args.root).await?;
+            sync::sync_repos(&sync_args.root, sync_args.jobs).await?;
         }
+        Command::Mirrors(mirrors_args) => match mirrors_args.command {
+            MirrorsCommand::Rank(rank_args) => {
+                rank::rank_mirrors(&rank_args.root, rank_args.region.as_deref()).await?;
+            }
+        },
+        Command::Config(config_args) => match config_args.command {
+            ConfigCommand::Diff(diff_args) => {
+                config::diff_pending(&diff_args.root)?;
+            }
+        },
         Command::Genpkg(genpkg_args) => {
             pkgutil::generate(&genpkg_args.name)?;
         }
         Command::Buildpkg(buildpkg_args) => {
-            pkgutil::build(&buildpkg_args.dir)?;
+            pkgutil::build(&buildpkg_args.dir, buildpkg_args.owner.unwrap_or(Ownership::Default))?;
         }
     }
 
@@ -1,9 +1,12 @@
 // src/mirror.rs
 
-use kdl::KdlDocument;
+use kdl::{KdlDocument, KdlNode};
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use std::fs;
-use std::str::FromStr;
+use std::path::{Path, PathBuf};
+
+use crate::repo::{self, Repository, Source};
 
 #[derive(Error, Debug)]
 pub enum MirrorError {
@@ -11,12 +14,18 @@ pub enum MirrorError {
     Io(#[from] std::io::Error),
     #[error("Invalid KDL syntax in mirrorlist: {0}")]
     Parse(#[from] kdl::KdlError),
+    #[error("Mirror ranking cache error: {0}")]
+    Json(#[from] serde_json::Error),
     #[error("Mirror '{name}' is missing required property: {field}")]
     MissingProperty { name: String, field: String },
     #[error("Mirror '{name}': invalid value for '{field}': {value}")]
     InvalidValue { name: String, field: String, value: String },
 }
 
+const MIRRORLIST: &str = "etc/koushou/mirrorlist.kdl";
+const MIRRORLIST_DIR: &str = "etc/koushou/mirrorlist.d";
+const RANKING_CACHE: &str = "var/cache/koushou/mirror-ranking.json";
+
 #[derive(Debug, Clone)]
 pub struct Mirror {
     pub name: String,
@@ -28,88 +37,108 @@ pub struct Mirror {
 }
 
 impl Mirror {
-    /// Load and parse `/etc/koushou/mirrorlist.kdl`
-    pub fn load_default() -> Result<Vec<Self>, MirrorError> {
-        let path = "/etc/koushou/mirrorlist.kdl";
+    /// Load the active mirrors in the order they should be tried: the order
+    /// of the last `kspkg mirrors rank`, if any, then by priority.
+    pub fn load(root: &Path) -> Result<Vec<Self>, MirrorError> {
+        let mut mirrors = Self::load_configured(root)?;
+        if let Some(ranking) = Ranking::load(root) {
+            ranking.apply(&mut mirrors);
+        }
+        Ok(mirrors)
+    }
+
+    /// Load the active mirrors from `{root}/etc/koushou/mirrorlist.kdl`,
+    /// highest priority first. Without a mirrorlist, the official repository
+    /// is the only mirror.
+    pub fn load_configured(root: &Path) -> Result<Vec<Self>, MirrorError> {
+        let path = root.join(MIRRORLIST);
+        if !path.exists() {
+            return Ok(vec![Self::official()]);
+        }
         let content = fs::read_to_string(path)?;
         Self::from_kdl(&content)
     }
 
+    /// The mirrors to download `repository` from, in the order to try them.
+    pub fn for_repository(root: &Path, repository: &Repository) -> Result<Vec<Self>, MirrorError> {
+        let mut mirrors = match &repository.source {
+            Source::Mirrorlist => return Self::load(root),
+            Source::Url(url) => vec![Mirror {
+                name: repository.name.clone(),
+                url: url.clone(),
+                ..Self::official()
+            }],
+            Source::NamedMirrorlist(list) => {
+                let path = root.join(MIRRORLIST_DIR).join(format!("{}.kdl", list));
+                Self::from_kdl(&fs::read_to_string(path)?)?
+            }
+        };
+        if let Some(ranking) = Ranking::load(root) {
+            ranking.apply(&mut mirrors);
+        }
+        Ok(mirrors)
+    }
+
+    pub fn official() -> Self {
+        Mirror {
+            name: "official".to_string(),
+            url: repo::DEFAULT_BASE_URL.to_string(),
+            priority: 0,
+            protocol: "https".to_string(),
+            region: "global".to_string(),
+            active: true,
+        }
+    }
+
     pub fn from_kdl(input: &str) -> Result<Vec<Self>, MirrorError> {
         let doc: KdlDocument = input.parse().map_err(MirrorError::Parse)?;
 
         let mut mirrors = Vec::new();
 
         for node in doc.nodes() {
-            if node.name().as_ref() != "mirror" {
+            if node.name().value() != "mirror" {
                 continue;
             }
 
             let name = node
                 .entries()
-                .first()
-                .and_then(|e| e.value())
-                .and_then(|v| v.as_string())
+                .iter()
+                .find(|e| e.name().is_none())
+                .and_then(|e| e.value().as_string())
                 .ok_or_else(|| MirrorError::MissingProperty {
                     name: "unknown".to_string(),
                     field: "name".to_string(),
                 })?
                 .to_string();
 
-            let get_str_prop = |prop_name: &str| -> Result<String, MirrorError> {
-                node.get(prop_name)
-                    .map(|v| v.clone().into_string())
-                    .ok_or_else(|| MirrorError::MissingProperty {
-                        name: name.clone(),
-                        field: prop_name.to_string(),
-                    })
-            };
-
-            let get_prop = |prop_name: &str, default: Option<&str>| -> Result<String, MirrorError> {
-                match node.get(prop_name) {
-                    Some(v) => Ok(v.clone().into_string()),
-                    None => match default {
-                        Some(d) => Ok(d.to_string()),
-                        None => Err(MirrorError::MissingProperty {
-                            name: name.clone(),
-                            field: prop_name.to_string(),
-                        }),
-                    },
+            let url = string_prop(node, &name, "url")?.ok_or_else(|| {
+                MirrorError::MissingProperty {
+                    name: name.clone(),
+                    field: "url".to_string(),
                 }
-            };
-
-            let url = get_str_prop("url")?;
+            })?;
 
             let priority = match node.get("priority") {
-                Some(v) => {
-                    let s = v.clone().into_string();
-                    s.parse::<i32>().map_err(|_| MirrorError::InvalidValue {
+                Some(v) => v
+                    .as_integer()
+                    .and_then(|p| i32::try_from(p).ok())
+                    .ok_or_else(|| MirrorError::InvalidValue {
                         name: name.clone(),
                         field: "priority".to_string(),
-                        value: s,
-                    })?
-                }
+                        value: v.to_string(),
+                    })?,
                 None => 0,
             };
 
-            let protocol = get_prop("protocol", Some("https"))?;
-            let region = get_prop("region", Some("global"))?;
+            let protocol = string_prop(node, &name, "protocol")?.unwrap_or_else(|| "https".to_string());
+            let region = string_prop(node, &name, "region")?.unwrap_or_else(|| "global".to_string());
 
             let active = match node.get("active") {
-                Some(v) => {
-                    let s = v.clone().into_string();
-                    if s == "true" {
-                        true
-                    } else if s == "false" {
-                        false
-                    } else {
-                        return Err(MirrorError::InvalidValue {
-                            name: name.clone(),
-                            field: "active".to_string(),
-                            value: s,
-                        });
-                    }
-                }
+                Some(v) => v.as_bool().ok_or_else(|| MirrorError::InvalidValue {
+                    name: name.clone(),
+                    field: "active".to_string(),
+                    value: v.to_string(),
+                })?,
                 None => true,
             };
 
@@ -129,7 +158,174 @@ impl Mirror {
         Ok(mirrors)
     }
 
-    pub fn repo_url(&self, flavour: &str, repo: &str, arch: &str) -> String {
-        format!("{}/{}/{}/{}/{}.db.zst", self.url.trim_end_matches('/'), flavour, repo, arch, repo)
+    /// Where this mirror serves a file given by its path inside the
+    /// repository tree, e.g. `glibc-systemd/core/x86_64/htop-3.3-x86_64.kpkg`.
+    ///
+    /// `file://` URLs, plain paths and mirrors with `protocol="file"` are read
+    /// from disk. A URL without a scheme otherwise gets `protocol` prepended.
+    pub fn locate(&self, path: &str) -> Location {
+        let path = path.trim_start_matches('/');
+        if let Some(dir) = self.url.strip_prefix("file://") {
+            return Location::Local(Path::new(dir).join(path));
+        }
+        if self.url.contains("://") {
+            return Location::Remote(format!("{}/{}", self.url.trim_end_matches('/'), path));
+        }
+        if self.protocol == "file" || self.url.starts_with('/') || self.url.starts_with('.') {
+            return Location::Local(Path::new(&self.url).join(path));
+        }
+        Location::Remote(format!("{}://{}/{}", self.protocol, self.url.trim_end_matches('/'), path))
+    }
+}
+
+/// The `preferred-region "eu"` setting of the mirrorlist, if there is one.
+pub fn preferred_region(root: &Path) -> Result<Option<String>, MirrorError> {
+    let path = root.join(MIRRORLIST);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let doc: KdlDocument = fs::read_to_string(path)?.parse()?;
+    Ok(doc
+        .get_arg("preferred-region")
+        .and_then(|v| v.as_string())
+        .map(|s| s.to_string()))
+}
+
+/// Result of `kspkg mirrors rank`, fastest mirror first. Kept in
+/// `{root}/var/cache/koushou/mirror-ranking.json` so the mirrorlist itself
+/// is never rewritten.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ranking {
+    pub region: Option<String>,
+    pub mirrors: Vec<RankedMirror>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RankedMirror {
+    pub name: String,
+    pub latency_ms: u64,
+    pub bytes_per_sec: u64,
+}
+
+impl Ranking {
+    /// The saved ranking. A missing or unreadable cache counts as no ranking.
+    pub fn load(root: &Path) -> Option<Self> {
+        let content = fs::read_to_string(root.join(RANKING_CACHE)).ok()?;
+        match serde_json::from_str(&content) {
+            Ok(ranking) => Some(ranking),
+            Err(e) => {
+                eprintln!("⚠️ Ignoring mirror ranking cache: {}", e);
+                None
+            }
+        }
+    }
+
+    pub fn save(&self, root: &Path) -> Result<(), MirrorError> {
+        let path = root.join(RANKING_CACHE);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Put ranked mirrors first, in ranking order. Unranked mirrors keep
+    /// their relative order after them.
+    pub fn apply(&self, mirrors: &mut [Mirror]) {
+        mirrors.sort_by_key(|m| {
+            self.mirrors.iter().position(|r| r.name == m.name).unwrap_or(usize::MAX)
+        });
+    }
+}
+
+/// A file on a mirror: a URL to download, or a path to read directly.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Location {
+    Remote(String),
+    Local(PathBuf),
+}
+
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Location::Remote(url) => f.write_str(url),
+            Location::Local(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+fn string_prop(node: &KdlNode, name: &str, field: &str) -> Result<Option<String>, MirrorError> {
+    match node.get(field) {
+        Some(v) => v
+            .as_string()
+            .map(|s| Some(s.to_string()))
+            .ok_or_else(|| MirrorError::InvalidValue {
+                name: name.to_string(),
+                field: field.to_string(),
+                value: v.to_string(),
+            }),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mirror(url: &str, protocol: &str) -> Mirror {
+        Mirror {
+            name: "test".to_string(),
+            url: url.to_string(),
+            priority: 0,
+            protocol: protocol.to_string(),
+            region: "global".to_string(),
+            active: true,
+        }
+    }
+
+    #[test]
+    fn locates_files_by_protocol() {
+        let path = "glibc-systemd/core/x86_64/core.db.zst";
+        assert_eq!(
+            mirror("https://example.org/repo/", "https").locate(path),
+            Location::Remote("https://example.org/repo/glibc-systemd/core/x86_64/core.db.zst".into())
+        );
+        assert_eq!(
+            mirror("example.org/repo", "http").locate(path),
+            Location::Remote("http://example.org/repo/glibc-systemd/core/x86_64/core.db.zst".into())
+        );
+        assert_eq!(
+            mirror("file:///mnt/usb/repo", "https").locate(path),
+            Location::Local(PathBuf::from("/mnt/usb/repo/glibc-systemd/core/x86_64/core.db.zst"))
+        );
+        assert_eq!(
+            mirror("/srv/repo", "https").locate(path),
+            Location::Local(PathBuf::from("/srv/repo/glibc-systemd/core/x86_64/core.db.zst"))
+        );
+        assert_eq!(
+            mirror("artifacts/repo", "file").locate(path),
+            Location::Local(PathBuf::from("artifacts/repo/glibc-systemd/core/x86_64/core.db.zst"))
+        );
+    }
+
+    #[test]
+    fn ranking_reorders_mirrors() {
+        let named = |name: &str| Mirror {
+            name: name.to_string(),
+            ..mirror("https://example.org", "https")
+        };
+        let mut mirrors = vec![named("a"), named("b"), named("c"), named("d")];
+        let ranked = |name: &str| RankedMirror {
+            name: name.to_string(),
+            latency_ms: 0,
+            bytes_per_sec: 0,
+        };
+        let ranking = Ranking {
+            region: None,
+            mirrors: vec![ranked("c"), ranked("a")],
+        };
+        ranking.apply(&mut mirrors);
+        let order: Vec<&str> = mirrors.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(order, vec!["c", "a", "b", "d"]);
     }
 }
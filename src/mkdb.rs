@@ -4,11 +4,13 @@ use std::fs;
 use std::path::{Path, PathBuf};
 use clap::Parser;
 use sha2::Sha256;
-use rusqlite::{Connection, params};
-use regex::Regex;
-use kdl::KdlDocument;
+use rusqlite::{Connection, OpenFlags, params};
 use sha2::Digest;
 
+use kspkg::dependency::{Dependency, Provide, VersionPredicate};
+use kspkg::{index, package, signature};
+use kspkg::version::Version;
+
 #[derive(Parser, Debug)]
 #[command(author, version, about = "Generate Seiryo Linux repo database from .kpkg files", long_about = None)]
 struct Args {
@@ -16,6 +18,10 @@ struct Args {
     input_dir: PathBuf,
     #[arg(short, long, default_value = "repo.db", help = "Output database name (e.g. core.db)")]
     output: String,
+    #[arg(long, help = "Previous index of this repository; publishes a delta from it")]
+    previous: Option<PathBuf>,
+    #[arg(long, help = "PKCS#8 Ed25519 private key (DER) to sign the published files with")]
+    signing_key: Option<PathBuf>,
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -27,67 +33,241 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
 
     let db_path = args.output;
-    generate_db(&input_dir, &PathBuf::from(db_path.clone()))?;
+    let output_path = PathBuf::from(db_path.clone());
+    let signing_key = match &args.signing_key {
+        Some(path) => Some(signature::SigningKey::load(path)?),
+        None => None,
+    };
+
+    // Build the new index beside the published one and only move it over
+    // once it is complete: until then the old index (which may also be
+    // `--previous`) stays where clients and the next run expect it.
+    let staged = with_suffix(&output_path, ".tmp");
+    let (generation, chain) = match generate_db(&input_dir, &staged, args.previous.as_deref()) {
+        Ok(generated) => generated,
+        Err(e) => {
+            let _ = fs::remove_file(&staged);
+            return Err(e);
+        }
+    };
+
+    // Every published file is signed while staged, and its signature goes
+    // out before it does, so a client never sees a file without its own
+    // signature beside it.
+    let key = signing_key.as_ref();
+    if let Some(previous) = &args.previous {
+        let delta = publish_delta(previous, &staged, &output_path, key)?;
+        println!("✓ Generated {}", delta.display());
+    }
+    fs::rename(&staged, &output_path)?;
+
+    // What clients download: the same database, zstd-compressed.
+    compress(&output_path, key)?;
 
-    println!("✓ Generated {}", db_path);
+    // Clients read this first to find out how far behind they are, so it
+    // goes last. The chain tells them whether their index is an earlier
+    // generation of this one at all.
+    let generation_path = sibling(&output_path, "generation");
+    publish_file(&generation_path, format!("{} {}\n", generation, chain).as_bytes(), key)?;
+    println!("✓ Generated {} and {}.zst (generation {})", db_path, db_path, generation);
+
+    if let Some(key) = key {
+        println!("✓ Signed with key {}", key.public_key());
+    }
     Ok(())
 }
 
-fn generate_db(input_dir: &Path, output_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+/// Generate the index at `output_path`, one generation after `previous`
+/// in its chain (or generation 1 of a new chain), and return its generation
+/// and chain.
+fn generate_db(
+    input_dir: &Path,
+    output_path: &Path,
+    previous: Option<&Path>,
+) -> Result<(u64, String), Box<dyn std::error::Error>> {
+    let (generation, chain) = match previous {
+        Some(previous) => {
+            let conn = Connection::open_with_flags(previous, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+            index::check_format(&conn)?;
+            (index::generation(&conn)? + 1, index::chain(&conn)?)
+        }
+        None => (1, None),
+    };
+    let chain = match chain {
+        Some(chain) => chain,
+        None => new_chain()?,
+    };
+
+    // Always start from an empty index so removed packages disappear.
+    if output_path.exists() {
+        fs::remove_file(output_path)?;
+    }
     let mut conn = Connection::open(output_path)?;
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS packages (
-            name TEXT NOT NULL,
-            version TEXT NOT NULL,
-            arch TEXT NOT NULL,
-            flavour TEXT NOT NULL,
-            filename TEXT NOT NULL,
-            sha256 TEXT NOT NULL,
-            PRIMARY KEY (name, version, arch, flavour)
-        )",
-        [],
-    )?;
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS dependencies (
-            package_name TEXT NOT NULL,
-            dep_name TEXT NOT NULL,
-            dep_predicate TEXT
-        )",
-        [],
-    )?;
-    conn.execute("CREATE INDEX IF NOT EXISTS idx_packages_name ON packages(name)", [])?;
+    index::create_schema(&conn)?;
 
     let tx = conn.transaction()?;
     {
         let mut pkg_stmt = tx.prepare("INSERT INTO packages VALUES (?, ?, ?, ?, ?, ?)")?;
-        let mut dep_stmt = tx.prepare("INSERT INTO dependencies VALUES (?, ?, ?)")?;
+        let mut dep_stmt = tx.prepare("INSERT INTO dependencies VALUES (?, ?, ?, ?, ?, ?, ?, ?)")?;
+        let mut prov_stmt = tx.prepare("INSERT INTO provides VALUES (?, ?, ?, ?, ?, ?)")?;
+        let mut conflict_stmt = tx.prepare("INSERT INTO conflicts VALUES (?, ?, ?, ?, ?, ?)")?;
+        let mut replace_stmt = tx.prepare("INSERT INTO replaces VALUES (?, ?, ?, ?, ?, ?)")?;
 
+        let mut repo_pkgs = Vec::new();
         for entry in fs::read_dir(input_dir)? {
             let entry = entry?;
             let path = entry.path();
             if path.extension().map_or(false, |ext| ext == "kpkg") {
-                if let Ok(pkg) = process_kpkg(&path) {
-                    pkg_stmt.execute(params![
+                match process_kpkg(&path) {
+                    Ok(pkg) => repo_pkgs.push(pkg),
+                    Err(e) => eprintln!("⚠️ Skipping {}: {}", path.display(), e),
+                }
+            }
+        }
+
+        // Stable row order: by name, then oldest to newest version.
+        repo_pkgs.sort_by(|a, b| a.name.cmp(&b.name).then_with(|| a.version.cmp(&b.version)));
+
+        for pkg in repo_pkgs {
+            pkg_stmt.execute(params![
+                pkg.name,
+                pkg.version.to_string(),
+                pkg.arch,
+                pkg.flavour,
+                pkg.filename,
+                pkg.sha256
+            ])?;
+
+            for dep in &pkg.depends {
+                dep_stmt.execute(params![
+                    pkg.name,
+                    pkg.version.to_string(),
+                    pkg.arch,
+                    pkg.flavour,
+                    dep.name,
+                    predicate_column(dep),
+                    dep.kind.as_str(),
+                    dep.reason
+                ])?;
+            }
+
+            for (stmt, relations) in [
+                (&mut conflict_stmt, &pkg.conflicts),
+                (&mut replace_stmt, &pkg.replaces),
+            ] {
+                for rule in relations {
+                    stmt.execute(params![
                         pkg.name,
-                        pkg.version,
+                        pkg.version.to_string(),
                         pkg.arch,
                         pkg.flavour,
-                        pkg.filename,
-                        pkg.sha256
+                        rule.name,
+                        predicate_column(rule)
                     ])?;
-
-                    for dep in pkg.depends {
-                        let (dep_name, predicate) = parse_dep_for_db(&dep);
-                        dep_stmt.execute(params![pkg.name, dep_name, predicate])?;
-                    }
                 }
             }
+
+            for provide in &pkg.provides {
+                prov_stmt.execute(params![
+                    pkg.name,
+                    pkg.version.to_string(),
+                    pkg.arch,
+                    pkg.flavour,
+                    provide.name,
+                    provide.version.as_ref().map(|v| v.to_string())
+                ])?;
+            }
         }
     }
     tx.commit()?;
+    index::set_generation(&conn, generation)?;
+    index::set_chain(&conn, &chain)?;
+    conn.close().map_err(|(_, e)| e)?;
+    Ok((generation, chain))
+}
+
+/// A random identifier for a new chain of indexes.
+fn new_chain() -> Result<String, Box<dyn std::error::Error>> {
+    use ring::rand::SecureRandom;
+    let mut bytes = [0u8; 16];
+    ring::rand::SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| "no random source for the index chain")?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Write `<repo>.delta-<from>.db.zst` next to `output_path`, turning the
+/// previous index into the new one at `new_index`.
+fn publish_delta(
+    previous: &Path,
+    new_index: &Path,
+    output_path: &Path,
+    key: Option<&signature::SigningKey>,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let old_generation = {
+        let conn = Connection::open_with_flags(previous, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        index::generation(&conn)?
+    };
+    let delta = sibling(output_path, &format!("delta-{}.db", old_generation));
+    if delta.exists() {
+        fs::remove_file(&delta)?;
+    }
+    index::write_delta(previous, new_index, &delta)?;
+    let published = compress(&delta, key)?;
+    fs::remove_file(&delta)?;
+    Ok(published)
+}
+
+/// Publish `<path>.zst` and return its path.
+fn compress(
+    path: &Path,
+    key: Option<&signature::SigningKey>,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let compressed = zstd::stream::encode_all(fs::File::open(path)?, 19)?;
+    let published = with_suffix(path, ".zst");
+    publish_file(&published, &compressed, key)?;
+    Ok(published)
+}
+
+/// Replace `path` with `contents` through a rename, so readers see either
+/// the old file or the new one. With a key, `<path>.sig` is replaced first
+/// with the signature of the new contents.
+fn publish_file(
+    path: &Path,
+    contents: &[u8],
+    key: Option<&signature::SigningKey>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let staged = with_suffix(path, ".tmp");
+    fs::write(&staged, contents)?;
+    if let Some(key) = key {
+        let staged_sig = key.sign_file(&staged)?;
+        fs::rename(staged_sig, with_suffix(path, ".sig"))?;
+    }
+    fs::rename(&staged, path)?;
     Ok(())
 }
 
+/// `path` with `suffix` appended to its file name.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// `<repo>.<suffix>` next to the index `<repo>.db`.
+fn sibling(output_path: &Path, suffix: &str) -> PathBuf {
+    let stem = output_path.file_stem().unwrap_or_default().to_string_lossy();
+    output_path.with_file_name(format!("{}.{}", stem, suffix))
+}
+
+/// Version predicates are stored as text; NULL means any version.
+fn predicate_column(dep: &Dependency) -> Option<String> {
+    match dep.predicate {
+        VersionPredicate::Any => None,
+        ref p => Some(p.to_string()),
+    }
+}
+
 fn process_kpkg(path: &Path) -> Result<RepoPackage, Box<dyn std::error::Error>> {
     let filename = path.file_name().unwrap().to_str().unwrap().to_string();
     let tar_file = fs::File::open(path)?;
@@ -108,46 +288,7 @@ fn process_kpkg(path: &Path) -> Result<RepoPackage, Box<dyn std::error::Error>>
         return Err("package.kdl not found".into());
     }
 
-    let doc: KdlDocument = kdl_content.parse()?;
-    let pkg_node = doc.get("package").ok_or("missing 'package' node")?;
-
-    let args: Vec<&kdl::KdlValue> = doc.iter_args("package").collect();
-    if args.is_empty() {
-        return Err("package name missing".into());
-    }
-
-    let name = match args[0] {
-        kdl::KdlValue::String(s) => s.clone(),
-        _ => return Err("package name must be string".into()),
-    };
-
-    let get_prop = |key: &str| -> Result<String, Box<dyn std::error::Error>> {
-        pkg_node
-            .get(key)
-            .and_then(|v| {
-                if let kdl::KdlValue::String(s) = v { Some(s.clone()) } else { None }
-            })
-            .ok_or_else(|| format!("missing property: {}", key).into())
-    };
-
-    let version = get_prop("version")?;
-    let arch = get_prop("arch")?;
-    let flavour = get_prop("flavour")?;
-
-    let mut depends = Vec::new();
-    for child_doc in pkg_node.children() {
-        let nodes = child_doc.nodes();
-        if nodes.is_empty() { continue; }
-        let child_name = &nodes[0].name();
-        if child_name.to_string() == "depends" {
-            let child_args: Vec<&kdl::KdlValue> = child_doc.iter_args("depends").collect();
-            if let Some(dep_val) = child_args.first() {
-                if let kdl::KdlValue::String(s) = dep_val {
-                    depends.push(s.clone());
-                }
-            }
-        }
-    }
+    let pkg = package::Package::from_kdl(&kdl_content)?;
 
     let mut hasher = Sha256::new();
     let pkg_bytes = fs::read(path)?;
@@ -155,39 +296,29 @@ fn process_kpkg(path: &Path) -> Result<RepoPackage, Box<dyn std::error::Error>>
     let sha256 = format!("{:x}", hasher.finalize());
 
     Ok(RepoPackage {
-        name,
-        version,
-        arch,
-        flavour,
+        name: pkg.name,
+        version: pkg.version,
+        arch: pkg.arch,
+        flavour: pkg.flavour,
         filename,
         sha256,
-        depends,
+        depends: pkg.depends,
+        provides: pkg.provides,
+        conflicts: pkg.conflicts,
+        replaces: pkg.replaces,
     })
 }
 
-fn parse_dep_for_db(s: &str) -> (String, Option<String>) {
-    let re = Regex::new(r"^([a-zA-Z0-9._-]+)([<>=!]+)?(.*)$").unwrap();
-    if let Some(caps) = re.captures(s) {
-        let name = caps.get(1).unwrap().as_str().to_string();
-        let op = caps.get(2).map(|m| m.as_str()).unwrap_or("");
-        let version = caps.get(3).map(|m| m.as_str()).unwrap_or("");
-        if version.is_empty() {
-            (name, None)
-        } else {
-            (name, Some(format!("{}{}", op, version)))
-        }
-    } else {
-        (s.to_string(), None)
-    }
-}
-
 #[derive(Debug)]
 struct RepoPackage {
     name: String,
-    version: String,
+    version: Version,
     arch: String,
     flavour: String,
     filename: String,
     sha256: String,
-    depends: Vec<String>,
+    depends: Vec<Dependency>,
+    provides: Vec<Provide>,
+    conflicts: Vec<Dependency>,
+    replaces: Vec<Dependency>,
 }
@@ -1,17 +1,30 @@
 // src/package.rs
 
+use std::path::{Component, Path};
 use kdl::KdlDocument;
 use thiserror::Error;
 
+use crate::dependency::{Dependency, DependencyError, DependencyKind, Provide};
+use crate::version::{Version, VersionError};
+
+/// Where configuration files are when a package does not declare them.
+pub const DEFAULT_CONFIG: &str = "/etc";
+
 #[derive(Debug, Clone)]
 pub struct Package {
     pub name: String,
-    pub version: String,
+    pub version: Version,
     pub arch: String,
     pub flavour: String,
-    pub depends: Vec<String>,
+    pub depends: Vec<Dependency>,
+    pub provides: Vec<Provide>,
+    pub conflicts: Vec<Dependency>,
+    pub replaces: Vec<Dependency>,
     pub homepage: Option<String>,
     pub license: Option<String>,
+    /// Configuration files, or directories holding them, as absolute paths.
+    /// Defaults to `/etc`.
+    pub config: Vec<String>,
 }
 
 #[derive(Error, Debug)]
@@ -26,6 +39,27 @@ pub enum PackageParseError {
     MissingProperty(String),
     #[error("Expected string value for property: {0}")]
     InvalidPropertyValue(String),
+    #[error("Invalid version: {0}")]
+    InvalidVersion(#[from] VersionError),
+    #[error("Invalid dependency: {0}")]
+    InvalidDependency(#[from] DependencyError),
+    #[error("Invalid config path '{0}': expected an absolute path below /")]
+    InvalidConfigPath(String),
+}
+
+/// A `config` entry: an absolute path to something below the root. The
+/// root itself would make every file of the package a configuration file.
+fn config_path(value: String) -> Result<String, PackageParseError> {
+    let path = Path::new(&value);
+    let mut components = path.components();
+    let valid = components.next() == Some(Component::RootDir)
+        && components.clone().next().is_some()
+        && components.all(|c| matches!(c, Component::Normal(_)));
+    if valid {
+        Ok(value)
+    } else {
+        Err(PackageParseError::InvalidConfigPath(value))
+    }
 }
 
 fn kdl_value_to_string(value: &kdl::KdlValue) -> Result<String, PackageParseError> {
@@ -49,11 +83,11 @@ impl Package {
         }
         let name = kdl_value_to_string(args[0])?;
 
-        let version = kdl_value_to_string(
+        let version = Version::parse(&kdl_value_to_string(
             pkg_node
                 .get("version")
                 .ok_or(PackageParseError::MissingProperty("version".to_string()))?,
-        )?;
+        )?)?;
         let arch = kdl_value_to_string(
             pkg_node
                 .get("arch")
@@ -66,42 +100,106 @@ impl Package {
         )?;
 
         let mut depends = Vec::new();
+        let mut provides = Vec::new();
+        let mut conflicts = Vec::new();
+        let mut replaces = Vec::new();
         let mut homepage = None;
         let mut license = None;
+        let mut config = Vec::new();
 
-        for child_doc in pkg_node.children() {
-            let nodes = child_doc.nodes();
-            if nodes.is_empty() {
-                continue;
-            }
-            let first_node = &nodes[0];
-            let child_name_id = first_node.name();
-            let child_name = child_name_id.to_string();
-
-            let child_args: Vec<&kdl::KdlValue> = child_doc.iter_args(&child_name).collect();
+        let children = pkg_node.children().map(|doc| doc.nodes()).unwrap_or_default();
+        for child in children {
+            let child_name = child.name().value();
 
-            if child_args.is_empty() {
+            let first_arg = child.entries().iter().find(|e| e.name().is_none());
+            let Some(first_arg) = first_arg else {
                 continue;
-            }
+            };
 
-            if let Ok(value) = kdl_value_to_string(child_args[0]) {
-                match child_name.as_str() {
-                    "depends" => depends.push(value),
+            if let Ok(value) = kdl_value_to_string(first_arg.value()) {
+                match child_name {
+                    "depends" | "recommends" | "optional" => {
+                        let mut dep = Dependency::parse(&value)?;
+                        dep.kind = DependencyKind::from_node(child_name).unwrap_or_default();
+                        dep.reason = match child.get("reason") {
+                            Some(reason) => Some(kdl_value_to_string(reason)?),
+                            None => None,
+                        };
+                        depends.push(dep);
+                    }
+                    "provides" => provides.push(Provide::parse(&value)?),
+                    "conflicts" => conflicts.push(Dependency::parse(&value)?),
+                    "replaces" => replaces.push(Dependency::parse(&value)?),
                     "homepage" => homepage = Some(value),
                     "license" => license = Some(value),
+                    "config" => config.push(config_path(value)?),
                     _ => {}
                 }
             }
         }
 
+        if config.is_empty() {
+            config.push(DEFAULT_CONFIG.to_string());
+        }
+
         Ok(Self {
             name,
             version,
             arch,
             flavour,
             depends,
+            provides,
+            conflicts,
+            replaces,
             homepage,
             license,
+            config,
         })
     }
+
+    /// Whether `path`, relative to the root, is one of the package's
+    /// configuration files.
+    pub fn is_config(&self, path: &str) -> bool {
+        let path = Path::new(path);
+        self.config
+            .iter()
+            .any(|config| path.starts_with(config.trim_start_matches('/')))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_config(config: &[&str]) -> Result<Package, PackageParseError> {
+        let children: String =
+            config.iter().map(|c| format!("    config \"{}\"\n", c)).collect();
+        Package::from_kdl(&format!(
+            "package \"nginx\" version=\"1.0\" arch=\"x86_64\" flavour=\"glibc-systemd\" {{\n{}}}\n",
+            children
+        ))
+    }
+
+    #[test]
+    fn rejects_invalid_config_paths() {
+        for config in ["/", "", "//", "etc/nginx", "/etc/../usr", "/./"] {
+            assert!(
+                matches!(with_config(&[config]), Err(PackageParseError::InvalidConfigPath(_))),
+                "{:?}",
+                config
+            );
+        }
+        assert_eq!(with_config(&[]).unwrap().config, [DEFAULT_CONFIG]);
+    }
+
+    #[test]
+    fn matches_whole_path_components() {
+        let pkg = with_config(&["/etc/foo", "/srv/nginx/"]).unwrap();
+        assert!(pkg.is_config("etc/foo"));
+        assert!(pkg.is_config("etc/foo/bar.conf"));
+        assert!(pkg.is_config("srv/nginx/site.conf"));
+        assert!(!pkg.is_config("etc/foobar"));
+        assert!(!pkg.is_config("etc"));
+        assert!(!pkg.is_config("usr/bin/nginx"));
+    }
 }
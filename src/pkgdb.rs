@@ -1,20 +1,30 @@
 /// src/pkgdb.rs
-
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::path::Path;
-use thiserror::Error;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
 use std::io;
+use std::path::Path;
+use thiserror::Error;
+
+use crate::fileinfo::FileInfo;
+use crate::version::Version;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct InstalledPackage {
     pub name: String,
-    pub version: String,
+    pub version: Version,
     pub arch: String,
     pub flavour: String,
     pub depends: Vec<String>,
     pub files: Vec<String>,
+    /// What was shipped at each of `files`. Missing for packages installed
+    /// before it was recorded.
+    #[serde(default)]
+    pub file_info: BTreeMap<String, FileInfo>,
+    /// The packaged SHA-256 of each configuration file, to tell whether it
+    /// has been edited since.
+    #[serde(default)]
+    pub config: BTreeMap<String, String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -76,6 +86,10 @@ impl PackageDatabase {
         self.packages.values()
     }
 
+    pub fn list_mut(&mut self) -> impl Iterator<Item = &mut InstalledPackage> {
+        self.packages.values_mut()
+    }
+
     pub fn contains(&self, name: &str) -> bool {
         self.packages.contains_key(name)
     }
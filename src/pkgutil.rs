@@ -1,15 +1,15 @@
 // src/pkgutil.rs
 
-use std::fs::{self, symlink_metadata};
+use std::fs;
 use std::path::Path;
-use std::os::unix::fs::PermissionsExt;
-use tar::{Builder, Header, EntryType};
+use tar::Builder;
 use zstd::stream::write::Encoder as ZstdEncoder;
 use flate2::write::GzEncoder;
 use flate2::Compression;
-use walkdir::WalkDir;
 use thiserror::Error;
 
+use crate::fileinfo::Ownership;
+
 #[derive(Error, Debug)]
 pub enum PkgUtilError {
     #[error("IO error: {0}")]
@@ -52,7 +52,7 @@ pub fn generate(name: &str) -> Result<(), PkgUtilError> {
     Ok(())
 }
 
-pub fn build(pkg_dir: &Path) -> Result<(), PkgUtilError> {
+pub fn build(pkg_dir: &Path, ownership: Ownership) -> Result<(), PkgUtilError> {
     let kdl_path = pkg_dir.join("package.kdl");
     if !kdl_path.exists() {
         return Err(PkgUtilError::MissingMetadata(pkg_dir.display().to_string()));
@@ -71,40 +71,9 @@ pub fn build(pkg_dir: &Path) -> Result<(), PkgUtilError> {
     let zstd_encoder = ZstdEncoder::new(files_tar_file, 3)?;
     let mut files_tar = Builder::new(zstd_encoder);
 
-    for entry in WalkDir::new(&files_dir).into_iter().filter_map(|e| e.ok()) {
-        let rel_path = entry.path().strip_prefix(&files_dir).unwrap();
-        if rel_path.as_os_str().is_empty() {
-            continue;
-        }
-
-        let metadata = symlink_metadata(entry.path())?;
-        let mut header = Header::new_gnu();
-        header.set_path(rel_path)?;
-
-        if metadata.is_file() {
-            header.set_size(metadata.len());
-            header.set_mode(metadata.permissions().mode());
-            header.set_cksum();
-            let file = fs::File::open(entry.path())?;
-            files_tar.append(&header, file)?;
-        } else if metadata.is_dir() {
-            header.set_size(0);
-            header.set_mode(0o755);
-            header.set_entry_type(EntryType::Directory);
-            header.set_cksum();
-            files_tar.append(&header, std::io::empty())?;
-        } else if metadata.file_type().is_symlink() {
-            let target = fs::read_link(entry.path())?;
-            header.set_size(0);
-            header.set_mode(0o777);
-            header.set_entry_type(EntryType::Symlink);
-            header.set_link_name(target.to_str().ok_or_else(|| {
-                std::io::Error::new(std::io::ErrorKind::InvalidData, "Non-UTF8 symlink target")
-            })?)?;
-            header.set_cksum();
-            files_tar.append(&header, std::io::empty())?;
-        }
-    }
+    // Modes, extended attributes, hard links and special files are all
+    // kept; owners only when building as root or with --owner.
+    crate::fileinfo::append_tree(&mut files_tar, &files_dir, ownership)?;
 
     files_tar.finish()?;
     let zstd_encoder = files_tar.into_inner()?;
@@ -0,0 +1,101 @@
+// src/rank.rs
+
+use std::path::Path;
+use thiserror::Error;
+
+use crate::fetch::{FetchError, Fetcher, Probe};
+use crate::mirror::{self, Mirror, MirrorError, RankedMirror, Ranking};
+use crate::repo;
+use crate::resolve;
+
+#[derive(Error, Debug)]
+pub enum RankError {
+    #[error("Mirror configuration error: {0}")]
+    Mirror(#[from] MirrorError),
+    #[error("Fetch error: {0}")]
+    Fetch(#[from] FetchError),
+    #[error("Repository configuration error: {0}")]
+    Repo(#[from] repo::RepoError),
+    #[error("Resolve error: {0}")]
+    Resolve(#[from] resolve::ResolveError),
+    #[error("No repositories use the mirrorlist")]
+    NoRepositories,
+    #[error("No mirror could serve {0}")]
+    NoReachableMirrors(String),
+}
+
+/// Time every active mirror against the index of the highest-priority
+/// repository and save the order, fastest first, for sync and install to use.
+///
+/// With a region (given, or `preferred-region` in the mirrorlist), only the
+/// mirrors of that region are ranked; the others are tried after them.
+pub async fn rank_mirrors(root: &Path, region: Option<&str>) -> Result<(), RankError> {
+    let region = match region {
+        Some(r) => Some(r.to_string()),
+        None => mirror::preferred_region(root)?,
+    };
+
+    let mut mirrors = Mirror::load_configured(root)?;
+    if let Some(region) = &region {
+        let in_region: Vec<Mirror> =
+            mirrors.iter().filter(|m| &m.region == region).cloned().collect();
+        if in_region.is_empty() {
+            eprintln!("⚠️ No mirrors in region '{}', ranking all mirrors.", region);
+        } else {
+            mirrors = in_region;
+        }
+    }
+
+    let (flavour, arch) = resolve::system_target(root)?;
+    // Repositories with a URL or mirrorlist of their own are not served by
+    // these mirrors.
+    let repository = repo::load_repositories(root)?
+        .into_iter()
+        .find(|r| r.source == repo::Source::Mirrorlist)
+        .ok_or(RankError::NoRepositories)?;
+    let index_path = repository.index_path(&flavour, arch);
+
+    println!("📡 Ranking {} mirrors against {}...", mirrors.len(), index_path);
+
+    let fetcher = Fetcher::new(mirrors.clone())?;
+    let mut results: Vec<(&Mirror, Probe)> = Vec::new();
+    for mirror in &mirrors {
+        match fetcher.probe(mirror, &index_path).await {
+            Ok(probe) => {
+                println!(
+                    "  {:<20} {:>6} ms  {:>8} KiB/s",
+                    mirror.name,
+                    probe.latency.as_millis(),
+                    probe.bytes_per_sec() / 1024
+                );
+                results.push((mirror, probe));
+            }
+            Err(e) => eprintln!("  ⚠️ {:<17} {}", mirror.name, e),
+        }
+    }
+    if results.is_empty() {
+        return Err(RankError::NoReachableMirrors(index_path));
+    }
+
+    // Fastest complete fetch first; names break ties.
+    results.sort_by(|(a, pa), (b, pb)| {
+        pa.elapsed.cmp(&pb.elapsed).then_with(|| a.name.cmp(&b.name))
+    });
+
+    let ranking = Ranking {
+        region,
+        mirrors: results
+            .iter()
+            .map(|(mirror, probe)| RankedMirror {
+                name: mirror.name.clone(),
+                latency_ms: probe.latency.as_millis() as u64,
+                bytes_per_sec: probe.bytes_per_sec(),
+            })
+            .collect(),
+    };
+    ranking.save(root)?;
+
+    let order: Vec<&str> = ranking.mirrors.iter().map(|m| m.name.as_str()).collect();
+    println!("✓ Mirror order saved: {}", order.join(", "));
+    Ok(())
+}
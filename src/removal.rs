@@ -3,6 +3,7 @@
 use std::path::Path;
 use thiserror::Error;
 use crate::pkgdb::{PackageDatabase, PkgDbError};
+use crate::transaction::{Transaction, TransactionError};
 
 #[derive(Error, Debug)]
 pub enum RemovalError {
@@ -12,6 +13,8 @@ pub enum RemovalError {
     Io(#[from] std::io::Error),
     #[error("Package database error: {0}")]
     PkgDb(#[from] PkgDbError),
+    #[error("Transaction failed: {0}")]
+    Transaction(#[from] TransactionError),
 }
 
 pub fn remove_package(root: &Path, package_name: &str) -> Result<(), RemovalError> {
@@ -20,20 +23,17 @@ pub fn remove_package(root: &Path, package_name: &str) -> Result<(), RemovalErro
         return Err(RemovalError::NotInstalled(package_name.to_string()));
     }
 
-    let mut db = PackageDatabase::load_or_new(&db_path)?;
-    let installed_pkg = db.remove(package_name)
-        .map_err(|_| RemovalError::NotInstalled(package_name.to_string()))?;
-
-    // DELETE ONLY FILES (directories are left behind — safe!)
-    for file_path in installed_pkg.files.iter().rev() {
-        let abs_path = root.join(file_path);
-        if abs_path.exists() {
-            std::fs::remove_file(&abs_path)?;
-        }
+    let db = PackageDatabase::load_or_new(&db_path)?;
+    if !db.contains(package_name) {
+        return Err(RemovalError::NotInstalled(package_name.to_string()));
     }
 
-    // Save updated DB
-    db.save(&db_path)?;
+    // Files are removed and the database updated as one transaction.
+    // Directories are left behind.
+    let mut txn = Transaction::begin(root)?;
+    txn.remove(package_name);
+    txn.commit()?;
+
     println!("✓ Removed {} from {}", package_name, root.display());
     Ok(())
 }
@@ -0,0 +1,267 @@
+// src/repo.rs
+
+use kdl::{KdlDocument, KdlNode};
+use thiserror::Error;
+use std::fs;
+use std::path::Path;
+
+/// Where the official repositories are published; the mirror used when no
+/// mirrorlist is configured.
+pub const DEFAULT_BASE_URL: &str = "https://seiryolinux.github.io/repo";
+
+#[derive(Error, Debug)]
+pub enum RepoError {
+    #[error("Failed to read repository list: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid KDL syntax in repository list: {0}")]
+    Parse(#[from] kdl::KdlError),
+    #[error("Repository entry is missing its name")]
+    MissingName,
+    #[error("Repository '{name}': invalid value for '{field}'")]
+    InvalidValue { name: String, field: String },
+    #[error("Repository '{0}' sets both 'url' and 'mirrorlist'")]
+    AmbiguousSource(String),
+    #[error("Repository '{0}' is listed more than once")]
+    DuplicateName(String),
+}
+
+/// A package repository. When several repositories carry a package of the
+/// same name, only the one with the highest `priority` is used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Repository {
+    pub name: String,
+    pub priority: i64,
+    pub source: Source,
+    pub enabled: bool,
+    /// Base64 Ed25519 public key the repository index must be signed with.
+    pub signing_key: Option<String>,
+}
+
+/// Where a repository is downloaded from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    /// The mirrors of `{root}/etc/koushou/mirrorlist.kdl`.
+    Mirrorlist,
+    /// A single base URL (or local directory), no mirrors.
+    Url(String),
+    /// The mirrors of `{root}/etc/koushou/mirrorlist.d/<name>.kdl`.
+    NamedMirrorlist(String),
+}
+
+impl Repository {
+    /// An enabled, unsigned repository served by the system mirrorlist.
+    pub fn new(name: &str, priority: i64) -> Self {
+        Repository {
+            name: name.to_string(),
+            priority,
+            source: Source::Mirrorlist,
+            enabled: true,
+            signing_key: None,
+        }
+    }
+
+    /// Path of `filename` in this repository, for one flavour and arch,
+    /// relative to the root of a mirror.
+    pub fn file_path(&self, flavour: &str, arch: &str, filename: &str) -> String {
+        format!("{}/{}/{}/{}", flavour, self.name, arch, filename)
+    }
+
+    /// Path of the compressed repository index.
+    pub fn index_path(&self, flavour: &str, arch: &str) -> String {
+        self.file_path(flavour, arch, &format!("{}.db.zst", self.name))
+    }
+
+    /// Path of the file holding the generation of the newest index.
+    pub fn generation_path(&self, flavour: &str, arch: &str) -> String {
+        self.file_path(flavour, arch, &format!("{}.generation", self.name))
+    }
+
+    /// Path of the compressed delta from index generation `from` to the next.
+    pub fn delta_path(&self, flavour: &str, arch: &str, from: u64) -> String {
+        self.file_path(flavour, arch, &format!("{}.delta-{}.db.zst", self.name, from))
+    }
+}
+
+/// The official repositories, used when `{root}/etc/koushou/repos.kdl` is missing.
+pub fn default_repositories() -> Vec<Repository> {
+    vec![Repository::new("core", 100), Repository::new("main", 50)]
+}
+
+/// Load the enabled repositories from `{root}/etc/koushou/repos.kdl`,
+/// highest priority first:
+///
+/// ```kdl
+/// repo "core" priority=100
+/// repo "main" priority=50
+/// repo "seiryo-work" priority=200 url="https://pkgs.example.com/seiryo" \
+///     signing-key="<base64 Ed25519 public key>"
+/// repo "testing" mirrorlist="testing" enabled=#false
+/// ```
+///
+/// A repository without `url` or `mirrorlist` uses the system mirrorlist.
+pub fn load_repositories(root: &Path) -> Result<Vec<Repository>, RepoError> {
+    let path = root.join("etc/koushou/repos.kdl");
+    if !path.exists() {
+        return Ok(default_repositories());
+    }
+    let content = fs::read_to_string(path)?;
+    let mut repos = parse_repositories(&content)?;
+    repos.retain(|r| r.enabled);
+    Ok(repos)
+}
+
+pub fn parse_repositories(input: &str) -> Result<Vec<Repository>, RepoError> {
+    let doc: KdlDocument = input.parse()?;
+
+    let mut repos: Vec<Repository> = Vec::new();
+    for node in doc.nodes() {
+        if node.name().value() != "repo" {
+            continue;
+        }
+
+        let name = node
+            .entries()
+            .iter()
+            .find(|e| e.name().is_none())
+            .and_then(|e| e.value().as_string())
+            .ok_or(RepoError::MissingName)?
+            .to_string();
+        // Each repository is cached as `<name>.db`.
+        if repos.iter().any(|r| r.name == name) {
+            return Err(RepoError::DuplicateName(name));
+        }
+
+        let priority = match node.get("priority") {
+            Some(v) => v.as_integer().and_then(|p| i64::try_from(p).ok()).ok_or_else(|| {
+                RepoError::InvalidValue {
+                    name: name.clone(),
+                    field: "priority".to_string(),
+                }
+            })?,
+            None => 0,
+        };
+
+        let url = string_prop(node, &name, "url")?;
+        let mirrorlist = string_prop(node, &name, "mirrorlist")?;
+        let source = match (url, mirrorlist) {
+            (Some(_), Some(_)) => return Err(RepoError::AmbiguousSource(name)),
+            (Some(url), None) => Source::Url(url),
+            (None, Some(list)) => Source::NamedMirrorlist(list),
+            (None, None) => Source::Mirrorlist,
+        };
+
+        let enabled = match node.get("enabled") {
+            Some(v) => v.as_bool().ok_or_else(|| RepoError::InvalidValue {
+                name: name.clone(),
+                field: "enabled".to_string(),
+            })?,
+            None => true,
+        };
+
+        let signing_key = string_prop(node, &name, "signing-key")?;
+
+        repos.push(Repository { name, priority, source, enabled, signing_key });
+    }
+
+    // Stable, so repositories of equal priority keep their listed order.
+    repos.sort_by(|a, b| b.priority.cmp(&a.priority));
+    Ok(repos)
+}
+
+fn string_prop(node: &KdlNode, name: &str, field: &str) -> Result<Option<String>, RepoError> {
+    match node.get(field) {
+        Some(v) => v.as_string().map(|s| Some(s.to_string())).ok_or_else(|| {
+            RepoError::InvalidValue {
+                name: name.to_string(),
+                field: field.to_string(),
+            }
+        }),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(repos: &[Repository]) -> Vec<&str> {
+        repos.iter().map(|r| r.name.as_str()).collect()
+    }
+
+    #[test]
+    fn sorts_by_priority_keeping_listed_order() {
+        let repos = parse_repositories(
+            r#"
+            repo "main" priority=50
+            repo "extra"
+            repo "core" priority=100
+            repo "community" priority=50
+            "#,
+        )
+        .unwrap();
+        assert_eq!(names(&repos), ["core", "main", "community", "extra"]);
+        assert_eq!(repos[3].priority, 0);
+    }
+
+    #[test]
+    fn reads_sources_and_signing_keys() {
+        let repos = parse_repositories(
+            r#"
+            repo "core" priority=3
+            repo "work" priority=2 url="https://pkgs.example.com/seiryo" signing-key="a2V5"
+            repo "testing" priority=1 mirrorlist="testing"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(repos[0].source, Source::Mirrorlist);
+        assert_eq!(repos[0].signing_key, None);
+        assert_eq!(repos[1].source, Source::Url("https://pkgs.example.com/seiryo".to_string()));
+        assert_eq!(repos[1].signing_key.as_deref(), Some("a2V5"));
+        assert_eq!(repos[2].source, Source::NamedMirrorlist("testing".to_string()));
+
+        assert!(matches!(
+            parse_repositories(r#"repo "core" url="/srv/repo" mirrorlist="core""#),
+            Err(RepoError::AmbiguousSource(name)) if name == "core"
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_entries() {
+        let invalid_value = |input: &str, expected: &str| {
+            match parse_repositories(input) {
+                Err(RepoError::InvalidValue { name, field }) => {
+                    assert_eq!((name.as_str(), field.as_str()), ("core", expected), "{}", input)
+                }
+                other => panic!("expected InvalidValue for {}, got {:?}", input, other),
+            }
+        };
+        invalid_value(r#"repo "core" priority="high""#, "priority");
+        invalid_value(r#"repo "core" enabled="yes""#, "enabled");
+        invalid_value(r#"repo "core" url=1"#, "url");
+        invalid_value(r#"repo "core" mirrorlist=#true"#, "mirrorlist");
+        invalid_value(r#"repo "core" signing-key=42"#, "signing-key");
+
+        assert!(matches!(parse_repositories("repo priority=1"), Err(RepoError::MissingName)));
+        assert!(matches!(
+            parse_repositories("repo \"core\"\nrepo \"main\"\nrepo \"core\" enabled=#false"),
+            Err(RepoError::DuplicateName(name)) if name == "core"
+        ));
+    }
+
+    #[test]
+    fn loads_enabled_repositories() {
+        let root = tempfile::tempdir().unwrap();
+        assert_eq!(load_repositories(root.path()).unwrap(), default_repositories());
+
+        let dir = root.path().join("etc/koushou");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("repos.kdl"),
+            "repo \"core\" priority=100\nrepo \"testing\" priority=200 enabled=#false\n",
+        )
+        .unwrap();
+        let repos = load_repositories(root.path()).unwrap();
+        assert_eq!(names(&repos), ["core"]);
+        assert!(repos[0].enabled);
+    }
+}
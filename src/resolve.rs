@@ -2,10 +2,9 @@
 
 use std::fs;
 use std::path::{Path, PathBuf};
-use std::io::Write;
 use thiserror::Error;
-use indicatif::{ProgressBar, ProgressStyle};
 use crate::depres;
+use crate::pkgdb;
 
 #[derive(Error, Debug)]
 pub enum ResolveError {
@@ -25,6 +24,8 @@ pub enum ResolveError {
     },
     #[error("Dependency resolution error: {0}")]
     Depres(#[from] depres::DepresError),
+    #[error("Package database error: {0}")]
+    PkgDb(#[from] pkgdb::PkgDbError),
     #[error("{0}")]
     Other(String),
 }
@@ -35,65 +36,97 @@ pub struct ResolvedPackage {
     pub version: String,
     pub arch: String,
     pub filename: String,
-    pub url: String,
+    /// Name of the repository the package comes from.
+    pub repo: String,
+    /// Location of the package file relative to a mirror's root.
+    pub path: String,
     pub sha256: String,
     pub depends: Vec<String>,
+    pub action: depres::Action,
 }
 
+/// A step of the install plan; see `depres::InstallStep`.
+#[derive(Debug, Clone)]
+pub struct ResolvedStep {
+    pub packages: Vec<ResolvedPackage>,
+}
+
+/// The flavour (from `{root}/etc/koushou/flavour`) and architecture that
+/// packages are resolved for.
+pub fn system_target(root: &Path) -> Result<(String, &'static str), ResolveError> {
+    let flavour_path = root.join("etc/koushou/flavour");
+    if !flavour_path.exists() {
+        return Err(ResolveError::Other(format!(
+            "Flavour file not found: {}",
+            flavour_path.display()
+        )));
+    }
+
+    let flavour = fs::read_to_string(&flavour_path)?.trim().to_string();
+
+    let arch = match std::env::consts::ARCH {
+        "x86_64" | "aarch64" => std::env::consts::ARCH,
+        _ => "x86_64",
+    };
+
+    Ok((flavour, arch))
+}
+
+/// Everything a transaction changes: the install plan, and the installed
+/// packages that are removed because something in the plan replaces them.
+#[derive(Debug, Clone)]
+pub struct ResolvedTransaction {
+    pub steps: Vec<ResolvedStep>,
+    pub removals: Vec<depres::Removal>,
+    pub suggestions: Vec<depres::Suggestion>,
+}
+
+/// Resolve `package_names` into an ordered install plan: dependencies come
+/// before dependents, and the members of a dependency cycle share one step.
+/// Every package carries its action relative to `{root}/var/lib/koushou/db.json`.
 pub async fn resolve_transaction(
     package_names: Vec<&str>,
     flavour: &str,
     arch: &str,
     root: &Path,
-) -> Result<Vec<ResolvedPackage>, ResolveError> {
+    policy: depres::DependencyPolicy,
+) -> Result<ResolvedTransaction, ResolveError> {
     let universe = depres::PackageUniverse::load_from_cache(root)?;
     let root_pkgs: Vec<String> = package_names.into_iter().map(|s| s.to_string()).collect();
 
-    let solution = universe.resolve(&root_pkgs, flavour, arch)?;
-
-    let mut resolved = Vec::new();
-    for pkg in solution.packages {
-        let filename = format!("{}-{}-{}.kpkg", pkg.name, pkg.version, pkg.arch);
-        resolved.push(ResolvedPackage {
-            name: pkg.name.clone(),
-            version: pkg.version,
-            arch: pkg.arch,
-            filename,
-            url: solution.download_urls[&pkg.name].clone(),
-            sha256: solution.sha256_sums[&pkg.name].clone(),
-            depends: Vec::new(), // not needed post-resolve
-        });
-    }
+    let db = pkgdb::PackageDatabase::load_or_new(root.join("var/lib/koushou/db.json"))?;
+    let installed = db
+        .list()
+        .map(|pkg| (pkg.name.clone(), pkg.version.clone()))
+        .collect();
 
-    Ok(resolved)
-}
+    let solution = universe.resolve(&root_pkgs, flavour, arch, &installed, policy)?;
 
-pub async fn download_package(url: &str, output_path: &Path) -> Result<(), ResolveError> {
-    let response = reqwest::get(url).await?;
-    let total_size = response.content_length().unwrap_or(0);
-
-    let pb = ProgressBar::new(total_size);
-    pb.set_style(
-        ProgressStyle::default_bar()
-            .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-            .unwrap()
-            .progress_chars("=>-"),
-    );
-
-    let mut file = fs::File::create(output_path)?;
-    let mut stream = response.bytes_stream();
-    let mut downloaded: u64 = 0;
-
-    use futures_util::StreamExt;
-    while let Some(item) = stream.next().await {
-        let chunk = item.map_err(|e| ResolveError::Http(e.into()))?;
-        file.write_all(&chunk)?;
-        downloaded += chunk.len() as u64;
-        pb.set_position(downloaded);
+    let mut steps = Vec::new();
+    for step in solution.plan {
+        let mut resolved = Vec::new();
+        for pkg in step.packages {
+            let filename = format!("{}-{}-{}.kpkg", pkg.name, pkg.version, pkg.arch);
+            resolved.push(ResolvedPackage {
+                name: pkg.name.clone(),
+                version: pkg.version.to_string(),
+                arch: pkg.arch,
+                filename,
+                repo: solution.repositories[&pkg.name].clone(),
+                path: solution.download_paths[&pkg.name].clone(),
+                sha256: solution.sha256_sums[&pkg.name].clone(),
+                depends: Vec::new(), // not needed post-resolve
+                action: solution.actions[&pkg.name].clone(),
+            });
+        }
+        steps.push(ResolvedStep { packages: resolved });
     }
 
-    pb.finish_with_message("Downloaded");
-    Ok(())
+    Ok(ResolvedTransaction {
+        steps,
+        removals: solution.removals,
+        suggestions: solution.suggestions,
+    })
 }
 
 pub fn compute_sha256(path: &Path) -> Result<String, std::io::Error> {
@@ -103,3 +136,74 @@ pub fn compute_sha256(path: &Path) -> Result<String, std::io::Error> {
     std::io::copy(&mut file, &mut hasher)?;
     Ok(format!("{:x}", hasher.finalize()))
 }
+
+/// Check that the file at `path` hashes to `expected`.
+pub fn verify_sha256(path: &Path, expected: &str) -> Result<(), ResolveError> {
+    let actual = compute_sha256(path)?;
+    if actual != expected {
+        return Err(ResolveError::Sha256Mismatch {
+            filename: path.file_name().unwrap_or_default().to_string_lossy().into_owned(),
+            expected: expected.to_string(),
+            actual,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache;
+    use crate::index;
+
+    #[tokio::test]
+    async fn keeps_the_plan_order() {
+        let root = tempfile::tempdir().unwrap();
+        let indexes = cache::RepoCache::new(root.path()).current();
+        fs::create_dir_all(&indexes).unwrap();
+        let conn = rusqlite::Connection::open(indexes.join("core.db")).unwrap();
+        index::create_schema(&conn).unwrap();
+        let packages: [(&str, &[&str]); 4] = [
+            ("editor", &["gui", "libc"]),
+            ("gui", &["toolkit"]),
+            ("toolkit", &["gui", "libc"]),
+            ("libc", &[]),
+        ];
+        for (name, depends) in packages {
+            conn.execute(
+                "INSERT INTO packages VALUES (?, '1.0', 'x86_64', 'glibc-systemd', ?, '')",
+                [name.to_string(), format!("{}-1.0-x86_64.kpkg", name)],
+            )
+            .unwrap();
+            for dep in depends {
+                conn.execute(
+                    "INSERT INTO dependencies
+                     VALUES (?, '1.0', 'x86_64', 'glibc-systemd', ?, NULL, 'depends', NULL)",
+                    [name, *dep],
+                )
+                .unwrap();
+            }
+        }
+        drop(conn);
+
+        let transaction = resolve_transaction(
+            vec!["editor"],
+            "glibc-systemd",
+            "x86_64",
+            root.path(),
+            depres::DependencyPolicy::default(),
+        )
+        .await
+        .unwrap();
+        let steps: Vec<Vec<&str>> = transaction
+            .steps
+            .iter()
+            .map(|step| step.packages.iter().map(|pkg| pkg.name.as_str()).collect())
+            .collect();
+        assert_eq!(steps, [vec!["libc"], vec!["gui", "toolkit"], vec!["editor"]]);
+        let gui = &transaction.steps[1].packages[0];
+        assert_eq!(gui.filename, "gui-1.0-x86_64.kpkg");
+        assert_eq!(gui.path, "glibc-systemd/core/x86_64/gui-1.0-x86_64.kpkg");
+        assert_eq!(gui.action, depres::Action::Install);
+    }
+}
@@ -0,0 +1,119 @@
+// src/signature.rs
+//
+// Repository indexes can be signed with Ed25519. ksmkdb writes the signature
+// of each file it publishes next to it as `<file>.sig`, base64-encoded;
+// kspkg checks it against the `signing-key` of the repository in repos.kdl.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use ring::signature::{self as ed25519, Ed25519KeyPair, KeyPair, UnparsedPublicKey};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum SignatureError {
+    #[error("Invalid signing key: {0}")]
+    InvalidKey(String),
+    #[error("Invalid signature file: {0}")]
+    InvalidSignature(PathBuf),
+    #[error("Signature check failed for {0}")]
+    BadSignature(PathBuf),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// Where the signature of the repository file at `path` is published.
+pub fn signature_path(path: &str) -> String {
+    format!("{}.sig", path)
+}
+
+/// The key a repository's files are checked against.
+#[derive(Clone)]
+pub struct PublicKey(Vec<u8>);
+
+impl PublicKey {
+    /// Parse a base64 Ed25519 public key, as printed by `ksmkdb --signing-key`.
+    pub fn from_base64(key: &str) -> Result<Self, SignatureError> {
+        let bytes = BASE64
+            .decode(key.trim())
+            .map_err(|e| SignatureError::InvalidKey(e.to_string()))?;
+        if bytes.len() != 32 {
+            return Err(SignatureError::InvalidKey(format!(
+                "expected 32 bytes, got {}",
+                bytes.len()
+            )));
+        }
+        Ok(PublicKey(bytes))
+    }
+
+    /// Check `path` against the signature in `signature`.
+    pub fn verify_file(&self, path: &Path, signature: &Path) -> Result<(), SignatureError> {
+        let encoded = fs::read_to_string(signature)?;
+        let signature_bytes = BASE64
+            .decode(encoded.trim())
+            .map_err(|_| SignatureError::InvalidSignature(signature.to_path_buf()))?;
+        let data = fs::read(path)?;
+        UnparsedPublicKey::new(&ed25519::ED25519, &self.0)
+            .verify(&data, &signature_bytes)
+            .map_err(|_| SignatureError::BadSignature(path.to_path_buf()))
+    }
+}
+
+/// The private key a repository is published with.
+pub struct SigningKey(Ed25519KeyPair);
+
+impl SigningKey {
+    /// Load a PKCS#8 Ed25519 private key in DER form, e.g. from
+    /// `openssl genpkey -algorithm ed25519 -outform DER`.
+    pub fn load(path: &Path) -> Result<Self, SignatureError> {
+        let der = fs::read(path)?;
+        Ed25519KeyPair::from_pkcs8_maybe_unchecked(&der)
+            .map(SigningKey)
+            .map_err(|e| SignatureError::InvalidKey(e.to_string()))
+    }
+
+    /// The public key, in the form repos.kdl takes it.
+    pub fn public_key(&self) -> String {
+        BASE64.encode(self.0.public_key().as_ref())
+    }
+
+    /// Write the signature of `path` to `<path>.sig` and return its path.
+    pub fn sign_file(&self, path: &Path) -> Result<PathBuf, SignatureError> {
+        let signature = self.0.sign(&fs::read(path)?);
+        let mut sig_path = path.as_os_str().to_owned();
+        sig_path.push(".sig");
+        let sig_path = PathBuf::from(sig_path);
+        fs::write(&sig_path, format!("{}\n", BASE64.encode(signature.as_ref())))?;
+        Ok(sig_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifies_signed_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let rng = ring::rand::SystemRandom::new();
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        let key_path = dir.path().join("repo.key");
+        fs::write(&key_path, pkcs8.as_ref()).unwrap();
+
+        let signing_key = SigningKey::load(&key_path).unwrap();
+        let file = dir.path().join("core.db.zst");
+        fs::write(&file, b"index").unwrap();
+        let sig = signing_key.sign_file(&file).unwrap();
+
+        let public_key = PublicKey::from_base64(&signing_key.public_key()).unwrap();
+        public_key.verify_file(&file, &sig).unwrap();
+
+        fs::write(&file, b"tampered").unwrap();
+        assert!(matches!(
+            public_key.verify_file(&file, &sig),
+            Err(SignatureError::BadSignature(_))
+        ));
+        assert!(PublicKey::from_base64("c2hvcnQ=").is_err());
+    }
+}
@@ -3,9 +3,16 @@
 use std::fs;
 use std::path::{Path, PathBuf};
 use zstd::stream::read::Decoder as ZstdDecoder;
-use std::io::Read;
 use thiserror::Error;
 use serde::{Deserialize, Serialize};
+use indicatif::MultiProgress;
+
+use crate::cache;
+use crate::fetch;
+use crate::index;
+use crate::repo;
+use crate::resolve;
+use crate::signature;
 
 #[derive(Error, Debug)]
 pub enum SyncError {
@@ -13,8 +20,22 @@ pub enum SyncError {
     Http(#[from] reqwest::Error),
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
-    #[error("TOML parse error: {0}")]
-    Toml(#[from] toml::de::Error),
+    #[error("Invalid repository index: {0}")]
+    Index(#[from] index::IndexError),
+    #[error("Fetch error: {0}")]
+    Fetch(#[from] fetch::FetchError),
+    #[error("Sync state error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Repository configuration error: {0}")]
+    Repo(#[from] repo::RepoError),
+    #[error("Signature error: {0}")]
+    Signature(#[from] signature::SignatureError),
+    #[error("Could not verify repository {repo}: {source}")]
+    Unverified {
+        repo: String,
+        #[source]
+        source: fetch::FetchError,
+    },
     #[error("Failed to read flavour from {{root}}/etc/koushou/flavour")]
     MissingFlavour,
     #[error("Unsupported architecture: {0}")]
@@ -23,21 +44,6 @@ pub enum SyncError {
     Other(String),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct RepoPackage {
-    pub version: String,
-    pub arch: String,
-    pub filename: String,
-    pub sha256: String,
-    pub depends: Vec<String>,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-pub struct RepoDatabase {
-    #[serde(flatten)]
-    pub packages: std::collections::HashMap<String, RepoPackage>,
-}
-
 fn detect_arch() -> Result<String, SyncError> {
     match std::env::consts::ARCH {
         "x86_64" => Ok("x86_64".to_string()),
@@ -55,56 +61,621 @@ fn read_flavour(root: &Path) -> Result<String, SyncError> {
     Ok(content.trim().to_string())
 }
 
-pub async fn sync_repos(root: &Path) -> Result<(), SyncError> {
+/// Sync every enabled repository, at most `jobs` of them at a time.
+pub async fn sync_repos(root: &Path, jobs: usize) -> Result<(), SyncError> {
     println!("📡 Syncing repositories...");
 
     let flavour = read_flavour(root)?;
     let arch = detect_arch()?;
-    let cache_dir = root.join("var/cache/koushou/repos");
-    fs::create_dir_all(&cache_dir)?;
+    let repositories = repo::load_repositories(root)?;
 
-    let repo_base = "https://seiryolinux.github.io/repo";
+    let progress = MultiProgress::new();
+    let mut fetchers = Vec::new();
+    for repository in &repositories {
+        let fetcher = fetch::Fetcher::for_repository(root, repository)?;
+        fetchers.push((repository, fetcher.with_progress(progress.clone())));
+    }
 
-    sync_repo(repo_base, &flavour, "core", &arch, &cache_dir).await?;
-    sync_repo(repo_base, &flavour, "main", &arch, &cache_dir).await?;
+    // Every repository is updated in a staged copy of the cache, which only
+    // replaces the current one once all of them succeeded.
+    let cache = cache::RepoCache::new(root);
+    let lock = cache.lock()?;
+    let staging = cache.stage(&lock)?;
+    let total = fetch::total_bar(&progress, fetchers.len(), "repositories");
+    let tasks = fetchers
+        .iter()
+        .map(|(repository, fetcher)| sync_repo(fetcher, repository, &flavour, &arch, &staging))
+        .collect();
+    let synced = fetch::run_all(tasks, jobs, &total).await;
+    total.finish_and_clear();
+
+    let changed = match synced {
+        Ok(updated) => updated.contains(&true),
+        Err(e) => {
+            cache.discard(&lock)?;
+            return Err(e);
+        }
+    };
+    if changed {
+        cache.commit(&lock)?;
+    } else {
+        cache.discard(&lock)?;
+    }
 
     println!("✓ Repos synced successfully.");
     Ok(())
 }
 
+/// What the last successful sync of a repository fetched, kept beside the
+/// cached index as `<repo>.sync.json`.
+#[derive(Debug, Serialize, Deserialize)]
+struct SyncState {
+    validators: fetch::Validators,
+    /// SHA-256 of `<repo>.db.zst`.
+    sha256: String,
+}
+
+impl SyncState {
+    fn load(path: &Path) -> Option<Self> {
+        serde_json::from_str(&fs::read_to_string(path).ok()?).ok()
+    }
+
+    /// Replaces the file rather than writing into it; it may be shared with
+    /// the current generation of the cache.
+    fn save(&self, path: &Path) -> Result<(), SyncError> {
+        let tmp_path = path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_string_pretty(self)?)?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
+
+/// Update one repository's index in `cache_dir`. Returns whether the index
+/// changed. Output goes through the fetcher's progress bars, and unpacking,
+/// hashing and SQLite run on the blocking thread pool, since the other
+/// repositories are synced on the same task.
 async fn sync_repo(
-    repo_base: &str,
+    fetcher: &fetch::Fetcher,
+    repository: &repo::Repository,
     flavour: &str,
-    repo_name: &str,
     arch: &str,
     cache_dir: &Path,
-) -> Result<(), SyncError> {
-    let url = format!(
-        "{}/{}/{}/{}/{}.db.zst",
-        repo_base, flavour, repo_name, arch, repo_name
-    );
+) -> Result<bool, SyncError> {
+    let repo_name = &repository.name;
+    let index_path = repository.index_path(flavour, arch);
     let cache_path = cache_dir.join(format!("{}.db.zst", repo_name));
+    let part_path = cache_dir.join(format!("{}.db.zst.part", repo_name));
+    let db_path = cache_dir.join(format!("{}.db", repo_name));
+    let tmp_path = cache_dir.join(format!("{}.db.tmp", repo_name));
+    let state_path = cache_dir.join(format!("{}.sync.json", repo_name));
+    let sig_path = cache_dir.join(format!("{}.db.zst.sig", repo_name));
+    let progress = fetcher.progress();
+
+    match sync_deltas(fetcher, repository, flavour, arch, cache_dir).await {
+        Ok(Some(changed)) => return Ok(changed),
+        Ok(None) => {}
+        Err(e) => progress
+            .suspend(|| eprintln!("    ⚠️ Could not update {} with deltas: {}", repo_name, e)),
+    }
+
+    progress.suspend(|| println!("  → Fetching {}", index_path));
 
-    println!("  → Fetching {}", url);
+    // Only trust the saved state while the index it describes is still there.
+    let previous = if db_path.exists() && cache_path.exists() {
+        SyncState::load(&state_path)
+    } else {
+        None
+    };
+    let _ = fs::remove_file(&tmp_path);
 
-    let response = reqwest::get(&url).await?;
-    if !response.status().is_success() {
-        eprintln!("    ⚠️ Repo {} not found ({}). Skipping.", repo_name, response.status());
-        return Ok(());
+    // A signed repository is only skipped when no mirror can be reached; a
+    // missing or bad signature is an error, so that it does not go unnoticed.
+    let key = match fetch_signature(fetcher, repository, &index_path, &sig_path).await {
+        Err(SyncError::Fetch(fetch::FetchError::AllMirrorsFailed { offline: true, .. })) => {
+            progress.suspend(|| {
+                eprintln!("    ⚠️ Repo {} is not available from any mirror. Skipping.", repo_name)
+            });
+            return Ok(false);
+        }
+        Err(SyncError::Fetch(source)) => {
+            return Err(SyncError::Unverified { repo: repo_name.clone(), source });
+        }
+        other => other?,
+    };
+
+    // Unpack next to the cache and check the index before it replaces the
+    // one depres reads. A mirror serving a broken index counts as failed.
+    // An index identical to the cached one is not unpacked at all.
+    let verify = {
+        let (sig_path, tmp_path) = (sig_path.clone(), tmp_path.clone());
+        let cached = previous.as_ref().map(|state| state.sha256.clone());
+        move |path: &Path| {
+            check_signature(key.as_ref(), path, &sig_path)?;
+            let sha256 = resolve::compute_sha256(path).map_err(|e| e.to_string())?;
+            if cached.as_ref() == Some(&sha256) {
+                return Ok(());
+            }
+            unpack_index(path, &tmp_path).map_err(|e| e.to_string())
+        }
+    };
+    let fetched = fetcher
+        .fetch_if_changed(
+            &index_path,
+            &part_path,
+            previous.as_ref().map(|state| &state.validators),
+            verify,
+        )
+        .await;
+    let (mirror, validators) = match fetched {
+        Ok(fetch::Fetched::NotModified { mirror }) => {
+            progress.suspend(|| println!("    ✓ {} is up to date ({})", repo_name, mirror.name));
+            return Ok(false);
+        }
+        Ok(fetch::Fetched::Updated { mirror, validators }) => (mirror, validators),
+        Err(fetch::FetchError::AllMirrorsFailed { offline, .. })
+            if offline || repository.signing_key.is_none() =>
+        {
+            let _ = fs::remove_file(&tmp_path);
+            progress.suspend(|| {
+                eprintln!("    ⚠️ Repo {} is not available from any mirror. Skipping.", repo_name)
+            });
+            return Ok(false);
+        }
+        Err(source @ fetch::FetchError::AllMirrorsFailed { .. }) => {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(SyncError::Unverified { repo: repo_name.clone(), source });
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let part = part_path.clone();
+    let state = SyncState {
+        validators,
+        sha256: blocking(move || Ok(resolve::compute_sha256(&part)?)).await?,
+    };
+    fs::rename(&part_path, &cache_path)?;
+    let changed = tmp_path.exists();
+    if changed {
+        fs::rename(&tmp_path, &db_path)?;
+    }
+    state.save(&state_path)?;
+
+    progress.suspend(|| {
+        if changed {
+            println!("    ✓ {} synced from {}", repo_name, mirror.name);
+        } else {
+            println!("    ✓ {} is up to date ({})", repo_name, mirror.name);
+        }
+    });
+    Ok(changed)
+}
+
+/// Bring the cached index up to the newest generation with the deltas the
+/// mirrors publish, returning whether it changed. Returns `None` when there
+/// is nothing to start from, the published index belongs to another chain
+/// or the deltas do not reach the cached generation; the full index is
+/// fetched then.
+async fn sync_deltas(
+    fetcher: &fetch::Fetcher,
+    repository: &repo::Repository,
+    flavour: &str,
+    arch: &str,
+    cache_dir: &Path,
+) -> Result<Option<bool>, SyncError> {
+    let repo_name = &repository.name;
+    let db_path = cache_dir.join(format!("{}.db", repo_name));
+    let tmp_path = cache_dir.join(format!("{}.db.tmp", repo_name));
+    let generation_part = cache_dir.join(format!("{}.generation.part", repo_name));
+    let generation_sig = cache_dir.join(format!("{}.generation.sig", repo_name));
+    let delta_part = cache_dir.join(format!("{}.delta.db.zst.part", repo_name));
+    let delta_path = cache_dir.join(format!("{}.delta.db", repo_name));
+    let delta_sig = cache_dir.join(format!("{}.delta.db.zst.sig", repo_name));
+
+    let progress = fetcher.progress();
+
+    if !db_path.exists() {
+        return Ok(None);
     }
+    let db = db_path.clone();
+    let (current, chain) = blocking(move || {
+        let conn = rusqlite::Connection::open(&db).map_err(index::IndexError::from)?;
+        index::check_format(&conn)?;
+        Ok((index::generation(&conn)?, index::chain(&conn)?))
+    })
+    .await?;
+    if current == 0 {
+        return Ok(None);
+    }
+
+    let generation_path = repository.generation_path(flavour, arch);
+    let key = match fetch_signature(fetcher, repository, &generation_path, &generation_sig).await {
+        Err(SyncError::Fetch(fetch::FetchError::AllMirrorsFailed { .. })) => return Ok(None),
+        other => other?,
+    };
+    let signature = generation_sig.clone();
+    let fetched = fetcher
+        .fetch(&generation_path, &generation_part, move |path| {
+            check_signature(key.as_ref(), path, &signature)?;
+            read_generation(path).map(|_| ()).map_err(|e| e.to_string())
+        })
+        .await;
+    let published = match fetched {
+        Ok(_) => read_generation(&generation_part),
+        Err(fetch::FetchError::AllMirrorsFailed { .. }) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let _ = fs::remove_file(&generation_part);
+    let _ = fs::remove_file(&generation_sig);
+    let (latest, latest_chain) = published?;
+
+    // A repository regenerated from scratch starts a new chain, possibly at
+    // the very generation the cache is at.
+    if latest_chain.is_none() || latest_chain != chain {
+        return Ok(None);
+    }
+    if latest == current {
+        progress.suspend(|| println!("    ✓ {} is up to date (generation {})", repo_name, current));
+        return Ok(Some(false));
+    }
+    if latest < current {
+        return Ok(None);
+    }
+
+    progress.suspend(|| {
+        println!("  → Updating {} from generation {} to {}", repo_name, current, latest)
+    });
+    tokio::fs::copy(&db_path, &tmp_path).await?;
+    for from in current..latest {
+        let path = repository.delta_path(flavour, arch, from);
+        let applied = match fetch_signature(fetcher, repository, &path, &delta_sig).await {
+            Ok(key) => {
+                fetch_and_apply_delta(fetcher, &path, key, &delta_part, &delta_sig, &delta_path, &tmp_path)
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        let _ = fs::remove_file(&delta_part);
+        let _ = fs::remove_file(&delta_path);
+        let _ = fs::remove_file(&delta_sig);
+        if let Err(e) = applied {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+    }
+    fs::rename(&tmp_path, &db_path)?;
+
+    // The cached full index and its validators no longer describe `<repo>.db`.
+    let _ = fs::remove_file(cache_dir.join(format!("{}.db.zst", repo_name)));
+    let _ = fs::remove_file(cache_dir.join(format!("{}.sync.json", repo_name)));
+
+    progress.suspend(|| {
+        println!("    ✓ {} updated to generation {} with {} deltas", repo_name, latest, latest - current)
+    });
+    Ok(Some(true))
+}
 
-    let bytes = response.bytes().await?;
-    fs::write(&cache_path, &bytes)?;
+/// Fetch the delta at `path` into `part`, unpack it to `unpacked` and apply
+/// it to the index at `db`.
+async fn fetch_and_apply_delta(
+    fetcher: &fetch::Fetcher,
+    path: &str,
+    key: Option<signature::PublicKey>,
+    part: &Path,
+    signature: &Path,
+    unpacked: &Path,
+    db: &Path,
+) -> Result<(), SyncError> {
+    let (signature, delta) = (signature.to_path_buf(), unpacked.to_path_buf());
+    let (unpacked, db) = (unpacked.to_path_buf(), db.to_path_buf());
+    fetcher
+        .fetch(path, part, move |part| {
+            check_signature(key.as_ref(), part, &signature)?;
+            unpack_index(part, &delta).map_err(|e| e.to_string())
+        })
+        .await?;
+    blocking(move || {
+        let conn = rusqlite::Connection::open(&db).map_err(index::IndexError::from)?;
+        Ok(index::apply_delta(&conn, &unpacked)?)
+    })
+    .await
+}
 
-    let mut decoder = ZstdDecoder::new(&bytes[..])?;
-    let mut db_content = String::new();
-    decoder.read_to_string(&mut db_content)?;
+/// Run blocking work on the blocking thread pool rather than on the task
+/// that drives the downloads.
+async fn blocking<T, F>(work: F) -> Result<T, SyncError>
+where
+    F: FnOnce() -> Result<T, SyncError> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(work)
+        .await
+        .map_err(|e| SyncError::Other(e.to_string()))?
+}
 
-    let _db: RepoDatabase = toml::from_str(&db_content)
-        .map_err(|e| SyncError::Other(format!("Invalid repo DB {}: {}", repo_name, e)))?;
+/// When the repository is signed, fetch the signature of `path` into `dest`
+/// and return the key the file has to be checked against.
+async fn fetch_signature(
+    fetcher: &fetch::Fetcher,
+    repository: &repo::Repository,
+    path: &str,
+    dest: &Path,
+) -> Result<Option<signature::PublicKey>, SyncError> {
+    let key = match &repository.signing_key {
+        Some(key) => signature::PublicKey::from_base64(key)?,
+        None => return Ok(None),
+    };
+    fetcher.fetch(&signature::signature_path(path), dest, |_| Ok(())).await?;
+    Ok(Some(key))
+}
 
-    fs::write(cache_dir.join(format!("{}.db", repo_name)), db_content)?;
+/// A file that fails its signature check counts as a failed mirror.
+fn check_signature(
+    key: Option<&signature::PublicKey>,
+    path: &Path,
+    signature: &Path,
+) -> Result<(), String> {
+    match key {
+        Some(key) => key.verify_file(path, signature).map_err(|e| e.to_string()),
+        None => Ok(()),
+    }
+}
 
-    println!("    ✓ {} synced", repo_name);
+/// The generation and chain of the newest index, as `<repo>.generation`
+/// publishes them: `<generation> <chain>`.
+fn read_generation(path: &Path) -> Result<(u64, Option<String>), SyncError> {
+    let content = fs::read_to_string(path)?;
+    let mut fields = content.split_whitespace();
+    let generation = fields
+        .next()
+        .and_then(|generation| generation.parse().ok())
+        .ok_or_else(|| SyncError::Other(format!("invalid index generation: {}", content.trim())))?;
+    Ok((generation, fields.next().map(str::to_string)))
+}
+
+/// Decompress the index at `compressed` into `dest` and check its format.
+fn unpack_index(compressed: &Path, dest: &Path) -> Result<(), SyncError> {
+    let mut decoder = ZstdDecoder::new(fs::File::open(compressed)?)?;
+    std::io::copy(&mut decoder, &mut fs::File::create(dest)?)?;
+    validate_index(dest)?;
     Ok(())
 }
+
+fn validate_index(path: &Path) -> Result<(), index::IndexError> {
+    let conn = rusqlite::Connection::open(path)?;
+    index::check_format(&conn)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mirror::Mirror;
+
+    /// Publish an index holding `packages` rows into a mirror directory.
+    fn publish(mirror_dir: &Path, repository: &repo::Repository, packages: &[&str]) {
+        publish_generation(mirror_dir, repository, 0, "", packages);
+    }
+
+    /// Publish an index of the given generation of `chain` as ksmkdb does,
+    /// with the delta from the previous generation published before, if any.
+    fn publish_generation(
+        mirror_dir: &Path,
+        repository: &repo::Repository,
+        generation: u64,
+        chain: &str,
+        packages: &[&str],
+    ) {
+        let db = mirror_dir.join(format!("build-{}.db", generation));
+        let _ = fs::remove_file(&db);
+        let conn = rusqlite::Connection::open(&db).unwrap();
+        index::create_schema(&conn).unwrap();
+        if generation > 0 {
+            index::set_generation(&conn, generation).unwrap();
+            index::set_chain(&conn, chain).unwrap();
+        }
+        for name in packages {
+            conn.execute(
+                "INSERT INTO packages VALUES (?, '1.0', 'x86_64', 'glibc-systemd', ?, '')",
+                [name.to_string(), format!("{}-1.0-x86_64.kpkg", name)],
+            )
+            .unwrap();
+        }
+        drop(conn);
+
+        let (flavour, arch) = ("glibc-systemd", "x86_64");
+        let target = mirror_dir.join(repository.index_path(flavour, arch));
+        fs::create_dir_all(target.parent().unwrap()).unwrap();
+        let compressed = zstd::stream::encode_all(fs::File::open(&db).unwrap(), 3).unwrap();
+        fs::write(target, compressed).unwrap();
+        if generation == 0 {
+            return;
+        }
+
+        let manifest = mirror_dir.join(repository.generation_path(flavour, arch));
+        fs::write(manifest, format!("{} {}\n", generation, chain)).unwrap();
+        let old = mirror_dir.join(format!("build-{}.db", generation - 1));
+        if old.exists() {
+            let delta = mirror_dir.join("delta.db");
+            let _ = fs::remove_file(&delta);
+            index::write_delta(&old, &db, &delta).unwrap();
+            let compressed = zstd::stream::encode_all(fs::File::open(&delta).unwrap(), 3).unwrap();
+            fs::write(mirror_dir.join(repository.delta_path(flavour, arch, generation - 1)), compressed)
+                .unwrap();
+        }
+    }
+
+    fn package_count(db_path: &Path) -> i64 {
+        let conn = rusqlite::Connection::open(db_path).unwrap();
+        conn.query_row("SELECT COUNT(*) FROM packages", [], |r| r.get(0)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn skips_unchanged_index() {
+        let mirror_dir = tempfile::tempdir().unwrap();
+        let cache = tempfile::tempdir().unwrap();
+        let repository = repo::Repository::new("core", 100);
+        let fetcher = fetch::Fetcher::new(vec![Mirror {
+            url: mirror_dir.path().display().to_string(),
+            ..Mirror::official()
+        }])
+        .unwrap();
+        let db_path = cache.path().join("core.db");
+        let sync = || sync_repo(&fetcher, &repository, "glibc-systemd", "x86_64", cache.path());
+
+        publish(mirror_dir.path(), &repository, &["htop"]);
+        sync().await.unwrap();
+        let first = fs::metadata(&db_path).unwrap().modified().unwrap();
+        let state = SyncState::load(&cache.path().join("core.sync.json")).unwrap();
+
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        sync().await.unwrap();
+        assert_eq!(fs::metadata(&db_path).unwrap().modified().unwrap(), first);
+
+        publish(mirror_dir.path(), &repository, &["htop", "zlib"]);
+        sync().await.unwrap();
+        let updated = SyncState::load(&cache.path().join("core.sync.json")).unwrap();
+        assert_ne!(updated.sha256, state.sha256);
+        assert_eq!(package_count(&db_path), 2);
+    }
+
+    #[tokio::test]
+    async fn applies_deltas_and_falls_back_to_full_index() {
+        let mirror_dir = tempfile::tempdir().unwrap();
+        let cache = tempfile::tempdir().unwrap();
+        let repository = repo::Repository::new("core", 100);
+        let fetcher = fetch::Fetcher::new(vec![Mirror {
+            url: mirror_dir.path().display().to_string(),
+            ..Mirror::official()
+        }])
+        .unwrap();
+        let db_path = cache.path().join("core.db");
+        let full_index = mirror_dir.path().join(repository.index_path("glibc-systemd", "x86_64"));
+        let sync = || sync_repo(&fetcher, &repository, "glibc-systemd", "x86_64", cache.path());
+
+        publish_generation(mirror_dir.path(), &repository, 1, "a", &["htop"]);
+        sync().await.unwrap();
+        assert_eq!(package_count(&db_path), 1);
+
+        // Without the full index, only the deltas can bring the cache up to date.
+        publish_generation(mirror_dir.path(), &repository, 2, "a", &["htop", "zlib"]);
+        publish_generation(mirror_dir.path(), &repository, 3, "a", &["htop", "zlib", "nano"]);
+        fs::remove_file(&full_index).unwrap();
+        sync().await.unwrap();
+        assert_eq!(package_count(&db_path), 3);
+        let conn = rusqlite::Connection::open(&db_path).unwrap();
+        assert_eq!(index::generation(&conn).unwrap(), 3);
+        drop(conn);
+
+        // A broken chain falls back to the full index.
+        publish_generation(mirror_dir.path(), &repository, 5, "a", &["htop"]);
+        sync().await.unwrap();
+        assert_eq!(package_count(&db_path), 1);
+        assert!(!cache.path().join("core.db.tmp").exists());
+
+        // So does a repository regenerated from scratch, even at the same
+        // generation.
+        let _ = fs::remove_file(mirror_dir.path().join("build-4.db"));
+        publish_generation(mirror_dir.path(), &repository, 5, "b", &["htop", "vim"]);
+        sync().await.unwrap();
+        assert_eq!(package_count(&db_path), 2);
+    }
+
+    #[tokio::test]
+    async fn checks_index_signatures() {
+        let mirror_dir = tempfile::tempdir().unwrap();
+        let cache = tempfile::tempdir().unwrap();
+        let rng = ring::rand::SystemRandom::new();
+        let pkcs8 = ring::signature::Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        let key_path = mirror_dir.path().join("repo.key");
+        fs::write(&key_path, pkcs8.as_ref()).unwrap();
+        let key = signature::SigningKey::load(&key_path).unwrap();
+
+        let repository = repo::Repository {
+            signing_key: Some(key.public_key()),
+            ..repo::Repository::new("core", 100)
+        };
+        let fetcher = fetch::Fetcher::new(vec![Mirror {
+            url: mirror_dir.path().display().to_string(),
+            ..Mirror::official()
+        }])
+        .unwrap();
+        let db_path = cache.path().join("core.db");
+        let index = mirror_dir.path().join(repository.index_path("glibc-systemd", "x86_64"));
+        let sync = || sync_repo(&fetcher, &repository, "glibc-systemd", "x86_64", cache.path());
+
+        // Unsigned: refused.
+        publish(mirror_dir.path(), &repository, &["htop"]);
+        assert!(matches!(sync().await, Err(SyncError::Unverified { .. })));
+        assert!(!db_path.exists());
+
+        key.sign_file(&index).unwrap();
+        assert!(sync().await.unwrap());
+        assert_eq!(package_count(&db_path), 1);
+
+        // Signed with another key: the cached index stays.
+        publish(mirror_dir.path(), &repository, &["htop", "zlib"]);
+        let pkcs8 = ring::signature::Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        fs::write(&key_path, pkcs8.as_ref()).unwrap();
+        signature::SigningKey::load(&key_path).unwrap().sign_file(&index).unwrap();
+        assert!(matches!(sync().await, Err(SyncError::Unverified { .. })));
+        assert_eq!(package_count(&db_path), 1);
+
+        // Only a repository whose mirrors cannot be reached is skipped.
+        let offline = fetch::Fetcher::new(vec![Mirror {
+            url: "http://127.0.0.1:1".to_string(),
+            ..Mirror::official()
+        }])
+        .unwrap();
+        let synced = sync_repo(&offline, &repository, "glibc-systemd", "x86_64", cache.path()).await;
+        assert!(!synced.unwrap());
+        let unplugged = fetch::Fetcher::new(vec![Mirror {
+            url: mirror_dir.path().join("usb").display().to_string(),
+            ..Mirror::official()
+        }])
+        .unwrap();
+        let synced = sync_repo(&unplugged, &repository, "glibc-systemd", "x86_64", cache.path()).await;
+        assert!(!synced.unwrap());
+        assert_eq!(package_count(&db_path), 1);
+    }
+
+    #[tokio::test]
+    async fn checks_generation_signatures() {
+        let mirror_dir = tempfile::tempdir().unwrap();
+        let cache = tempfile::tempdir().unwrap();
+        let rng = ring::rand::SystemRandom::new();
+        let pkcs8 = ring::signature::Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        let key_path = mirror_dir.path().join("repo.key");
+        fs::write(&key_path, pkcs8.as_ref()).unwrap();
+        let key = signature::SigningKey::load(&key_path).unwrap();
+
+        let repository = repo::Repository {
+            signing_key: Some(key.public_key()),
+            ..repo::Repository::new("core", 100)
+        };
+        let fetcher = fetch::Fetcher::new(vec![Mirror {
+            url: mirror_dir.path().display().to_string(),
+            ..Mirror::official()
+        }])
+        .unwrap();
+        let (flavour, arch) = ("glibc-systemd", "x86_64");
+        let db_path = cache.path().join("core.db");
+        let index = mirror_dir.path().join(repository.index_path(flavour, arch));
+        let generation = mirror_dir.path().join(repository.generation_path(flavour, arch));
+        let sync = || sync_repo(&fetcher, &repository, flavour, arch, cache.path());
+
+        publish_generation(mirror_dir.path(), &repository, 1, "a", &["htop"]);
+        key.sign_file(&index).unwrap();
+        key.sign_file(&generation).unwrap();
+        assert!(sync().await.unwrap());
+
+        // Only the deltas are there, and the generation is not signed.
+        publish_generation(mirror_dir.path(), &repository, 2, "a", &["htop", "zlib"]);
+        key.sign_file(&mirror_dir.path().join(repository.delta_path(flavour, arch, 1))).unwrap();
+        fs::remove_file(&index).unwrap();
+        assert!(matches!(sync().await, Err(SyncError::Unverified { .. })));
+        assert_eq!(package_count(&db_path), 1);
+
+        key.sign_file(&generation).unwrap();
+        assert!(sync().await.unwrap());
+        assert_eq!(package_count(&db_path), 2);
+    }
+}
@@ -0,0 +1,1110 @@
+// src/transaction.rs
+//
+// Installs and removals run as one transaction, so a failure part-way leaves
+// the root exactly as it was. The work happens in
+// `{root}/var/lib/koushou/transaction`:
+//
+//   1. every package is unpacked into `staging/<n>/` with the owners, modes
+//      and extended attributes it was built with;
+//   2. the staged files are checked for conflicts with each other, with
+//      the files of installed packages and with unowned files;
+//   3. the new package database is written to `db.json` in the work
+//      directory, then the staged trees are merged into the root. Existing
+//      directories stay; each file is renamed over the one it replaces, so
+//      it is never missing. Every file that is replaced or removed is kept
+//      in `backup/` first, and every step is appended to `journal` before
+//      it is taken;
+//   4. the staged `db.json` is renamed over the real database. That rename
+//      is the commit; the backups are dropped after it.
+//
+// Configuration files (`config` in package.kdl, `/etc` by default) that were
+// edited since they were installed are never overwritten: an upgrade writes
+// the new version next to them as `<file>.kpnew`, and a removal leaves a copy
+// as `<file>.kpsave`.
+//
+// A failure before the commit undoes the journal. After a crash, the next
+// transaction does the same if the staged database is still there. Only one
+// transaction runs on a root at a time; `var/lib/koushou/lock` is held from
+// `begin` until the transaction is dropped.
+//
+// Parts of the root may be other filesystems, where rename fails with EXDEV.
+// There a file is copied to a temporary name next to its destination and
+// renamed from that.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use flate2::read::GzDecoder;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tar::Archive;
+use thiserror::Error;
+use walkdir::WalkDir;
+use zstd::stream::read::Decoder as ZstdDecoder;
+
+use crate::fileinfo::{self, Attributes, FileInfo, FileKind};
+use crate::lock::Lock;
+use crate::package::{self, Package};
+use crate::pkgdb::{self, InstalledPackage, PackageDatabase};
+use crate::resolve;
+
+const WORK_DIR: &str = "var/lib/koushou/transaction";
+/// A new version of a configuration file that was kept because it had
+/// been edited.
+pub const NEW_SUFFIX: &str = ".kpnew";
+/// An edited configuration file of a removed package.
+pub const SAVE_SUFFIX: &str = ".kpsave";
+const LOCK_PATH: &str = "var/lib/koushou/lock";
+const DB_PATH: &str = "var/lib/koushou/db.json";
+
+#[derive(Error, Debug)]
+pub enum TransactionError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Package parse error: {0}")]
+    PackageParse(#[from] package::PackageParseError),
+    #[error("Invalid package: missing 'files.tar.zst'")]
+    MissingFilesTar,
+    #[error("Package database error: {0}")]
+    PkgDb(#[from] pkgdb::PkgDbError),
+    #[error("Transaction journal error: {0}")]
+    Journal(#[from] serde_json::Error),
+    #[error("{}", describe_conflicts(.0))]
+    Conflicts(Vec<FileConflict>),
+    #[error("Invalid --overwrite pattern '{0}'")]
+    InvalidGlob(String),
+}
+
+/// A file a staged package cannot put in place without destroying another.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileConflict {
+    /// Relative to the root.
+    pub path: String,
+    /// The staged package shipping it.
+    pub package: String,
+    pub kind: ConflictKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConflictKind {
+    /// Also shipped by these other packages of the transaction.
+    Staged(String),
+    /// Owned by this installed package.
+    Owned(String),
+    /// On the filesystem, but not owned by any package.
+    Unowned,
+    /// A directory is where the package has a file.
+    Directory,
+    /// A file is where the package has a directory.
+    NotADirectory,
+}
+
+impl std::fmt::Display for FileConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "/{} ({}): ", self.path, self.package)?;
+        match &self.kind {
+            ConflictKind::Staged(others) => write!(f, "also in {}", others),
+            ConflictKind::Owned(owner) => write!(f, "owned by {}", owner),
+            ConflictKind::Unowned => f.write_str("exists on the filesystem"),
+            ConflictKind::Directory => f.write_str("is a directory"),
+            ConflictKind::NotADirectory => f.write_str("is not a directory"),
+        }
+    }
+}
+
+fn describe_conflicts(conflicts: &[FileConflict]) -> String {
+    let mut message = String::from("File conflicts:");
+    for conflict in conflicts {
+        message.push_str(&format!("\n  {}", conflict));
+    }
+    if conflicts.iter().any(|c| matches!(c.kind, ConflictKind::Owned(_) | ConflictKind::Unowned)) {
+        message.push_str("\nUse --overwrite <glob> or --force to replace these files.");
+    }
+    message
+}
+
+/// Which conflicting files a transaction may replace. Directories are
+/// never replaced by files, nor files by directories, and two packages of
+/// one transaction never ship the same file.
+#[derive(Debug, Clone, Default)]
+pub struct OverwritePolicy {
+    force: bool,
+    globs: Vec<Regex>,
+}
+
+impl OverwritePolicy {
+    /// `globs` are matched against paths in the root, like `/usr/lib/python3*/**`:
+    /// `*` and `?` stay within one path component, `**` spans several.
+    pub fn new(globs: &[String], force: bool) -> Result<Self, TransactionError> {
+        let globs = globs
+            .iter()
+            .map(|glob| {
+                Regex::new(&glob_to_regex(glob.trim_start_matches('/')))
+                    .map_err(|_| TransactionError::InvalidGlob(glob.clone()))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(OverwritePolicy { force, globs })
+    }
+
+    fn allows(&self, path: &str) -> bool {
+        self.force || self.globs.iter().any(|glob| glob.is_match(path))
+    }
+}
+
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                regex.push_str(".*");
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+/// One step of committing files, recorded before it is taken.
+#[derive(Debug, Serialize, Deserialize)]
+enum JournalEntry {
+    CreatedDir { path: PathBuf },
+    Created { path: PathBuf },
+    Replaced { path: PathBuf, backup: PathBuf },
+    Removed { path: PathBuf, backup: PathBuf },
+}
+
+/// A package unpacked into the work directory, waiting to be committed.
+pub struct StagedPackage {
+    pub package: Package,
+    /// The package's file tree, laid out like the root.
+    tree: PathBuf,
+    /// Everything in `tree` but directories, relative to it.
+    files: Vec<String>,
+    info: BTreeMap<String, FileInfo>,
+}
+
+pub struct Transaction {
+    root: PathBuf,
+    dir: PathBuf,
+    packages: Vec<StagedPackage>,
+    removals: Vec<String>,
+    overwrite: OverwritePolicy,
+    /// Whether files in the root may have been changed.
+    touched: bool,
+    finished: bool,
+    /// Held until the transaction is dropped; declared last so it outlives
+    /// the cleanup in `drop`.
+    _lock: Lock,
+}
+
+impl Transaction {
+    /// Start a transaction on `root`, first rolling back one that was
+    /// interrupted before its commit. Waits for a transaction running in
+    /// another process.
+    pub fn begin(root: &Path) -> Result<Self, TransactionError> {
+        let lock = Lock::acquire(&root.join(LOCK_PATH))?;
+        recover(root, &lock)?;
+        let dir = root.join(WORK_DIR);
+        fs::create_dir_all(dir.join("staging"))?;
+        fs::create_dir_all(dir.join("backup"))?;
+        Ok(Transaction {
+            root: root.to_path_buf(),
+            dir,
+            packages: Vec::new(),
+            removals: Vec::new(),
+            overwrite: OverwritePolicy::default(),
+            touched: false,
+            finished: false,
+            _lock: lock,
+        })
+    }
+
+    /// Unpack the `.kpkg` at `kpkg_path` into the work directory.
+    pub fn stage(&mut self, kpkg_path: &Path) -> Result<&Package, TransactionError> {
+        let dir = self.dir.join("staging").join(self.packages.len().to_string());
+        fs::create_dir_all(&dir)?;
+        Archive::new(GzDecoder::new(File::open(kpkg_path)?)).unpack(&dir)?;
+
+        let kdl_content = fs::read_to_string(dir.join("package.kdl"))
+            .map_err(|_| package::PackageParseError::MissingPackageNode)?;
+        let package = Package::from_kdl(&kdl_content)?;
+
+        let files_tar_path = dir.join("files.tar.zst");
+        if !files_tar_path.exists() {
+            return Err(TransactionError::MissingFilesTar);
+        }
+        let tree = dir.join("files");
+        fs::create_dir_all(&tree)?;
+        fileinfo::unpack_tree(&mut Archive::new(ZstdDecoder::new(File::open(&files_tar_path)?)?), &tree)?;
+
+        self.add_staged(package, tree)
+    }
+
+    fn add_staged(&mut self, package: Package, tree: PathBuf) -> Result<&Package, TransactionError> {
+        let info = fileinfo::describe_tree(&tree)?;
+        let files = info.keys().cloned().collect();
+        self.packages.push(StagedPackage { package, tree, files, info });
+        Ok(&self.packages.last().unwrap().package)
+    }
+
+    /// Remove the installed package `name` as part of this transaction.
+    pub fn remove(&mut self, name: &str) {
+        self.removals.push(name.to_string());
+    }
+
+    /// Replace conflicting files as `overwrite` allows instead of refusing.
+    pub fn overwrite(&mut self, overwrite: OverwritePolicy) {
+        self.overwrite = overwrite;
+    }
+
+    /// Every staged file that is also shipped by another staged package, is
+    /// owned by an installed package this transaction does not replace, or
+    /// exists on the filesystem without an owner; and every path where a
+    /// file and a directory would swap. Files of installed packages and
+    /// unowned files can be allowed with the overwrite policy.
+    fn check_conflicts(&self, db: &PackageDatabase) -> Result<(), TransactionError> {
+        let mut conflicts = Vec::new();
+
+        // Packages going away in this transaction give up their files.
+        let leaving: HashSet<&str> = self
+            .removals
+            .iter()
+            .map(|n| n.as_str())
+            .chain(self.packages.iter().map(|s| s.package.name.as_str()))
+            .collect();
+        let mut installed_owners: HashMap<&str, &str> = HashMap::new();
+        let mut leaving_files: HashSet<&str> = HashSet::new();
+        for pkg in db.list() {
+            for file in &pkg.files {
+                if leaving.contains(pkg.name.as_str()) {
+                    leaving_files.insert(file);
+                } else {
+                    installed_owners.insert(file, &pkg.name);
+                }
+            }
+        }
+
+        let mut staged_owners: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        for staged in &self.packages {
+            for file in &staged.files {
+                staged_owners.entry(file).or_default().push(&staged.package.name);
+            }
+        }
+
+        for (file, names) in &staged_owners {
+            let package = names.last().unwrap().to_string();
+            let kind = if names.len() > 1 {
+                ConflictKind::Staged(names[..names.len() - 1].join(", "))
+            } else if let Some(owner) = installed_owners.get(file) {
+                ConflictKind::Owned(owner.to_string())
+            } else if !leaving_files.contains(file)
+                && fs::symlink_metadata(self.root.join(file)).is_ok()
+            {
+                ConflictKind::Unowned
+            } else {
+                continue;
+            };
+            // Only one package can own a path, so two in one transaction
+            // always conflict.
+            if matches!(kind, ConflictKind::Staged(_)) || !self.overwrite.allows(file) {
+                conflicts.push(FileConflict { path: file.to_string(), package, kind });
+            }
+        }
+
+        for staged in &self.packages {
+            for entry in WalkDir::new(&staged.tree).min_depth(1).sort_by_file_name() {
+                let entry = entry.map_err(io::Error::from)?;
+                let rel_path = entry.path().strip_prefix(&staged.tree).unwrap();
+                let existing = match fs::metadata(self.root.join(rel_path)) {
+                    Ok(existing) => existing,
+                    Err(_) => continue,
+                };
+                if entry.file_type().is_dir() != existing.is_dir() {
+                    conflicts.push(FileConflict {
+                        path: rel_path.to_string_lossy().to_string(),
+                        package: staged.package.name.clone(),
+                        kind: if existing.is_dir() {
+                            ConflictKind::Directory
+                        } else {
+                            ConflictKind::NotADirectory
+                        },
+                    });
+                }
+            }
+        }
+
+        if conflicts.is_empty() {
+            Ok(())
+        } else {
+            Err(TransactionError::Conflicts(conflicts))
+        }
+    }
+
+    /// Check, move the staged files into the root, and record the result in
+    /// the package database. Nothing is left changed if this fails.
+    pub fn commit(mut self) -> Result<(), TransactionError> {
+        let db_path = self.root.join(DB_PATH);
+        let mut db = PackageDatabase::load_or_new(&db_path)?;
+        self.check_conflicts(&db)?;
+
+        // Files of removed and replaced versions that nothing ships anymore,
+        // and the packaged hashes of their configuration files.
+        let mut obsolete = Vec::new();
+        let mut old_config = HashMap::new();
+        let staged_names: Vec<String> = self.packages.iter().map(|s| s.package.name.clone()).collect();
+        for name in self.removals.iter().chain(&staged_names) {
+            if let Ok(old) = db.remove(name) {
+                old_config.extend(packaged_config(&old));
+                obsolete.extend(old.files);
+            }
+        }
+        // Overwritten files change owner.
+        let shipped: HashSet<&String> = self.packages.iter().flat_map(|s| &s.files).collect();
+        for pkg in db.list_mut() {
+            pkg.files.retain(|f| !shipped.contains(f));
+            pkg.file_info.retain(|f, _| !shipped.contains(f));
+        }
+        let mut kept = Vec::new();
+        for staged in &self.packages {
+            let config = staged
+                .files
+                .iter()
+                .filter(|f| staged.package.is_config(f))
+                .filter_map(|f| match &staged.info[f].kind {
+                    FileKind::File { sha256 } => Some((f.clone(), sha256.clone())),
+                    _ => None,
+                })
+                .collect::<BTreeMap<_, _>>();
+            // An edited configuration file stays. If the package changed it
+            // too, the new version goes next to it as `.kpnew`.
+            for (file, sha256) in &config {
+                let dest = self.root.join(file);
+                let Some(packaged) = old_config.get(file) else { continue };
+                let Ok(current) = resolve::compute_sha256(&dest) else { continue };
+                if current == *packaged || current == *sha256 {
+                    continue;
+                }
+                let staged_file = staged.tree.join(file);
+                if *packaged == *sha256 {
+                    fs::remove_file(&staged_file)?;
+                } else {
+                    fs::rename(&staged_file, config_path(&staged_file, NEW_SUFFIX))?;
+                    kept.push(file.clone());
+                }
+            }
+            db.add(InstalledPackage {
+                name: staged.package.name.clone(),
+                version: staged.package.version.clone(),
+                arch: staged.package.arch.clone(),
+                flavour: staged.package.flavour.clone(),
+                depends: staged
+                    .package
+                    .depends
+                    .iter()
+                    .filter(|d| d.is_required())
+                    .map(|d| d.to_string())
+                    .collect(),
+                files: staged.files.clone(),
+                file_info: staged.info.clone(),
+                config,
+            });
+        }
+        let owned: HashSet<&String> = db.list().flat_map(|p| &p.files).collect();
+        obsolete.retain(|f| !owned.contains(f));
+        obsolete.sort();
+        obsolete.dedup();
+        let saved: Vec<&String> = obsolete
+            .iter()
+            .filter(|f| {
+                old_config.get(*f).is_some_and(|packaged| {
+                    resolve::compute_sha256(&self.root.join(f)).is_ok_and(|current| current != *packaged)
+                })
+            })
+            .collect();
+
+        let staged_db = self.dir.join("db.json");
+        db.save(&staged_db)?;
+        File::open(&staged_db)?.sync_all()?;
+
+        // From here on the work directory holds the only way back: it is
+        // left for `recover` unless the root is known to be restored.
+        self.touched = true;
+        let mut journal = Journal::create(&self.dir)?;
+        sync_dir(&self.dir)?;
+        let applied = (|| {
+            for file in &saved {
+                let path = self.root.join(file);
+                journal.copy_file(&path, &config_path(&path, SAVE_SUFFIX))?;
+            }
+            for file in obsolete.iter().rev() {
+                journal.remove_file(&self.root.join(file))?;
+            }
+            for staged in &self.packages {
+                journal.merge(&staged.tree, &self.root)?;
+            }
+            Ok::<_, io::Error>(())
+        })();
+        drop(journal);
+        let committed = applied.and_then(|()| fs::rename(&staged_db, &db_path));
+        if let Err(e) = committed {
+            rollback(&self.dir)?;
+            self.touched = false;
+            return Err(e.into());
+        }
+
+        self.finished = true;
+        sync_dir(db_path.parent().unwrap())?;
+        fs::remove_dir_all(&self.dir)?;
+
+        for file in kept {
+            println!("⚠️ Kept modified /{0}, new version saved as /{0}{1}", file, NEW_SUFFIX);
+        }
+        for file in saved {
+            println!("⚠️ Saved modified /{0} as /{0}{1}", file, SAVE_SUFFIX);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Transaction {
+    /// A transaction dropped before it touched the root only leaves its work
+    /// directory. One that failed half-way, and could not be rolled back,
+    /// keeps it for `recover`.
+    fn drop(&mut self) {
+        if !self.finished && !self.touched {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+}
+
+/// Roll back a transaction interrupted before its commit, and clean up
+/// after one interrupted after it. Returns whether there was one. Only safe
+/// while holding the transaction lock.
+fn recover(root: &Path, _lock: &Lock) -> Result<bool, TransactionError> {
+    let dir = root.join(WORK_DIR);
+    if !dir.exists() {
+        return Ok(false);
+    }
+    if dir.join("db.json").exists() {
+        eprintln!("⚠️ Rolling back an interrupted transaction in {}", root.display());
+        rollback(&dir)?;
+    }
+    fs::remove_dir_all(&dir)?;
+    Ok(true)
+}
+
+/// Undo the journal in `dir`, last step first.
+fn rollback(dir: &Path) -> Result<(), TransactionError> {
+    let path = dir.join("journal");
+    if !path.exists() {
+        return Ok(());
+    }
+    let mut entries = Vec::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        // A step cut off by a crash was never taken.
+        match serde_json::from_str::<JournalEntry>(&line) {
+            Ok(entry) => entries.push(entry),
+            Err(_) => break,
+        }
+    }
+
+    for entry in entries.into_iter().rev() {
+        match entry {
+            JournalEntry::CreatedDir { path } => {
+                let _ = fs::remove_dir(path);
+            }
+            JournalEntry::Created { path } => {
+                remove_if_exists(&temp_path(&path))?;
+                remove_if_exists(&path)?;
+            }
+            JournalEntry::Replaced { path, backup } | JournalEntry::Removed { path, backup } => {
+                remove_if_exists(&temp_path(&path))?;
+                if fs::symlink_metadata(&backup).is_ok() {
+                    move_file(&backup, &path)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Takes the steps of a commit, recording each one first.
+struct Journal {
+    file: File,
+    backup_dir: PathBuf,
+    backups: usize,
+    /// Where hard-linked files have been moved to, by their staged inode.
+    linked: HashMap<(u64, u64), PathBuf>,
+}
+
+impl Journal {
+    fn create(dir: &Path) -> io::Result<Self> {
+        Ok(Journal {
+            file: File::create(dir.join("journal"))?,
+            backup_dir: dir.join("backup"),
+            backups: 0,
+            linked: HashMap::new(),
+        })
+    }
+
+    fn record(&mut self, entry: &JournalEntry) -> io::Result<()> {
+        let line = serde_json::to_string(entry).map_err(io::Error::other)?;
+        writeln!(self.file, "{}", line)?;
+        // The entry must be on disk before the step it describes.
+        self.file.sync_data()
+    }
+
+    fn next_backup(&mut self) -> PathBuf {
+        self.backups += 1;
+        self.backup_dir.join(self.backups.to_string())
+    }
+
+    fn remove_file(&mut self, path: &Path) -> io::Result<()> {
+        if fs::symlink_metadata(path).is_err() {
+            return Ok(());
+        }
+        let backup = self.next_backup();
+        self.record(&JournalEntry::Removed { path: path.to_path_buf(), backup: backup.clone() })?;
+        move_file(path, &backup)
+    }
+
+    /// Record that `dest` is about to be written, backing up what is there.
+    /// The old file stays in place until the new one is renamed over it.
+    fn prepare(&mut self, dest: &Path) -> io::Result<()> {
+        if fs::symlink_metadata(dest).is_ok() {
+            let backup = self.next_backup();
+            self.record(&JournalEntry::Replaced { path: dest.to_path_buf(), backup: backup.clone() })?;
+            if fs::hard_link(dest, &backup).is_err() {
+                copy_file(dest, &backup)?;
+            }
+        } else {
+            self.record(&JournalEntry::Created { path: dest.to_path_buf() })?;
+        }
+        Ok(())
+    }
+
+    fn copy_file(&mut self, from: &Path, to: &Path) -> io::Result<()> {
+        self.prepare(to)?;
+        let tmp = temp_path(to);
+        copy_file(from, &tmp)?;
+        fs::rename(&tmp, to)
+    }
+
+    /// Move the tree at `from` into `to`, keeping the directories that are
+    /// already there and replacing files one at a time.
+    fn merge(&mut self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut entries = fs::read_dir(from)?.collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|e| e.file_name());
+        for entry in entries {
+            let src = entry.path();
+            let dest = to.join(entry.file_name());
+            if entry.file_type()?.is_dir() {
+                // Follows symlinks, so `lib -> usr/lib` is merged into.
+                if !dest.is_dir() {
+                    self.record(&JournalEntry::CreatedDir { path: dest.clone() })?;
+                    fs::create_dir(&dest)?;
+                    Attributes::read(&src)?.apply(&dest)?;
+                }
+                self.merge(&src, &dest)?;
+            } else {
+                self.prepare(&dest)?;
+
+                // Across filesystems every name of a hard-linked file would
+                // become a copy of its own; link to the first one instead.
+                let meta = fs::symlink_metadata(&src)?;
+                let inode = (meta.dev(), meta.ino());
+                if let Some(first) = self.linked.get(&inode) {
+                    let tmp = temp_path(&dest);
+                    remove_if_exists(&tmp)?;
+                    fs::hard_link(first, &tmp)?;
+                    fs::rename(&tmp, &dest)?;
+                    fs::remove_file(&src)?;
+                } else {
+                    move_file(&src, &dest)?;
+                    if meta.is_file() && meta.nlink() > 1 {
+                        self.linked.insert(inode, dest.clone());
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The packaged hashes of the configuration files of `pkg`. Packages
+/// installed before these were recorded fall back to the hashes of their
+/// files in the default configuration directory.
+fn packaged_config(pkg: &InstalledPackage) -> BTreeMap<String, String> {
+    if !pkg.config.is_empty() {
+        return pkg.config.clone();
+    }
+    let dir = format!("{}/", package::DEFAULT_CONFIG.trim_start_matches('/'));
+    pkg.file_info
+        .iter()
+        .filter(|(file, _)| file.starts_with(&dir))
+        .filter_map(|(file, info)| match &info.kind {
+            FileKind::File { sha256 } => Some((file.clone(), sha256.clone())),
+            _ => None,
+        })
+        .collect()
+}
+
+/// `path` with `suffix` appended, for the versions of a configuration file
+/// kept next to it.
+pub fn config_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// The name a file coming from another filesystem is copied to before it is
+/// renamed to `path`.
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = std::ffi::OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(".kspkg-tmp");
+    path.with_file_name(name)
+}
+
+/// Rename `from` to `to`, replacing whatever is at `to` in one step, even
+/// when the two are on different filesystems.
+fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    #[cfg(test)]
+    if tests::CROSS_DEVICE.get() {
+        return move_across(from, to);
+    }
+    match fs::rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => move_across(from, to),
+        result => result,
+    }
+}
+
+/// What `move_file` does when `from` and `to` are on different filesystems.
+fn move_across(from: &Path, to: &Path) -> io::Result<()> {
+    let tmp = temp_path(to);
+    copy_file(from, &tmp)?;
+    fs::rename(&tmp, to)?;
+    fs::remove_file(from)
+}
+
+/// Copy a file, symlink, FIFO or device node with its owner, mode and
+/// extended attributes, and flush it to disk.
+fn copy_file(from: &Path, to: &Path) -> io::Result<()> {
+    remove_if_exists(to)?;
+    let meta = fs::symlink_metadata(from)?;
+    if meta.file_type().is_symlink() {
+        std::os::unix::fs::symlink(fs::read_link(from)?, to)?;
+    } else if meta.is_file() {
+        fs::copy(from, to)?;
+        File::open(to)?.sync_all()?;
+    } else {
+        fileinfo::make_node(to, meta.mode(), meta.rdev())?;
+    }
+    Attributes::read(from)?.apply(to)
+}
+
+fn sync_dir(path: &Path) -> io::Result<()> {
+    File::open(path)?.sync_all()
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use crate::version::Version;
+
+    thread_local! {
+        /// Makes `move_file` behave as if every rename crossed filesystems.
+        pub(super) static CROSS_DEVICE: Cell<bool> = const { Cell::new(false) };
+    }
+
+    fn package(name: &str, version: &str) -> Package {
+        Package {
+            name: name.to_string(),
+            version: Version::parse(version).unwrap(),
+            arch: "x86_64".to_string(),
+            flavour: "glibc-systemd".to_string(),
+            depends: Vec::new(),
+            provides: Vec::new(),
+            conflicts: Vec::new(),
+            replaces: Vec::new(),
+            homepage: None,
+            license: None,
+            config: vec!["/etc".to_string()],
+        }
+    }
+
+    /// Stage `name` with `files` (path, content) written into a fresh tree.
+    fn stage(txn: &mut Transaction, name: &str, version: &str, files: &[(&str, &str)]) {
+        let tree = txn.dir.join("staging").join(format!("{}-{}", name, version));
+        for (path, content) in files {
+            let path = tree.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+        fs::create_dir_all(&tree).unwrap();
+        txn.add_staged(package(name, version), tree).unwrap();
+    }
+
+    fn installed(root: &Path) -> Vec<String> {
+        let db = PackageDatabase::load_or_new(root.join(DB_PATH)).unwrap();
+        let mut names: Vec<String> = db.list().map(|p| format!("{}-{}", p.name, p.version)).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn commits_all_packages_at_once() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        fs::create_dir_all(root.join("usr/bin")).unwrap();
+        fs::write(root.join("usr/bin/other"), "other").unwrap();
+
+        let mut txn = Transaction::begin(root).unwrap();
+        stage(&mut txn, "htop", "3.3", &[("usr/bin/htop", "v3.3"), ("usr/share/htop/old", "x")]);
+        stage(&mut txn, "zlib", "1.3", &[("usr/lib/libz.so", "z")]);
+        txn.commit().unwrap();
+        assert_eq!(installed(root), vec!["htop-3.3", "zlib-1.3"]);
+        assert_eq!(fs::read_to_string(root.join("usr/bin/other")).unwrap(), "other");
+
+        // Upgrade one, remove the other; files the new version dropped go.
+        let mut txn = Transaction::begin(root).unwrap();
+        stage(&mut txn, "htop", "3.4", &[("usr/bin/htop", "v3.4")]);
+        txn.remove("zlib");
+        txn.commit().unwrap();
+        assert_eq!(installed(root), vec!["htop-3.4"]);
+        assert_eq!(fs::read_to_string(root.join("usr/bin/htop")).unwrap(), "v3.4");
+        assert!(!root.join("usr/share/htop/old").exists());
+        assert!(!root.join("usr/lib/libz.so").exists());
+        assert!(!root.join(WORK_DIR).exists());
+    }
+
+    #[test]
+    fn failed_commit_restores_previous_state() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        let mut txn = Transaction::begin(root).unwrap();
+        stage(&mut txn, "htop", "3.3", &[("usr/bin/htop", "v3.3"), ("usr/share/doc/htop", "doc")]);
+        txn.commit().unwrap();
+
+        // The second package cannot be moved in: `opt` is a dangling symlink.
+        std::os::unix::fs::symlink("/nonexistent", root.join("opt")).unwrap();
+        let mut txn = Transaction::begin(root).unwrap();
+        stage(&mut txn, "htop", "3.4", &[("usr/bin/htop", "v3.4")]);
+        stage(&mut txn, "nano", "8.0", &[("opt/nano/bin/nano", "nano")]);
+        assert!(matches!(txn.commit(), Err(TransactionError::Io(_))));
+
+        assert_eq!(installed(root), vec!["htop-3.3"]);
+        assert_eq!(fs::read_to_string(root.join("usr/bin/htop")).unwrap(), "v3.3");
+        assert_eq!(fs::read_to_string(root.join("usr/share/doc/htop")).unwrap(), "doc");
+        assert!(!root.join(WORK_DIR).exists());
+    }
+
+    #[test]
+    fn recovers_interrupted_transaction() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        fs::create_dir_all(root.join("usr/bin")).unwrap();
+        fs::write(root.join("usr/bin/htop"), "v3.3").unwrap();
+
+        // A commit that died after replacing the file but before the rename
+        // of the database.
+        let dir = root.join(WORK_DIR);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("db.json"), "{}").unwrap();
+        let mut journal = Journal::create(&dir).unwrap();
+        fs::create_dir_all(&journal.backup_dir).unwrap();
+        let staged = dir.join("htop");
+        fs::write(&staged, "v3.4").unwrap();
+        let backup = journal.next_backup();
+        let path = root.join("usr/bin/htop");
+        journal.record(&JournalEntry::Replaced { path: path.clone(), backup: backup.clone() }).unwrap();
+        fs::rename(&path, &backup).unwrap();
+        fs::rename(&staged, &path).unwrap();
+        journal.record(&JournalEntry::Created { path: root.join("usr/bin/htopx") }).unwrap();
+        drop(journal);
+
+        let lock = Lock::acquire(&root.join(LOCK_PATH)).unwrap();
+        assert!(recover(root, &lock).unwrap());
+        assert_eq!(fs::read_to_string(root.join("usr/bin/htop")).unwrap(), "v3.3");
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn holds_the_lock_until_dropped() {
+        let root = tempfile::tempdir().unwrap();
+        let txn = Transaction::begin(root.path()).unwrap();
+        let other = File::open(root.path().join(LOCK_PATH)).unwrap();
+        assert!(matches!(other.try_lock(), Err(std::fs::TryLockError::WouldBlock)));
+        drop(txn);
+        other.try_lock().unwrap();
+    }
+
+    #[test]
+    fn keeps_work_directory_of_a_touched_transaction() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        let mut txn = Transaction::begin(root).unwrap();
+        stage(&mut txn, "htop", "3.3", &[("usr/bin/htop", "v3.3")]);
+        fs::write(txn.dir.join("db.json"), "{}").unwrap();
+        txn.touched = true;
+        drop(txn);
+        assert!(root.join(WORK_DIR).join("db.json").exists());
+
+        // Left for the next transaction to recover.
+        drop(Transaction::begin(root).unwrap());
+        assert!(!root.join(WORK_DIR).exists());
+    }
+
+    #[test]
+    fn merges_across_filesystems() {
+        // As if the work directory were on another filesystem than the root.
+        CROSS_DEVICE.set(true);
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        fs::create_dir_all(root.join("usr/bin")).unwrap();
+        fs::write(root.join("usr/bin/other"), "other").unwrap();
+
+        let mut txn = Transaction::begin(root).unwrap();
+        stage(&mut txn, "htop", "3.3", &[("usr/bin/htop", "v3.3"), ("usr/share/htop/old", "x")]);
+        txn.commit().unwrap();
+        let mut txn = Transaction::begin(root).unwrap();
+        let tree = txn.dir.join("staging/htop-3.4");
+        fs::create_dir_all(tree.join("usr/bin")).unwrap();
+        fs::write(tree.join("usr/bin/htop"), "v3.4").unwrap();
+        fs::hard_link(tree.join("usr/bin/htop"), tree.join("usr/bin/top")).unwrap();
+        txn.add_staged(package("htop", "3.4"), tree).unwrap();
+        txn.commit().unwrap();
+        assert_eq!(fs::read_to_string(root.join("usr/bin/htop")).unwrap(), "v3.4");
+        assert_eq!(fs::read_to_string(root.join("usr/bin/other")).unwrap(), "other");
+        let ino = |path: &str| fs::metadata(root.join(path)).unwrap().ino();
+        assert_eq!(ino("usr/bin/top"), ino("usr/bin/htop"));
+        assert!(!root.join("usr/share/htop/old").exists());
+
+        // Rolled back across filesystems too.
+        std::os::unix::fs::symlink("/nonexistent", root.join("opt")).unwrap();
+        let mut txn = Transaction::begin(root).unwrap();
+        stage(&mut txn, "htop", "3.5", &[("usr/bin/htop", "v3.5")]);
+        stage(&mut txn, "nano", "8.0", &[("opt/nano/bin/nano", "nano")]);
+        assert!(txn.commit().is_err());
+        assert_eq!(fs::read_to_string(root.join("usr/bin/htop")).unwrap(), "v3.4");
+        let leftovers: Vec<_> = fs::read_dir(root.join("usr/bin")).unwrap().collect();
+        assert_eq!(leftovers.len(), 3);
+    }
+
+    #[test]
+    fn installs_and_removes_links_and_special_files() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        let mut txn = Transaction::begin(root).unwrap();
+        let tree = txn.dir.join("staging/busybox");
+        fs::create_dir_all(tree.join("usr/bin")).unwrap();
+        fs::write(tree.join("usr/bin/busybox"), "bb").unwrap();
+        fs::hard_link(tree.join("usr/bin/busybox"), tree.join("usr/bin/sh")).unwrap();
+        std::os::unix::fs::symlink("busybox", tree.join("usr/bin/ls")).unwrap();
+        fs::create_dir_all(tree.join("run")).unwrap();
+        fileinfo::make_node(&tree.join("run/initctl"), libc::S_IFIFO | 0o600, 0).unwrap();
+        txn.add_staged(package("busybox", "1.37"), tree).unwrap();
+        txn.commit().unwrap();
+
+        let db = PackageDatabase::load_or_new(root.join(DB_PATH)).unwrap();
+        let busybox = db.get("busybox").unwrap();
+        assert_eq!(busybox.files, vec!["run/initctl", "usr/bin/busybox", "usr/bin/ls", "usr/bin/sh"]);
+        assert_eq!(
+            busybox.file_info["usr/bin/sh"].kind,
+            fileinfo::FileKind::Hardlink { target: "usr/bin/busybox".to_string() }
+        );
+        let ino = |path: &str| fs::metadata(root.join(path)).unwrap().ino();
+        assert_eq!(ino("usr/bin/sh"), ino("usr/bin/busybox"));
+
+        let mut txn = Transaction::begin(root).unwrap();
+        txn.remove("busybox");
+        txn.commit().unwrap();
+        assert!(fs::symlink_metadata(root.join("usr/bin/ls")).is_err());
+        assert!(fs::symlink_metadata(root.join("run/initctl")).is_err());
+    }
+
+    #[test]
+    fn keeps_edited_configuration_files() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        let mut txn = Transaction::begin(root).unwrap();
+        stage(&mut txn, "nginx", "1.26", &[
+            ("etc/nginx/nginx.conf", "v1"),
+            ("etc/nginx/mime.types", "v1"),
+            ("usr/bin/nginx", "v1"),
+        ]);
+        txn.commit().unwrap();
+        let db = PackageDatabase::load_or_new(root.join(DB_PATH)).unwrap();
+        let config: Vec<&String> = db.get("nginx").unwrap().config.keys().collect();
+        assert_eq!(config, vec!["etc/nginx/mime.types", "etc/nginx/nginx.conf"]);
+
+        // Edited files stay, untouched ones are upgraded.
+        fs::write(root.join("etc/nginx/nginx.conf"), "mine").unwrap();
+        let mut txn = Transaction::begin(root).unwrap();
+        stage(&mut txn, "nginx", "1.27", &[
+            ("etc/nginx/nginx.conf", "v2"),
+            ("etc/nginx/mime.types", "v2"),
+            ("usr/bin/nginx", "v2"),
+        ]);
+        txn.commit().unwrap();
+        let read = |path: &str| fs::read_to_string(root.join(path)).unwrap();
+        assert_eq!(read("etc/nginx/nginx.conf"), "mine");
+        assert_eq!(read("etc/nginx/nginx.conf.kpnew"), "v2");
+        assert_eq!(read("etc/nginx/mime.types"), "v2");
+        assert!(!root.join("etc/nginx/mime.types.kpnew").exists());
+        assert_eq!(read("usr/bin/nginx"), "v2");
+
+        // A configuration file the package did not change gets no `.kpnew`.
+        fs::remove_file(root.join("etc/nginx/nginx.conf.kpnew")).unwrap();
+        let mut txn = Transaction::begin(root).unwrap();
+        stage(&mut txn, "nginx", "1.28", &[
+            ("etc/nginx/nginx.conf", "v2"),
+            ("etc/nginx/mime.types", "v2"),
+            ("usr/bin/nginx", "v3"),
+        ]);
+        txn.commit().unwrap();
+        assert_eq!(read("etc/nginx/nginx.conf"), "mine");
+        assert!(!root.join("etc/nginx/nginx.conf.kpnew").exists());
+
+        // Removing leaves a copy of edited files only.
+        let mut txn = Transaction::begin(root).unwrap();
+        txn.remove("nginx");
+        txn.commit().unwrap();
+        assert_eq!(read("etc/nginx/nginx.conf.kpsave"), "mine");
+        assert!(!root.join("etc/nginx/nginx.conf").exists());
+        assert!(!root.join("etc/nginx/mime.types").exists());
+        assert!(!root.join("etc/nginx/mime.types.kpsave").exists());
+    }
+
+    #[test]
+    fn protects_configuration_of_packages_installed_without_a_record() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        let mut txn = Transaction::begin(root).unwrap();
+        stage(&mut txn, "sudo", "1.9", &[("etc/sudoers", "v1")]);
+        txn.commit().unwrap();
+        let mut db = PackageDatabase::load_or_new(root.join(DB_PATH)).unwrap();
+        for pkg in db.list_mut() {
+            pkg.config.clear();
+        }
+        db.save(root.join(DB_PATH)).unwrap();
+
+        fs::write(root.join("etc/sudoers"), "mine").unwrap();
+        let mut txn = Transaction::begin(root).unwrap();
+        stage(&mut txn, "sudo", "1.10", &[("etc/sudoers", "v2")]);
+        txn.commit().unwrap();
+        assert_eq!(fs::read_to_string(root.join("etc/sudoers")).unwrap(), "mine");
+        assert_eq!(fs::read_to_string(root.join("etc/sudoers.kpnew")).unwrap(), "v2");
+    }
+
+    #[test]
+    fn refuses_files_shipped_twice() {
+        let root = tempfile::tempdir().unwrap();
+        let mut txn = Transaction::begin(root.path()).unwrap();
+        stage(&mut txn, "vim", "9.1", &[("usr/bin/vi", "vim")]);
+        stage(&mut txn, "nvi", "1.8", &[("usr/bin/vi", "nvi")]);
+        // Not even with --force.
+        txn.overwrite(OverwritePolicy::new(&[], true).unwrap());
+        let err = txn.commit().unwrap_err();
+        assert!(matches!(err, TransactionError::Conflicts(ref c)
+            if c[0] == FileConflict {
+                path: "usr/bin/vi".to_string(),
+                package: "nvi".to_string(),
+                kind: ConflictKind::Staged("vim".to_string()),
+            }));
+        assert!(!root.path().join("usr/bin/vi").exists());
+    }
+
+    #[test]
+    fn refuses_files_of_other_packages_and_unowned_files() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        let mut txn = Transaction::begin(root).unwrap();
+        stage(&mut txn, "vim", "9.1", &[("usr/bin/vi", "vim")]);
+        txn.commit().unwrap();
+        fs::write(root.join("usr/bin/ex"), "local").unwrap();
+
+        let mut txn = Transaction::begin(root).unwrap();
+        stage(&mut txn, "nvi", "1.8", &[("usr/bin/vi", "nvi"), ("usr/bin/ex", "nvi")]);
+        let err = txn.commit().unwrap_err();
+        let TransactionError::Conflicts(conflicts) = &err else { panic!("{}", err) };
+        let kinds: Vec<_> = conflicts.iter().map(|c| (c.path.as_str(), &c.kind)).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                ("usr/bin/ex", &ConflictKind::Unowned),
+                ("usr/bin/vi", &ConflictKind::Owned("vim".to_string())),
+            ]
+        );
+        assert!(err.to_string().contains("/usr/bin/vi (nvi): owned by vim"));
+        assert_eq!(fs::read_to_string(root.join("usr/bin/ex")).unwrap(), "local");
+
+        // Upgrading the owner is not a conflict.
+        let mut txn = Transaction::begin(root).unwrap();
+        stage(&mut txn, "vim", "9.2", &[("usr/bin/vi", "vim 9.2")]);
+        txn.commit().unwrap();
+    }
+
+    #[test]
+    fn overwrites_matching_files() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        let mut txn = Transaction::begin(root).unwrap();
+        stage(&mut txn, "vim", "9.1", &[("usr/bin/vi", "vim"), ("usr/bin/vim", "vim")]);
+        txn.commit().unwrap();
+        fs::write(root.join("usr/bin/ex"), "local").unwrap();
+
+        let mut txn = Transaction::begin(root).unwrap();
+        stage(&mut txn, "nvi", "1.8", &[("usr/bin/vi", "nvi"), ("usr/bin/ex", "nvi")]);
+        txn.overwrite(OverwritePolicy::new(&["/usr/bin/vi".to_string()], false).unwrap());
+        assert!(matches!(txn.commit(), Err(TransactionError::Conflicts(ref c)) if c.len() == 1));
+
+        let mut txn = Transaction::begin(root).unwrap();
+        stage(&mut txn, "nvi", "1.8", &[("usr/bin/vi", "nvi"), ("usr/bin/ex", "nvi")]);
+        txn.overwrite(OverwritePolicy::new(&["/usr/**".to_string()], false).unwrap());
+        txn.commit().unwrap();
+        assert_eq!(fs::read_to_string(root.join("usr/bin/vi")).unwrap(), "nvi");
+        assert_eq!(fs::read_to_string(root.join("usr/bin/ex")).unwrap(), "nvi");
+
+        // vi now belongs to nvi alone, so removing vim leaves it.
+        let db = PackageDatabase::load_or_new(root.join(DB_PATH)).unwrap();
+        assert_eq!(db.get("vim").unwrap().files, vec!["usr/bin/vim"]);
+        let mut txn = Transaction::begin(root).unwrap();
+        txn.remove("vim");
+        txn.commit().unwrap();
+        assert!(root.join("usr/bin/vi").exists());
+
+        // --force replaces anything but never swaps a directory for a file.
+        fs::create_dir_all(root.join("usr/share/doc")).unwrap();
+        let mut txn = Transaction::begin(root).unwrap();
+        stage(&mut txn, "vim", "9.1", &[("usr/bin/vi", "vim"), ("usr/share/doc", "vim")]);
+        txn.overwrite(OverwritePolicy::new(&[], true).unwrap());
+        assert!(matches!(txn.commit(), Err(TransactionError::Conflicts(ref c))
+            if c.len() == 1 && c[0].kind == ConflictKind::Directory));
+    }
+
+    #[test]
+    fn matches_globs_per_component() {
+        let policy = OverwritePolicy::new(&["/usr/lib/python3.?/*.py".to_string()], false).unwrap();
+        assert!(!policy.allows("usr/lib/python3.12/os.py"));
+        assert!(policy.allows("usr/lib/python3.9/os.py"));
+        assert!(!policy.allows("usr/lib/python3.9/json/decoder.py"));
+        assert!(OverwritePolicy::new(&[], false).map(|p| !p.allows("etc/passwd")).unwrap());
+    }
+}
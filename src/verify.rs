@@ -0,0 +1,128 @@
+// src/verify.rs
+
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use thiserror::Error;
+
+use crate::fileinfo::{self, FileInfo, FileKind};
+use crate::pkgdb::{PackageDatabase, PkgDbError};
+
+#[derive(Error, Debug)]
+pub enum VerifyError {
+    #[error("Package database error: {0}")]
+    PkgDb(#[from] PkgDbError),
+    #[error("{0} file(s) of {1} differ from the package")]
+    Modified(usize, String),
+}
+
+/// Check every file of the installed package `name` against what the
+/// package shipped: type, contents, mode, owner and extended attributes.
+pub fn verify_package(root: &Path, name: &str) -> Result<(), VerifyError> {
+    let db = PackageDatabase::load_or_new(root.join("var/lib/koushou/db.json"))?;
+    let pkg = db.get(name)?;
+
+    let mut modified = 0;
+    for file in &pkg.files {
+        let problems = match pkg.file_info.get(file) {
+            Some(info) => check(root, file, info),
+            None if root.join(file).symlink_metadata().is_err() => vec!["missing".to_string()],
+            None => Vec::new(),
+        };
+        if !problems.is_empty() {
+            modified += 1;
+            println!("✗ /{}: {}", file, problems.join(", "));
+        }
+    }
+
+    if modified > 0 {
+        return Err(VerifyError::Modified(modified, name.to_string()));
+    }
+    println!("✓ {}-{}: all {} files intact", pkg.name, pkg.version, pkg.files.len());
+    Ok(())
+}
+
+/// How the entry at `file` differs from `expected`.
+fn check(root: &Path, file: &str, expected: &FileInfo) -> Vec<String> {
+    let path = root.join(file);
+    if let FileKind::Hardlink { target } = &expected.kind {
+        let same_inode = match (path.symlink_metadata(), root.join(target).symlink_metadata()) {
+            (Ok(a), Ok(b)) => (a.dev(), a.ino()) == (b.dev(), b.ino()),
+            (Err(_), _) => return vec!["missing".to_string()],
+            _ => false,
+        };
+        return if same_inode { Vec::new() } else { vec![format!("no longer a link to /{}", target)] };
+    }
+
+    let actual = match fileinfo::describe(&path, None) {
+        Ok(actual) => actual,
+        Err(_) => return vec!["missing".to_string()],
+    };
+    let mut problems = Vec::new();
+    match (&expected.kind, &actual.kind) {
+        (FileKind::File { .. }, FileKind::File { .. }) if expected.kind != actual.kind => {
+            problems.push("contents changed".to_string())
+        }
+        (FileKind::Symlink { target: a }, FileKind::Symlink { target: b }) if a != b => {
+            problems.push(format!("points to {} instead of {}", b, a))
+        }
+        (a, b) if a != b => problems.push("type changed".to_string()),
+        _ => {}
+    }
+    let (a, b) = (&expected.attributes, &actual.attributes);
+    if a.mode != b.mode && !matches!(actual.kind, FileKind::Symlink { .. }) {
+        problems.push(format!("mode {:04o} instead of {:04o}", b.mode, a.mode));
+    }
+    if (a.uid, a.gid) != (b.uid, b.gid) {
+        problems.push(format!("owner {}:{} instead of {}:{}", b.uid, b.gid, a.uid, a.gid));
+    }
+    if a.xattrs != b.xattrs {
+        problems.push("extended attributes changed".to_string());
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use crate::pkgdb::InstalledPackage;
+    use crate::version::Version;
+
+    #[test]
+    fn reports_changed_files() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        fs::create_dir_all(root.join("usr/bin")).unwrap();
+        fs::write(root.join("usr/bin/su"), "su").unwrap();
+        fs::set_permissions(root.join("usr/bin/su"), fs::Permissions::from_mode(0o4755)).unwrap();
+        fs::hard_link(root.join("usr/bin/su"), root.join("usr/bin/su2")).unwrap();
+        std::os::unix::fs::symlink("su", root.join("usr/bin/sudo")).unwrap();
+
+        let file_info = fileinfo::describe_tree(root).unwrap();
+        let mut db = PackageDatabase::new();
+        db.add(InstalledPackage {
+            name: "shadow".to_string(),
+            version: Version::parse("4.16").unwrap(),
+            arch: "x86_64".to_string(),
+            flavour: "glibc-systemd".to_string(),
+            depends: Vec::new(),
+            files: file_info.keys().cloned().collect(),
+            file_info: file_info.clone(),
+            config: Default::default(),
+        });
+        fs::create_dir_all(root.join("var/lib/koushou")).unwrap();
+        db.save(root.join("var/lib/koushou/db.json")).unwrap();
+        verify_package(root, "shadow").unwrap();
+
+        fs::set_permissions(root.join("usr/bin/su"), fs::Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(check(root, "usr/bin/su", &file_info["usr/bin/su"]), vec!["mode 0755 instead of 4755"]);
+        fs::remove_file(root.join("usr/bin/sudo")).unwrap();
+        std::os::unix::fs::symlink("bash", root.join("usr/bin/sudo")).unwrap();
+        assert_eq!(
+            check(root, "usr/bin/sudo", &file_info["usr/bin/sudo"]),
+            vec!["points to bash instead of su"]
+        );
+        assert!(matches!(verify_package(root, "shadow"), Err(VerifyError::Modified(2, _))));
+    }
+}
@@ -0,0 +1,369 @@
+// src/version.rs
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum VersionError {
+    #[error("Empty version string")]
+    Empty,
+    #[error("Invalid epoch in version '{0}'")]
+    InvalidEpoch(String),
+    #[error("Missing upstream version in '{0}'")]
+    MissingUpstream(String),
+    #[error("Empty package release in version '{0}'")]
+    EmptyRelease(String),
+    #[error("Invalid character '{ch}' in version '{version}'")]
+    InvalidCharacter { version: String, ch: char },
+}
+
+/// A package version of the form `[epoch:]upstream[-release]`, e.g. `1:2.10.3-2`.
+///
+/// Versions are ordered by epoch first (missing means 0), then upstream, then
+/// release (missing means 0). Upstream and release strings are compared the
+/// way dpkg does it: each string is split into alternating runs of non-digits
+/// and digits. Non-digit runs are compared character by character, with `~`
+/// sorting before anything (even the end of the string), letters before
+/// other characters, and the end of the run in between. Digit runs are
+/// compared numerically. So `1.9 < 1.10`, `1.0~rc1 < 1.0 < 1.0a < 1.0.1`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Version {
+    raw: String,
+    epoch: u64,
+    upstream: String,
+    release: Option<String>,
+}
+
+impl Version {
+    pub fn parse(s: &str) -> Result<Self, VersionError> {
+        if s.is_empty() {
+            return Err(VersionError::Empty);
+        }
+
+        let (epoch, rest) = match s.split_once(':') {
+            Some((e, rest)) => {
+                if e.is_empty() || !e.bytes().all(|b| b.is_ascii_digit()) {
+                    return Err(VersionError::InvalidEpoch(s.to_string()));
+                }
+                let epoch = e.parse::<u64>()
+                    .map_err(|_| VersionError::InvalidEpoch(s.to_string()))?;
+                (epoch, rest)
+            }
+            None => (0, s),
+        };
+
+        let (upstream, release) = match rest.rsplit_once('-') {
+            Some((_, r)) if r.is_empty() => return Err(VersionError::EmptyRelease(s.to_string())),
+            Some((u, r)) => (u, Some(r)),
+            None => (rest, None),
+        };
+
+        if upstream.is_empty() {
+            return Err(VersionError::MissingUpstream(s.to_string()));
+        }
+
+        let allowed = |c: char| c.is_ascii_alphanumeric() || matches!(c, '.' | '+' | '~' | '_');
+        let bad = upstream.chars().find(|&c| !allowed(c) && c != '-')
+            .or_else(|| release.and_then(|r| r.chars().find(|&c| !allowed(c))));
+        if let Some(ch) = bad {
+            return Err(VersionError::InvalidCharacter { version: s.to_string(), ch });
+        }
+
+        Ok(Self {
+            raw: s.to_string(),
+            epoch,
+            upstream: upstream.to_string(),
+            release: release.map(str::to_string),
+        })
+    }
+}
+
+/// Split a version fragment into (non-digit run, digit run) pairs. Leading
+/// zeros are stripped from digit runs, and a trailing empty pair is dropped,
+/// so that fragments which compare equal also produce equal segment lists.
+fn segments(s: &str) -> Vec<(&str, &str)> {
+    let mut out = Vec::new();
+    let mut rest = s;
+    while !rest.is_empty() {
+        let split = rest.find(|c: char| c.is_ascii_digit()).unwrap_or(rest.len());
+        let (alpha, tail) = rest.split_at(split);
+        let split = tail.find(|c: char| !c.is_ascii_digit()).unwrap_or(tail.len());
+        let (digits, tail) = tail.split_at(split);
+        out.push((alpha, digits.trim_start_matches('0')));
+        rest = tail;
+    }
+    if out.last() == Some(&("", "")) {
+        out.pop();
+    }
+    out
+}
+
+fn char_order(c: Option<u8>) -> i32 {
+    match c {
+        None => 0,
+        Some(b'~') => -1,
+        Some(c) if c.is_ascii_alphabetic() => c as i32,
+        Some(c) => c as i32 + 256,
+    }
+}
+
+fn compare_alpha(a: &str, b: &str) -> Ordering {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    for i in 0..a.len().max(b.len()) {
+        let ord = char_order(a.get(i).copied()).cmp(&char_order(b.get(i).copied()));
+        if ord != Ordering::Equal {
+            return ord;
+        }
+    }
+    Ordering::Equal
+}
+
+fn compare_digits(a: &str, b: &str) -> Ordering {
+    // Both runs have their leading zeros stripped already.
+    a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+}
+
+fn compare_fragment(a: &str, b: &str) -> Ordering {
+    let (sa, sb) = (segments(a), segments(b));
+    for i in 0..sa.len().max(sb.len()) {
+        let (aa, ad) = sa.get(i).copied().unwrap_or(("", ""));
+        let (ba, bd) = sb.get(i).copied().unwrap_or(("", ""));
+        let ord = compare_alpha(aa, ba).then_with(|| compare_digits(ad, bd));
+        if ord != Ordering::Equal {
+            return ord;
+        }
+    }
+    Ordering::Equal
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.epoch
+            .cmp(&other.epoch)
+            .then_with(|| compare_fragment(&self.upstream, &other.upstream))
+            .then_with(|| {
+                compare_fragment(
+                    self.release.as_deref().unwrap_or(""),
+                    other.release.as_deref().unwrap_or(""),
+                )
+            })
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Version {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Version {}
+
+impl Hash for Version {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // Must agree with `Ord`: "1.0", "1.00" and "0:1.0-0" hash the same.
+        self.epoch.hash(state);
+        segments(&self.upstream).hash(state);
+        segments(self.release.as_deref().unwrap_or("")).hash(state);
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.raw)
+    }
+}
+
+impl FromStr for Version {
+    type Err = VersionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+impl TryFrom<String> for Version {
+    type Error = VersionError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        Self::parse(&s)
+    }
+}
+
+impl From<Version> for String {
+    fn from(v: Version) -> Self {
+        v.raw
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::hash_map::DefaultHasher;
+
+    fn v(s: &str) -> Version {
+        Version::parse(s).unwrap_or_else(|e| panic!("failed to parse '{}': {}", s, e))
+    }
+
+    fn hash_of(v: &Version) -> u64 {
+        let mut h = DefaultHasher::new();
+        v.hash(&mut h);
+        h.finish()
+    }
+
+    #[test]
+    fn parse_components() {
+        let cases: &[(&str, u64, &str, Option<&str>)] = &[
+            ("1.0", 0, "1.0", None),
+            ("1.0-1", 0, "1.0", Some("1")),
+            ("1:2.10.3-2", 1, "2.10.3", Some("2")),
+            ("0:1.0", 0, "1.0", None),
+            ("2.0~rc1", 0, "2.0~rc1", None),
+            ("1.2-beta-3", 0, "1.2-beta", Some("3")),
+            ("20240101", 0, "20240101", None),
+            ("1.0+git20240101.abc_def-0.1", 0, "1.0+git20240101.abc_def", Some("0.1")),
+            ("12:3", 12, "3", None),
+        ];
+        for &(input, epoch, upstream, release) in cases {
+            let ver = v(input);
+            assert_eq!(ver.epoch, epoch, "epoch of {}", input);
+            assert_eq!(ver.upstream, upstream, "upstream of {}", input);
+            assert_eq!(ver.release.as_deref(), release, "release of {}", input);
+            assert_eq!(ver.to_string(), input, "display of {}", input);
+        }
+    }
+
+    #[test]
+    fn parse_errors() {
+        let cases: &[(&str, VersionError)] = &[
+            ("", VersionError::Empty),
+            (":1.0", VersionError::InvalidEpoch(":1.0".into())),
+            ("a:1.0", VersionError::InvalidEpoch("a:1.0".into())),
+            ("-1:1.0", VersionError::InvalidEpoch("-1:1.0".into())),
+            ("1:", VersionError::MissingUpstream("1:".into())),
+            ("-1", VersionError::MissingUpstream("-1".into())),
+            ("1.0-", VersionError::EmptyRelease("1.0-".into())),
+            ("1.0 ", VersionError::InvalidCharacter { version: "1.0 ".into(), ch: ' ' }),
+            ("1.0/2", VersionError::InvalidCharacter { version: "1.0/2".into(), ch: '/' }),
+            ("1:2:3", VersionError::InvalidCharacter { version: "1:2:3".into(), ch: ':' }),
+            ("1.0-r=1", VersionError::InvalidCharacter { version: "1.0-r=1".into(), ch: '=' }),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(Version::parse(input).unwrap_err(), *expected, "parsing '{}'", input);
+        }
+    }
+
+    #[test]
+    fn ordering() {
+        use Ordering::*;
+        let cases: &[(&str, &str, Ordering)] = &[
+            // plain numeric segments
+            ("1.0", "1.0", Equal),
+            ("1.9", "1.10", Less),
+            ("1.10", "1.9", Greater),
+            ("2.0", "10.0", Less),
+            ("1.2.3", "1.2.10", Less),
+            ("1.0", "1.0.1", Less),
+            ("1.0.0", "1.0", Greater),
+            ("1.01", "1.1", Equal),
+            ("1.00", "1.0", Equal),
+            ("007", "7", Equal),
+            ("99999999999999999999", "100000000000000000000", Less),
+            // alphabetic segments
+            ("1.0a", "1.0", Greater),
+            ("1.0a", "1.0b", Less),
+            ("1.0a", "1.0.1", Less),
+            ("1.0alpha", "1.0beta", Less),
+            ("1.0z", "1.0+", Less),
+            ("1.0+", "1.0.", Less),
+            ("1.0_1", "1.0.1", Greater),
+            ("1.0a1", "1.0a2", Less),
+            ("1.0a10", "1.0a9", Greater),
+            ("A", "a", Less),
+            // tilde pre-releases
+            ("1.0~rc1", "1.0", Less),
+            ("1.0~rc1", "1.0~rc2", Less),
+            ("1.0~alpha", "1.0~beta", Less),
+            ("1.0~~", "1.0~", Less),
+            ("1.0~", "1.0", Less),
+            ("1.0~rc1", "0.9", Greater),
+            ("1.0~rc1", "1.0.0~rc1", Less),
+            ("2.0~rc1", "1.9.99", Greater),
+            // epochs
+            ("1:1.0", "2.0", Greater),
+            ("0:1.0", "1.0", Equal),
+            ("1:1.0", "1:1.1", Less),
+            ("2:0.1", "1:99", Greater),
+            // releases
+            ("1.0-1", "1.0-2", Less),
+            ("1.0-10", "1.0-9", Greater),
+            ("1.0-1", "1.0", Greater),
+            ("1.0-0", "1.0", Equal),
+            ("1.0-1", "1.0.1", Less),
+            ("1.0-1~bp1", "1.0-1", Less),
+            ("1.0-1.1", "1.0-1", Greater),
+            ("1.2-beta-3", "1.2-beta-10", Less),
+            ("1.2-beta-3", "1.2-3", Greater),
+            // everything together
+            ("1:2.10.3-2", "1:2.9.3-2", Greater),
+            ("1:2.10.3-2", "1:2.10.3-10", Less),
+            ("1:2.10.3~rc1-5", "1:2.10.3-1", Less),
+        ];
+        for &(a, b, expected) in cases {
+            assert_eq!(v(a).cmp(&v(b)), expected, "{} vs {}", a, b);
+            assert_eq!(v(b).cmp(&v(a)), expected.reverse(), "{} vs {}", b, a);
+        }
+    }
+
+    #[test]
+    fn equal_versions_hash_equal() {
+        let cases: &[(&str, &str)] = &[
+            ("1.0", "1.00"),
+            ("0:1.0", "1.0"),
+            ("1.0-0", "1.0"),
+            ("1.0", "1."),
+            ("007", "7"),
+            ("1:2.010-01", "1:2.10-1"),
+        ];
+        for &(a, b) in cases {
+            assert_eq!(v(a), v(b), "{} == {}", a, b);
+            assert_eq!(hash_of(&v(a)), hash_of(&v(b)), "hash({}) == hash({})", a, b);
+        }
+    }
+
+    #[test]
+    fn sorting() {
+        let mut versions: Vec<Version> = [
+            "1.10", "1.9", "1:0.1", "1.0~rc1", "1.0", "1.0-1", "1.0a", "1.0.1", "1.0~beta", "0.9",
+        ]
+        .iter()
+        .map(|s| v(s))
+        .collect();
+        versions.sort();
+        let sorted: Vec<String> = versions.iter().map(|v| v.to_string()).collect();
+        assert_eq!(
+            sorted,
+            ["0.9", "1.0~beta", "1.0~rc1", "1.0", "1.0-1", "1.0a", "1.0.1", "1.9", "1.10", "1:0.1"]
+        );
+    }
+
+    #[test]
+    fn serde_round_trip() {
+        let ver = v("1:2.10.3-2");
+        let json = serde_json::to_string(&ver).unwrap();
+        assert_eq!(json, "\"1:2.10.3-2\"");
+        let back: Version = serde_json::from_str(&json).unwrap();
+        assert_eq!(back.to_string(), "1:2.10.3-2");
+        assert!(serde_json::from_str::<Version>("\"1.0-\"").is_err());
+    }
+}